@@ -0,0 +1,620 @@
+use crate::instruction::{Condition, Instruction, Operation, Register, RegisterPair};
+use crate::symbols::{Symbol, SymbolKind, SymbolTable};
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+/// The output of `assemble`: the bytes from the lowest to the highest address
+/// written (gaps between ORGs are zero) and the value of every label and EQU.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub labels: BTreeMap<String, u16>,
+}
+
+/// Why a line of source could not be assembled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssemblyError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for AssemblyError {}
+
+/// Assembles Intel 8080 source.
+///
+/// Each line is `[label:] [mnemonic [operands]] [; comment]`, or
+/// `name EQU expression`. The directives are ORG, DB (bytes and quoted
+/// strings), DW, DS, EQU and END. Numbers are decimal, or hex as `0FFH`,
+/// `0xff` or `$ff`, binary as `1010B`, or a character in quotes; `$` alone
+/// is the address of the current line. Expressions take `+ - * / % & | ^
+/// << >>`, unary `-` and `~`, `HIGH` and `LOW`, and parentheses.
+///
+/// The `#$xx` and `$xxxx` operands the disassembler prints are accepted, so
+/// its output assembles back to the same bytes.
+pub fn assemble(source: &str) -> Result<Program, AssemblyError> {
+    let lines = source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| parse_line(text).map_err(|message| AssemblyError { line: i + 1, message }))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Pass 1: where every label is. Operands referring to labels further
+    // down evaluate to 0 for now, which does not change any sizes.
+    let mut labels = BTreeMap::new();
+    let mut pending_equs = Vec::new();
+    let mut address: u16 = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let error = |message: String| AssemblyError { line: i + 1, message };
+        if let Some(label) = &line.label {
+            if line.mnemonic.as_deref() != Some("EQU") {
+                define(&mut labels, label, address).map_err(error)?;
+            }
+        }
+        match line.mnemonic.as_deref() {
+            Some("EQU") => {
+                let name = line.label.as_ref().ok_or_else(|| error("EQU needs a name".to_string()))?;
+                match evaluate(&line.operands.join(","), &labels, address, true) {
+                    Ok(value) => define(&mut labels, name, value).map_err(error)?,
+                    Err(_) => pending_equs.push((i, name.clone(), address)),
+                }
+            }
+            Some("END") => break,
+            Some(_) => {
+                let size = assemble_line(line, &labels, address, false).map_err(error)?;
+                if line.mnemonic.as_deref() == Some("ORG") {
+                    address = size.0;
+                } else {
+                    address = address.wrapping_add(size.1.len() as u16);
+                }
+            }
+            None => {}
+        }
+    }
+    // EQUs of labels defined further down
+    while !pending_equs.is_empty() {
+        let before = pending_equs.len();
+        let mut still_pending = Vec::new();
+        for (i, name, address) in pending_equs {
+            match evaluate(&lines[i].operands.join(","), &labels, address, true) {
+                Ok(value) => {
+                    labels.insert(name, value);
+                }
+                Err(e) => still_pending.push((i, name, address, e)),
+            }
+        }
+        if still_pending.len() == before {
+            let (i, _, _, message) = still_pending.swap_remove(0);
+            return Err(AssemblyError { line: i + 1, message });
+        }
+        pending_equs = still_pending.into_iter().map(|(i, n, a, _)| (i, n, a)).collect();
+    }
+
+    // Pass 2: the bytes, now that every label has a value
+    let mut memory: BTreeMap<u16, u8> = BTreeMap::new();
+    let mut address: u16 = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let error = |message: String| AssemblyError { line: i + 1, message };
+        match line.mnemonic.as_deref() {
+            Some("EQU") | None => {}
+            Some("END") => break,
+            Some(mnemonic) => {
+                let (org, bytes) = assemble_line(line, &labels, address, true).map_err(error)?;
+                if mnemonic == "ORG" {
+                    address = org;
+                    continue;
+                }
+                for byte in bytes {
+                    memory.insert(address, byte);
+                    address = address.wrapping_add(1);
+                }
+            }
+        }
+    }
+
+    let origin = memory.keys().next().copied().unwrap_or(0);
+    let end = memory.keys().next_back().map(|a| *a as usize + 1).unwrap_or(0);
+    let mut bytes = vec![0; end - origin as usize];
+    for (address, byte) in memory {
+        bytes[(address - origin) as usize] = byte;
+    }
+    Ok(Program { origin, bytes, labels })
+}
+
+impl Program {
+    /// The labels as symbols, all of them code, for the disassembler and
+    /// the `CPUState` dump.
+    pub fn symbol_table(&self) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        for (name, &address) in &self.labels {
+            symbols.insert(Symbol { address, name: name.clone(), kind: SymbolKind::Code, comment: None });
+        }
+        symbols
+    }
+}
+
+fn define(labels: &mut BTreeMap<String, u16>, name: &str, value: u16) -> Result<(), String> {
+    if labels.insert(name.to_string(), value).is_some() {
+        return Err(format!("{} is defined twice", name));
+    }
+    Ok(())
+}
+
+struct Line {
+    label: Option<String>,
+    mnemonic: Option<String>,
+    operands: Vec<String>,
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// Splits a line into label, mnemonic and comma separated operands, leaving
+// commas and semicolons inside quotes alone
+fn parse_line(text: &str) -> Result<Line, String> {
+    let mut code = String::new();
+    let mut quote = None;
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => break,
+            None => {}
+        }
+        code.push(c);
+    }
+    let mut rest = code.trim();
+
+    let mut label = None;
+    if let Some(colon) = rest.find(':') {
+        let name = &rest[..colon];
+        if is_identifier(name) {
+            label = Some(name.to_string());
+            rest = rest[colon + 1..].trim();
+        }
+    }
+    let (mut mnemonic, mut operands) = match rest.find(char::is_whitespace) {
+        Some(i) => (&rest[..i], rest[i..].trim()),
+        None => (rest, ""),
+    };
+    // name EQU value, without a colon
+    if label.is_none() {
+        let mut words = operands.splitn(2, char::is_whitespace);
+        if words.next().map(|w| w.eq_ignore_ascii_case("EQU")) == Some(true) && is_identifier(mnemonic) {
+            label = Some(mnemonic.to_string());
+            operands = words.next().unwrap_or("").trim();
+            mnemonic = "EQU";
+        }
+    }
+    if mnemonic.is_empty() {
+        return Ok(Line { label, mnemonic: None, operands: Vec::new() });
+    }
+
+    let mut split = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    for c in operands.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ',' => {
+                split.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            None => {}
+        }
+        current.push(c);
+    }
+    if quote.is_some() {
+        return Err("unterminated string".to_string());
+    }
+    if !current.trim().is_empty() || !split.is_empty() {
+        split.push(current.trim().to_string());
+    }
+    Ok(Line { label, mnemonic: Some(mnemonic.to_uppercase()), operands: split })
+}
+
+// The bytes for a line, or the new address for ORG. When `resolve` is false
+// unknown labels count as 0.
+fn assemble_line(line: &Line, labels: &BTreeMap<String, u16>, address: u16, resolve: bool) -> Result<(u16, Vec<u8>), String> {
+    let mnemonic = line.mnemonic.as_deref().unwrap_or("");
+    let operands = &line.operands;
+    let value = |text: &str| evaluate(text, labels, address, resolve);
+    match mnemonic {
+        "ORG" => Ok((value(single(operands)?)?, Vec::new())),
+        "DB" => {
+            let mut bytes = Vec::new();
+            for operand in operands {
+                let quoted = operand.len() >= 2
+                    && (operand.starts_with('\'') || operand.starts_with('"'))
+                    && operand.ends_with(&operand[..1]);
+                if quoted && operand.len() != 3 {
+                    bytes.extend(operand[1..operand.len() - 1].bytes());
+                } else {
+                    bytes.push(byte(operand, value(operand)?)?);
+                }
+            }
+            Ok((address, bytes))
+        }
+        "DW" => {
+            let mut bytes = Vec::new();
+            for operand in operands {
+                bytes.extend(&value(operand)?.to_le_bytes());
+            }
+            Ok((address, bytes))
+        }
+        "DS" => {
+            // The size has to be known in the first pass
+            let size = evaluate(single(operands)?, labels, address, true)?;
+            Ok((address, vec![0; size as usize]))
+        }
+        _ => {
+            let operation = operation(mnemonic, operands, &value)?;
+            Ok((address, Instruction::new(operation).bytes()))
+        }
+    }
+}
+
+fn single(operands: &[String]) -> Result<&str, String> {
+    match operands {
+        [operand] => Ok(operand),
+        _ => Err(format!("expected one operand, found {}", operands.len())),
+    }
+}
+
+// The byte `text` evaluated to: 0 to 255, or down to -128 when written with
+// a minus sign, which comes out of the evaluator as 0xff80..0xffff
+fn byte(text: &str, value: u16) -> Result<u8, String> {
+    let negative = text.trim_start().starts_with('-');
+    if value <= 0xff || (negative && value >= 0xff80) {
+        Ok(value as u8)
+    } else {
+        Err(format!("{} does not fit in a byte", text))
+    }
+}
+
+fn register(text: &str) -> Result<Register, String> {
+    match text.to_uppercase().as_str() {
+        "A" => Ok(Register::A),
+        "B" => Ok(Register::B),
+        "C" => Ok(Register::C),
+        "D" => Ok(Register::D),
+        "E" => Ok(Register::E),
+        "H" => Ok(Register::H),
+        "L" => Ok(Register::L),
+        "M" => Ok(Register::M),
+        _ => Err(format!("expected a register, found {:?}", text)),
+    }
+}
+
+// B, D, H and SP, or PSW when `psw`; BC, DE and HL are taken too
+fn pair(text: &str, psw: bool) -> Result<RegisterPair, String> {
+    match text.to_uppercase().as_str() {
+        "B" | "BC" => Ok(RegisterPair::BC),
+        "D" | "DE" => Ok(RegisterPair::DE),
+        "H" | "HL" => Ok(RegisterPair::HL),
+        "SP" if !psw => Ok(RegisterPair::SP),
+        "PSW" if psw => Ok(RegisterPair::PSW),
+        _ => Err(format!("expected a register pair, found {:?}", text)),
+    }
+}
+
+fn condition(text: &str) -> Option<Condition> {
+    match text {
+        "NZ" => Some(Condition::NZ),
+        "Z" => Some(Condition::Z),
+        "NC" => Some(Condition::NC),
+        "C" => Some(Condition::C),
+        "PO" => Some(Condition::PO),
+        "PE" => Some(Condition::PE),
+        "P" => Some(Condition::P),
+        "M" => Some(Condition::M),
+        _ => None,
+    }
+}
+
+fn operation<F>(mnemonic: &str, operands: &[String], value: &F) -> Result<Operation, String>
+where
+    F: Fn(&str) -> Result<u16, String>,
+{
+    let count = |n: usize| {
+        if operands.len() == n {
+            Ok(())
+        } else {
+            Err(format!("{} takes {} operands, found {}", mnemonic, n, operands.len()))
+        }
+    };
+    let immediate = || -> Result<u8, String> {
+        count(1)?;
+        byte(&operands[0], value(&operands[0])?)
+    };
+    let word = || -> Result<u16, String> {
+        count(1)?;
+        value(&operands[0])
+    };
+    let reg = || -> Result<Register, String> {
+        count(1)?;
+        register(&operands[0])
+    };
+    let rp = |psw: bool| -> Result<RegisterPair, String> {
+        count(1)?;
+        pair(&operands[0], psw)
+    };
+    let none = |operation: Operation| -> Result<Operation, String> {
+        count(0)?;
+        Ok(operation)
+    };
+
+    let operation = match mnemonic {
+        "NOP" => none(Operation::Nop)?,
+        "LXI" => {
+            count(2)?;
+            Operation::Lxi(pair(&operands[0], false)?, value(&operands[1])?)
+        }
+        "STAX" | "LDAX" => {
+            let rp = rp(false)?;
+            if rp != RegisterPair::BC && rp != RegisterPair::DE {
+                return Err(format!("{} only takes B or D", mnemonic));
+            }
+            if mnemonic == "STAX" {
+                Operation::Stax(rp)
+            } else {
+                Operation::Ldax(rp)
+            }
+        }
+        "SHLD" => Operation::Shld(word()?),
+        "LHLD" => Operation::Lhld(word()?),
+        "STA" => Operation::Sta(word()?),
+        "LDA" => Operation::Lda(word()?),
+        "INX" => Operation::Inx(rp(false)?),
+        "DCX" => Operation::Dcx(rp(false)?),
+        "DAD" => Operation::Dad(rp(false)?),
+        "INR" => Operation::Inr(reg()?),
+        "DCR" => Operation::Dcr(reg()?),
+        "MVI" => {
+            count(2)?;
+            Operation::Mvi(register(&operands[0])?, byte(&operands[1], value(&operands[1])?)?)
+        }
+        "RLC" => none(Operation::Rlc)?,
+        "RRC" => none(Operation::Rrc)?,
+        "RAL" => none(Operation::Ral)?,
+        "RAR" => none(Operation::Rar)?,
+        "DAA" => none(Operation::Daa)?,
+        "CMA" => none(Operation::Cma)?,
+        "STC" => none(Operation::Stc)?,
+        "CMC" => none(Operation::Cmc)?,
+        "MOV" => {
+            count(2)?;
+            let (destination, source) = (register(&operands[0])?, register(&operands[1])?);
+            if destination == Register::M && source == Register::M {
+                return Err("MOV M,M is HLT".to_string());
+            }
+            Operation::Mov(destination, source)
+        }
+        "HLT" => none(Operation::Hlt)?,
+        "ADD" => Operation::Add(reg()?),
+        "ADC" => Operation::Adc(reg()?),
+        "SUB" => Operation::Sub(reg()?),
+        "SBB" => Operation::Sbb(reg()?),
+        "ANA" => Operation::Ana(reg()?),
+        "XRA" => Operation::Xra(reg()?),
+        "ORA" => Operation::Ora(reg()?),
+        "CMP" => Operation::Cmp(reg()?),
+        "ADI" => Operation::Adi(immediate()?),
+        "ACI" => Operation::Aci(immediate()?),
+        "SUI" => Operation::Sui(immediate()?),
+        "SBI" => Operation::Sbi(immediate()?),
+        "ANI" => Operation::Ani(immediate()?),
+        "XRI" => Operation::Xri(immediate()?),
+        "ORI" => Operation::Ori(immediate()?),
+        "CPI" => Operation::Cpi(immediate()?),
+        "JMP" => Operation::Jmp(word()?),
+        "CALL" => Operation::Call(word()?),
+        "RET" => none(Operation::Ret)?,
+        "RST" => {
+            let n = word()?;
+            if n > 7 {
+                return Err(format!("RST takes 0 to 7, found {}", n));
+            }
+            Operation::Rst(n as u8)
+        }
+        "PCHL" => none(Operation::Pchl)?,
+        "PUSH" => Operation::Push(rp(true)?),
+        "POP" => Operation::Pop(rp(true)?),
+        "XTHL" => none(Operation::Xthl)?,
+        "SPHL" => none(Operation::Sphl)?,
+        "XCHG" => none(Operation::Xchg)?,
+        "IN" => Operation::In(immediate()?),
+        "OUT" => Operation::Out(immediate()?),
+        "EI" => none(Operation::Ei)?,
+        "DI" => none(Operation::Di)?,
+        _ => {
+            // Jcc, Ccc, Rcc
+            let mut chars = mnemonic.chars();
+            let kind = chars.next();
+            match (kind, condition(chars.as_str())) {
+                (Some('J'), Some(c)) => Operation::Jcc(c, word()?),
+                (Some('C'), Some(c)) => Operation::Ccc(c, word()?),
+                (Some('R'), Some(c)) => none(Operation::Rcc(c))?,
+                _ => return Err(format!("unknown mnemonic {}", mnemonic)),
+            }
+        }
+    };
+    Ok(operation)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(u16),
+    Name(String),
+    Operator(&'static str),
+    Open,
+    Close,
+}
+
+fn number(text: &str) -> Option<u16> {
+    let upper = text.to_uppercase();
+    let parsed = if let Some(hex) = upper.strip_prefix("0X") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(hex) = upper.strip_prefix("#$").or_else(|| upper.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(hex) = upper.strip_suffix('H') {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(binary) = upper.strip_suffix('B') {
+        u32::from_str_radix(binary, 2)
+    } else if let Some(octal) = upper.strip_suffix('Q').or_else(|| upper.strip_suffix('O')) {
+        u32::from_str_radix(octal, 8)
+    } else {
+        upper.strip_suffix('D').unwrap_or(&upper).parse()
+    };
+    parsed.ok().filter(|v| *v <= 0xffff).map(|v| v as u16)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            // A character constant
+            match (chars.get(i + 1), chars.get(i + 2)) {
+                (Some(&value), Some(&close)) if close == c => {
+                    tokens.push(Token::Number(value as u16));
+                    i += 3;
+                }
+                _ => return Err(format!("bad character constant in {:?}", text)),
+            }
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$' || c == '#' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == '$') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if word == "$" {
+                tokens.push(Token::Name(word));
+            } else if c.is_ascii_digit() || c == '$' || c == '#' {
+                tokens.push(Token::Number(number(&word).ok_or_else(|| format!("bad number {:?}", word))?));
+            } else {
+                tokens.push(Token::Name(word));
+            }
+        } else if c == '(' {
+            tokens.push(Token::Open);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::Close);
+            i += 1;
+        } else {
+            let two: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let operator = ["<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~"]
+                .iter()
+                .find(|o| two.starts_with(*o))
+                .ok_or_else(|| format!("unexpected {:?} in {:?}", c, text))?;
+            tokens.push(Token::Operator(operator));
+            i += operator.len();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    labels: &'a BTreeMap<String, u16>,
+    address: u16,
+    resolve: bool,
+}
+
+fn evaluate(text: &str, labels: &BTreeMap<String, u16>, address: u16, resolve: bool) -> Result<u16, String> {
+    let mut evaluator = Evaluator { tokens: tokenize(text)?, position: 0, labels, address, resolve };
+    if evaluator.tokens.is_empty() {
+        return Err("missing operand".to_string());
+    }
+    let value = evaluator.binary(0)?;
+    if evaluator.position != evaluator.tokens.len() {
+        return Err(format!("unexpected {:?} in {:?}", evaluator.tokens[evaluator.position], text));
+    }
+    Ok(value)
+}
+
+// Binary operators, loosest first
+const PRECEDENCE: [&[&str]; 4] = [&["|", "^"], &["&"], &["<<", ">>", "+", "-"], &["*", "/", "%"]];
+
+impl<'a> Evaluator<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn binary(&mut self, level: usize) -> Result<u16, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        while let Some(Token::Operator(operator)) = self.tokens.get(self.position).cloned() {
+            if !PRECEDENCE[level].contains(&operator) {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            value = match operator {
+                "|" => value | right,
+                "^" => value ^ right,
+                "&" => value & right,
+                "<<" => value.checked_shl(right as u32).unwrap_or(0),
+                ">>" => value.checked_shr(right as u32).unwrap_or(0),
+                "+" => value.wrapping_add(right),
+                "-" => value.wrapping_sub(right),
+                "*" => value.wrapping_mul(right),
+                _ if right == 0 => return Err("division by zero".to_string()),
+                "/" => value / right,
+                _ => value % right,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<u16, String> {
+        match self.next() {
+            Some(Token::Operator("-")) => Ok(self.unary()?.wrapping_neg()),
+            Some(Token::Operator("+")) => self.unary(),
+            Some(Token::Operator("~")) => Ok(!self.unary()?),
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Open) => {
+                let value = self.binary(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    _ => Err("missing )".to_string()),
+                }
+            }
+            Some(Token::Name(name)) => match name.to_uppercase().as_str() {
+                "$" => Ok(self.address),
+                "HIGH" => Ok(self.unary()? >> 8),
+                "LOW" => Ok(self.unary()? & 0xff),
+                _ => match self.labels.get(&name) {
+                    Some(value) => Ok(*value),
+                    None if !self.resolve => Ok(0),
+                    None => Err(format!("undefined symbol {}", name)),
+                },
+            },
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("expression ends early".to_string()),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+// Runs a CP/M-80 .COM program on the 8080 core, with the console on
+// stdin/stdout and drive A: mapped to a host directory.
+//
+//     cpm8080 [--dir DIRECTORY] [--trace FILE [--trace-pc START-END]
+//             [--trace-count FIRST-LAST]] PROGRAM.COM [ARGUMENTS...]
+//
+// The trace has a line per instruction, in the format of `trace::Tracer`.
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+
+use emulator8080::cpm::{Cpm, CpmExit, StdioConsole};
+use emulator8080::trace::{Filter, Tracer};
+
+const USAGE: &str = "usage: cpm8080 [--dir DIRECTORY] [--trace FILE [--trace-pc START-END] \
+                     [--trace-count FIRST-LAST]] PROGRAM.COM [ARGUMENTS...]";
+
+fn main() {
+    let mut arguments = env::args().skip(1).collect::<Vec<_>>();
+    let mut directory = String::from(".");
+    let mut trace = None;
+    let mut filter = Filter::default();
+    while arguments.first().map(|a| a.starts_with("--")).unwrap_or(false) {
+        if arguments.len() < 2 {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+        let value = arguments.remove(1);
+        let parsed = match arguments.remove(0).as_str() {
+            "--dir" => {
+                directory = value;
+                Ok(())
+            }
+            "--trace" => {
+                trace = Some(value);
+                Ok(())
+            }
+            "--trace-pc" => Filter::parse_pc_range(&value).map(|range| filter.pc_range = Some(range)),
+            "--trace-count" => Filter::parse_window(&value).map(|window| filter.window = Some(window)),
+            _ => Err(USAGE.to_string()),
+        };
+        if let Err(message) = parsed {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    }
+    if arguments.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let path = arguments.remove(0);
+
+    let program = match fs::read(&path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("cpm8080: cannot read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let mut cpm = match Cpm::with_console(&program, StdioConsole::new()) {
+        Ok(cpm) => cpm.with_directory(&directory),
+        Err(e) => {
+            eprintln!("cpm8080: cannot load {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    if let Some(trace) = &trace {
+        match Tracer::create(trace, filter) {
+            Ok(tracer) => cpm.tracer = Some(tracer),
+            Err(e) => {
+                eprintln!("cpm8080: cannot create {}: {}", trace, e);
+                process::exit(1);
+            }
+        }
+    }
+    cpm.set_arguments(&arguments);
+    let exit = cpm.run(u64::MAX);
+    let _ = io::stdout().flush();
+    if let Some(Err(e)) = cpm.tracer.take().map(Tracer::finish) {
+        eprintln!("cpm8080: cannot write the trace: {}", e);
+    }
+    match exit {
+        Ok(CpmExit::WarmBoot) => {}
+        Ok(CpmExit::Halted) => eprintln!("\ncpm8080: halted at {:04x}", cpm.cpu.pc),
+        Ok(CpmExit::InstructionLimit) => unreachable!(),
+        Err(e) => {
+            eprintln!("\ncpm8080: {}", e);
+            eprintln!("{}", cpm.cpu);
+            process::exit(1);
+        }
+    }
+}
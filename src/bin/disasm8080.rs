@@ -0,0 +1,103 @@
+// Disassembles an 8080 binary.
+//
+//     disasm8080 [--symbols SYMBOLS] [--origin ADDRESS] [--entry ADDRESS]... FILE
+//     disasm8080 --linear [--zilog] [--hex] [--symbols SYMBOLS] [--origin ADDRESS] FILE
+//     disasm8080 --xref|--dot [--symbols SYMBOLS] [--origin ADDRESS] [--entry ADDRESS]... FILE
+//
+// By default the control flow is traced from the entry points (0x0000,
+// 0x0008 and 0x0010, the Space Invaders vectors, for a ROM at 0; the origin
+// otherwise) and the output is a listing that assembles back to FILE.
+// --linear decodes every byte as an instruction instead, --xref lists who
+// calls, jumps to, reads and writes each address, and --dot prints the call
+// graph for Graphviz. Names from a symbol file (src/invaders.sym for Space
+// Invaders) replace the addresses they stand for.
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::process;
+
+use emulator8080::code_map::{CodeMap, INVADERS_ENTRY_POINTS};
+use emulator8080::dissassembler::{write_listing, Options, Syntax};
+use emulator8080::symbols::SymbolTable;
+use emulator8080::xref::CrossReference;
+
+const USAGE: &str =
+    "usage: disasm8080 [--linear [--zilog] [--hex] | --xref | --dot] [--symbols SYMBOLS] [--origin ADDRESS] [--entry ADDRESS]... FILE";
+
+enum Report {
+    Listing,
+    Linear,
+    CrossReference,
+    CallGraph,
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut options = Options::default();
+    let mut report = Report::Listing;
+    let mut origin = 0;
+    let mut entry_points = Vec::new();
+    let mut path = None;
+    let mut symbols = SymbolTable::new();
+
+    let mut arguments = env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--linear" => report = Report::Linear,
+            "--xref" => report = Report::CrossReference,
+            "--dot" => report = Report::CallGraph,
+            "--zilog" => options.syntax = Syntax::Zilog,
+            "--hex" => options.hex_dump = true,
+            "--symbols" => {
+                let file = arguments.next().unwrap_or_else(|| usage());
+                symbols = SymbolTable::load(&file).unwrap_or_else(|e| {
+                    eprintln!("disasm8080: {}: {}", file, e);
+                    process::exit(1);
+                });
+            }
+            "--origin" => origin = arguments.next().and_then(|a| parse_address(&a)).unwrap_or_else(|| usage()),
+            "--entry" => entry_points.push(arguments.next().and_then(|a| parse_address(&a)).unwrap_or_else(|| usage())),
+            _ if path.is_none() && !argument.starts_with("--") => path = Some(argument),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+    let rom = fs::read(&path).unwrap_or_else(|e| {
+        eprintln!("disasm8080: cannot read {}: {}", path, e);
+        process::exit(1);
+    });
+    if entry_points.is_empty() {
+        entry_points = if origin == 0 { INVADERS_ENTRY_POINTS.to_vec() } else { vec![origin] };
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let map = CodeMap::trace_with_symbols(&rom, origin, &entry_points, symbols.clone());
+    let written = match report {
+        Report::Linear => write_listing(&mut out, &rom, origin as usize, Options { symbols: Some(&symbols), ..options }),
+        Report::Listing => map.write_listing(&mut out, &rom),
+        Report::CrossReference => CrossReference::new(&map).write_report(&mut out),
+        Report::CallGraph => CrossReference::new(&map).write_dot(&mut out),
+    };
+    if let Err(e) = written.and_then(|_| out.flush()) {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("disasm8080: {}", e);
+            process::exit(1);
+        }
+    }
+}
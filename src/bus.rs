@@ -0,0 +1,52 @@
+use crate::cpu::MEMORY_SIZE;
+
+/// Everything the CPU core can reach outside its own registers.
+///
+/// `emulate_8080_op` goes through this trait for every memory access and for
+/// the `IN`/`OUT` instructions, so a machine decides how its address space
+/// looks (ROM protection, mirroring, memory mapped devices) and what sits
+/// behind each port.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    fn port_in(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    fn port_out(&mut self, _port: u8, _value: u8) {}
+}
+
+/// A flat 64KB of RAM with nothing connected to the ports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Memory {
+    pub bytes: Vec<u8>,
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            bytes: vec![0; MEMORY_SIZE],
+        }
+    }
+
+    pub fn load(&mut self, data: &[u8], start: usize) {
+        self.bytes[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for Memory {
+    fn read(&mut self, address: u16) -> u8 {
+        self.bytes[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.bytes[address as usize] = value;
+    }
+}
@@ -0,0 +1,209 @@
+use crate::cpu::CPUState;
+use crate::instruction::Operation;
+use crate::symbols::SymbolTable;
+use std::fmt;
+use std::sync::Arc;
+
+/// How a routine was entered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Entry {
+    Call,
+    Rst(u8),
+    /// The hardware jamming RST `n` onto the bus.
+    Interrupt(u8),
+}
+
+/// A routine that has been entered and not yet returned from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub entry: Entry,
+    /// The CALL or RST, or for an interrupt the instruction it came before.
+    pub call_site: u16,
+    pub routine: u16,
+    pub return_address: u16,
+    /// Where the return address is on the stack.
+    pub sp: u16,
+}
+
+/// Something the stack did that calls and returns do not explain.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The RET at `at` went to `to`, which is not the return address of the
+    /// innermost routine, `expected` if there is one.
+    BadReturn { at: u16, to: u16, expected: Option<u16> },
+    /// The RET at `at` returned from `routine` with SP at `sp`, not at the
+    /// return address the call pushed at `expected`: the routine pushed
+    /// more than it popped, or the other way around.
+    Unbalanced { at: u16, routine: u16, sp: u16, expected: u16 },
+    /// The instruction at `at` moved SP past the return address of
+    /// `routine`, which can then not return normally.
+    Discarded { at: u16, routine: u16 },
+}
+
+impl Mismatch {
+    /// The address of the instruction that did it.
+    pub fn at(&self) -> u16 {
+        match *self {
+            Mismatch::BadReturn { at, .. } | Mismatch::Unbalanced { at, .. } | Mismatch::Discarded { at, .. } => at,
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::BadReturn { at, to, expected: Some(expected) } => {
+                write!(f, "RET at {:04x} to {:04x}, not to the return address {:04x}", at, to, expected)
+            }
+            Mismatch::BadReturn { at, to, expected: None } => {
+                write!(f, "RET at {:04x} to {:04x}, with no call to return from", at, to)
+            }
+            Mismatch::Unbalanced { at, routine, sp, expected } => write!(
+                f,
+                "RET at {:04x} from {:04x} with SP at {:04x}, the return address is at {:04x}",
+                at, routine, sp, expected
+            ),
+            Mismatch::Discarded { at, routine } => {
+                write!(f, "{:04x} dropped the return address of {:04x} from the stack", at, routine)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    frame: Frame,
+    below: Option<Arc<Node>>,
+}
+
+/// A shadow of the routines the program is in, innermost first, kept by
+/// following every call and return since the 8080's stack does not say
+/// which of the words on it are return addresses.
+///
+/// Clones share their frames, so keeping one per instruction costs a
+/// pointer.
+#[derive(Clone, Debug, Default)]
+pub struct CallStack {
+    top: Option<Arc<Node>>,
+    depth: usize,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The frames, innermost first.
+    pub fn frames(&self) -> impl Iterator<Item = &Frame> {
+        let mut node = self.top.as_deref();
+        std::iter::from_fn(move || {
+            let current = node?;
+            node = current.below.as_deref();
+            Some(&current.frame)
+        })
+    }
+
+    pub fn top(&self) -> Option<&Frame> {
+        self.top.as_ref().map(|node| &node.frame)
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        self.top = Some(Arc::new(Node { frame, below: self.top.take() }));
+        self.depth += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<Frame> {
+        let node = self.top.take()?;
+        self.top = node.below.clone();
+        self.depth -= 1;
+        Some(node.frame)
+    }
+
+    /// Follows `operation`, which took the CPU from `before` to `after`.
+    pub fn execute(&mut self, before: &CPUState, after: &CPUState, operation: Operation) -> Option<Mismatch> {
+        if before.halted {
+            return None;
+        }
+        let at = before.pc;
+        match operation {
+            Operation::Call(_) | Operation::Rst(_) => {}
+            Operation::Ccc(condition, _) if condition.holds(&before.cc) => {}
+            Operation::Ret => return self.ret(at, before.sp, after),
+            Operation::Rcc(condition) if condition.holds(&before.cc) => return self.ret(at, before.sp, after),
+            _ => return self.discard(at, after.sp, matches!(operation, Operation::Pop(_))),
+        }
+        let entry = match operation {
+            Operation::Rst(n) => Entry::Rst(n),
+            _ => Entry::Call,
+        };
+        let return_address = before.pc.wrapping_add(if entry == Entry::Call { 3 } else { 1 });
+        self.push(Frame { entry, call_site: at, routine: after.pc, return_address, sp: after.sp });
+        None
+    }
+
+    /// Follows the interrupt that took the CPU from `before` to `after`.
+    pub fn interrupt(&mut self, before: &CPUState, after: &CPUState, number: u8) {
+        let frame = Frame {
+            entry: Entry::Interrupt(number),
+            call_site: before.pc,
+            routine: after.pc,
+            return_address: before.pc,
+            sp: after.sp,
+        };
+        self.push(frame);
+    }
+
+    fn ret(&mut self, at: u16, sp: u16, after: &CPUState) -> Option<Mismatch> {
+        let top = match self.top() {
+            Some(top) => *top,
+            None => return Some(Mismatch::BadReturn { at, to: after.pc, expected: None }),
+        };
+        if top.return_address != after.pc {
+            // Still drop the routines whose return addresses went with it
+            self.discard(at, after.sp, false);
+            return Some(Mismatch::BadReturn { at, to: after.pc, expected: Some(top.return_address) });
+        }
+        self.pop();
+        if top.sp != sp {
+            return Some(Mismatch::Unbalanced { at, routine: top.routine, sp, expected: top.sp });
+        }
+        None
+    }
+
+    // Drops the frames whose return addresses are now above SP, which only
+    // a POP is expected not to do
+    fn discard(&mut self, at: u16, sp: u16, flag: bool) -> Option<Mismatch> {
+        let mut dropped = None;
+        while let Some(top) = self.top() {
+            if top.sp >= sp {
+                break;
+            }
+            dropped = Some(top.routine);
+            self.pop();
+        }
+        dropped.filter(|_| flag).map(|routine| Mismatch::Discarded { at, routine })
+    }
+
+    /// A line per frame, innermost first: the current PC, then where each
+    /// routine was called from.
+    pub fn backtrace(&self, pc: u16, symbols: &SymbolTable) -> String {
+        let place = |address: u16| match symbols.describe(address) {
+            Some(name) => format!("{:04x} in {}", address, name),
+            None => format!("{:04x}", address),
+        };
+        let mut lines = vec![format!("#0  {}", place(pc))];
+        for (i, frame) in self.frames().enumerate() {
+            let how = match frame.entry {
+                Entry::Call => String::new(),
+                Entry::Rst(n) => format!(" (RST {})", n),
+                Entry::Interrupt(n) => format!(" (interrupt {})", n),
+            };
+            lines.push(format!("#{:<2} {}{}", i + 1, place(frame.call_site), how));
+        }
+        lines.join("\n")
+    }
+}
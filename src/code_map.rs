@@ -0,0 +1,239 @@
+use crate::instruction::{decode, Instruction, Operation};
+use crate::symbols::{SymbolKind, SymbolTable};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+/// Which bytes of a ROM are instructions, found by following the control
+/// flow from a set of entry points rather than sweeping linearly, so data
+/// tables are not decoded as code.
+///
+/// JMP, CALL, RST and conditional targets are followed; PCHL, RET and JMP
+/// end a path. Anything never reached is data. Targets of jumps, calls and
+/// 16 bit operands pointing into the ROM get `L_xxxx` labels, or their name
+/// from the symbol table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeMap {
+    pub origin: u16,
+    pub length: usize,
+    /// Where the trace started, not counting symbols.
+    pub entry_points: Vec<u16>,
+    /// Every instruction reached, by address.
+    pub instructions: BTreeMap<u16, Instruction>,
+    pub labels: BTreeSet<u16>,
+    pub symbols: SymbolTable,
+}
+
+// The entry points of the Space Invaders board: reset, and the two
+// interrupts it generates (RST 1 and RST 2)
+pub const INVADERS_ENTRY_POINTS: [u16; 3] = [0x0000, 0x0008, 0x0010];
+
+impl CodeMap {
+    /// Traces `rom`, loaded at `origin`, from each of `entry_points`.
+    pub fn trace(rom: &[u8], origin: u16, entry_points: &[u16]) -> CodeMap {
+        CodeMap::trace_with_symbols(rom, origin, entry_points, SymbolTable::new())
+    }
+
+    /// Like `trace`, also starting from every code symbol in the ROM, which
+    /// reaches routines only called through PCHL.
+    pub fn trace_with_symbols(rom: &[u8], origin: u16, entry_points: &[u16], symbols: SymbolTable) -> CodeMap {
+        let mut map = CodeMap {
+            origin,
+            length: rom.len(),
+            entry_points: entry_points.to_vec(),
+            instructions: BTreeMap::new(),
+            labels: BTreeSet::new(),
+            symbols,
+        };
+        // Bytes already claimed by an instruction
+        let mut claimed = vec![false; rom.len()];
+        let mut pending: Vec<u16> = entry_points.to_vec();
+        pending.extend(map.symbols.iter().filter(|s| s.kind == SymbolKind::Code).map(|s| s.address));
+        let named: Vec<u16> = map.symbols.iter().map(|s| s.address).collect();
+        for &entry in pending.iter().chain(&named) {
+            if map.contains(entry) {
+                map.labels.insert(entry);
+            }
+        }
+        // Entry points first, in the order given
+        pending.reverse();
+
+        while let Some(mut address) = pending.pop() {
+            while let Some(offset) = map.offset(address) {
+                if map.instructions.contains_key(&address) {
+                    break;
+                }
+                let instruction = decode(&rom[offset..]);
+                let length = instruction.length() as usize;
+                // Runs off the end, or into the middle of code already found
+                if offset + length > rom.len() || claimed[offset..offset + length].iter().any(|c| *c) {
+                    break;
+                }
+                for byte in &mut claimed[offset..offset + length] {
+                    *byte = true;
+                }
+                map.instructions.insert(address, instruction);
+
+                let next = address.wrapping_add(length as u16);
+                match instruction.operation {
+                    Operation::Jmp(target) => {
+                        map.follow(target, &mut pending);
+                        break;
+                    }
+                    Operation::Jcc(_, target) | Operation::Call(target) | Operation::Ccc(_, target) => {
+                        map.follow(target, &mut pending);
+                    }
+                    Operation::Rst(n) => map.follow(n as u16 * 8, &mut pending),
+                    Operation::Ret | Operation::Pchl => break,
+                    Operation::Lxi(_, value)
+                    | Operation::Lhld(value)
+                    | Operation::Shld(value)
+                    | Operation::Lda(value)
+                    | Operation::Sta(value)
+                        if map.contains(value) =>
+                    {
+                        map.labels.insert(value);
+                    }
+                    _ => {}
+                }
+                address = next;
+            }
+        }
+        map
+    }
+
+    fn follow(&mut self, target: u16, pending: &mut Vec<u16>) {
+        if self.contains(target) {
+            self.labels.insert(target);
+            pending.push(target);
+        }
+    }
+
+    fn offset(&self, address: u16) -> Option<usize> {
+        let offset = address.wrapping_sub(self.origin) as usize;
+        if address >= self.origin && offset < self.length {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        self.offset(address).is_some()
+    }
+
+    /// Whether `address` is part of an instruction that was reached.
+    pub fn is_code(&self, address: u16) -> bool {
+        match self.instructions.range(..=address).next_back() {
+            Some((&start, instruction)) => address - start < instruction.length() as u16,
+            None => false,
+        }
+    }
+
+    // A label can only be emitted where a line starts: at an instruction,
+    // or anywhere in data, which is split into DB lines as needed
+    fn has_label(&self, address: u16) -> bool {
+        self.labels.contains(&address) && (self.instructions.contains_key(&address) || !self.is_code(address))
+    }
+
+    /// The name `address` is written as in the listing.
+    pub fn label(&self, address: u16) -> Option<String> {
+        if self.has_label(address) {
+            match self.symbols.name(address) {
+                Some(name) => Some(name.to_string()),
+                None => Some(format!("L_{:04x}", address)),
+            }
+        } else if !self.contains(address) {
+            // Defined with EQU at the top of the listing
+            self.symbols.name(address).map(|name| name.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Writes `rom` as Intel 8080 source that assembles back to the same
+    /// bytes: instructions with labels for their targets, DB for data and
+    /// for undocumented opcodes, which have no mnemonic of their own.
+    pub fn write_listing<W: io::Write>(&self, out: &mut W, rom: &[u8]) -> io::Result<()> {
+        for symbol in self.symbols.iter().filter(|s| !self.contains(s.address)) {
+            write!(out, "{}\tEQU\t{}", symbol.name, hex16(symbol.address))?;
+            match &symbol.comment {
+                Some(comment) => writeln!(out, "\t; {}", comment)?,
+                None => writeln!(out)?,
+            }
+        }
+        writeln!(out, "\tORG\t{}", hex16(self.origin))?;
+        let mut offset = 0;
+        while offset < self.length {
+            let address = self.origin.wrapping_add(offset as u16);
+            if let Some(label) = self.label(address) {
+                match self.symbols.get(address).and_then(|s| s.comment.as_ref()) {
+                    Some(comment) => writeln!(out, "{}:\t\t; {}", label, comment)?,
+                    None => writeln!(out, "{}:", label)?,
+                }
+            }
+            match self.instructions.get(&address) {
+                Some(instruction) => {
+                    let bytes = instruction.bytes();
+                    let text = self.source(instruction);
+                    if instruction.is_undocumented() {
+                        let data = bytes.iter().map(|b| hex8(*b)).collect::<Vec<_>>().join(",");
+                        writeln!(out, "\tDB\t{}\t; {:04x}\t{}", data, address, text)?;
+                    } else {
+                        writeln!(out, "\t{}\t; {:04x}", text, address)?;
+                    }
+                    offset += bytes.len();
+                }
+                None => {
+                    // Up to 8 bytes of data, stopping at the next label or code
+                    let mut end = offset + 1;
+                    while end < self.length && end - offset < 8 {
+                        let next = self.origin.wrapping_add(end as u16);
+                        if self.has_label(next) || self.instructions.contains_key(&next) {
+                            break;
+                        }
+                        end += 1;
+                    }
+                    let data = rom[offset..end].iter().map(|b| hex8(*b)).collect::<Vec<_>>().join(",");
+                    writeln!(out, "\tDB\t{}\t; {:04x}", data, address)?;
+                    offset = end;
+                }
+            }
+        }
+        writeln!(out, "\tEND")
+    }
+
+    // The instruction in assembler syntax, its operand replaced by a label
+    // when there is one
+    fn source(&self, instruction: &Instruction) -> String {
+        let text = instruction.operation.to_string();
+        let value = match instruction.operation.operand() {
+            Some(value) => value,
+            None => return text,
+        };
+        let operand = match self.label(value) {
+            Some(label) if instruction.length() == 3 => label,
+            _ if instruction.length() == 3 => hex16(value),
+            _ => hex8(value as u8),
+        };
+        // Display puts the operand last, as #$xx or $xxxx
+        let start = text.find(['#', '$']).unwrap_or(text.len());
+        format!("{}{}", &text[..start], operand)
+    }
+}
+
+// Intel style numbers, which must not start with a letter: 0C3H
+fn hex8(value: u8) -> String {
+    if value >= 0xa0 {
+        format!("0{:02X}H", value)
+    } else {
+        format!("{:02X}H", value)
+    }
+}
+
+fn hex16(value: u16) -> String {
+    if value >= 0xa000 {
+        format!("0{:04X}H", value)
+    } else {
+        format!("{:04X}H", value)
+    }
+}
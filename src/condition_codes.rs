@@ -1,6 +1,6 @@
 use std::fmt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Flags {
     pub z: u8,
     pub s: u8,
@@ -21,6 +21,19 @@ impl Flags {
     }
 }
 
+impl Flags {
+    /// The flags as PUSH PSW stores them: S Z 0 AC 0 P 1 CY.
+    pub fn psw(&self) -> u8 {
+        self.s << 7 | self.z << 6 | self.ac << 4 | self.p << 2 | 1 << 1 | self.cy
+    }
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl fmt::Display for Flags {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
                 write!(f, "{}{}{}{}",
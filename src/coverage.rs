@@ -0,0 +1,196 @@
+use crate::bus::Bus;
+use crate::cpu::{step, CPUState, StepOutcome};
+use crate::dissassembler::{disassemble_with, Options};
+use crate::error::EmulationError;
+use crate::instruction::length;
+use crate::invaders::{INVADERS_MEMORY_SIZE, ROM_CHIPS, ROM_CHIP_SIZE, ROM_SIZE};
+use crate::symbols::SymbolTable;
+use std::io::{self, Write};
+
+// What happened to a ROM byte
+const START: u8 = 1;
+const CODE: u8 = 2;
+const DATA: u8 = 4;
+
+/// How a ROM byte was used during a run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Use {
+    /// Executed as part of an instruction, whether or not it was also read.
+    Code,
+    /// Read by an instruction, never executed.
+    Data,
+    Untouched,
+}
+
+/// Which bytes of the Space Invaders ROM were executed, read as data or
+/// never touched, wherever in the mirrored address space they were reached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    marks: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage { marks: vec![0; ROM_SIZE] }
+    }
+
+    fn mark(&mut self, address: u16, mark: u8) {
+        if let Some(marks) = self.marks.get_mut(address as usize % INVADERS_MEMORY_SIZE) {
+            *marks |= mark;
+        }
+    }
+
+    /// Marks the `length` bytes of an instruction executed at `pc`.
+    pub fn executed(&mut self, pc: u16, length: u8) {
+        self.mark(pc, START);
+        for i in 0..length {
+            self.mark(pc.wrapping_add(i as u16), CODE);
+        }
+    }
+
+    /// Marks a byte an instruction read as data.
+    pub fn read(&mut self, address: u16) {
+        self.mark(address, DATA);
+    }
+
+    /// Executes one instruction like `cpu::step`, marking what it fetched
+    /// and read.
+    pub fn step<B: Bus>(&mut self, cpu: &mut CPUState, bus: &mut B) -> Result<StepOutcome, EmulationError> {
+        let (pc, halted) = (cpu.pc, cpu.halted);
+        let mut reads = Reads { bus, reads: Vec::new() };
+        let outcome = step(cpu, &mut reads)?;
+        // The opcode and its operands are read first, the data after them
+        if let Some(&(_, opcode)) = reads.reads.first().filter(|_| !halted) {
+            let fetched = length(opcode);
+            self.executed(pc, fetched);
+            for &(address, _) in &reads.reads[fetched as usize..] {
+                self.read(address);
+            }
+        }
+        Ok(outcome)
+    }
+
+    pub fn usage(&self, address: u16) -> Use {
+        let marks = self.marks.get(address as usize % INVADERS_MEMORY_SIZE).copied().unwrap_or(0);
+        if marks & CODE != 0 {
+            Use::Code
+        } else if marks & DATA != 0 {
+            Use::Data
+        } else {
+            Use::Untouched
+        }
+    }
+
+    /// How many bytes from `start` to `end`, both included, were code, data
+    /// and untouched.
+    pub fn count(&self, start: u16, end: u16) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for address in start..=end {
+            match self.usage(address) {
+                Use::Code => counts.0 += 1,
+                Use::Data => counts.1 += 1,
+                Use::Untouched => counts.2 += 1,
+            }
+        }
+        counts
+    }
+
+    /// A line per ROM chip, and one for the whole ROM, with the share of
+    /// its bytes executed, read and untouched.
+    pub fn write_summary<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let chips = ROM_CHIPS.iter().map(|&(name, start)| (name, start, start + ROM_CHIP_SIZE as u16 - 1));
+        for (name, start, end) in chips.chain(std::iter::once(("total", 0, ROM_SIZE as u16 - 1))) {
+            let (code, data, untouched) = self.count(start, end);
+            let percent = |n: usize| 100.0 * n as f64 / (end - start + 1) as f64;
+            writeln!(
+                out,
+                "{:<11} {:04x}-{:04x}  code {:5.1}%  data {:5.1}%  untouched {:5.1}%",
+                name,
+                start,
+                end,
+                percent(code),
+                percent(data),
+                percent(untouched)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The summary as comments, then `rom` disassembled where it was
+    /// executed and as DB elsewhere, each line marked `x` when executed,
+    /// `r` when only read and `.` when untouched.
+    pub fn write_listing<W: Write>(&self, out: &mut W, rom: &[u8], symbols: &SymbolTable) -> io::Result<()> {
+        let mut summary = Vec::new();
+        self.write_summary(&mut summary)?;
+        for line in String::from_utf8_lossy(&summary).lines() {
+            writeln!(out, "; {}", line)?;
+        }
+        let options = Options { symbols: Some(symbols), ..Options::default() };
+        let end = rom.len().min(ROM_SIZE);
+        let mut offset = 0;
+        while offset < end {
+            let address = offset as u16;
+            if let Some(symbol) = symbols.get(address) {
+                writeln!(out, "{}:", symbol.name)?;
+            }
+            if self.marks[offset] & START != 0 {
+                let line = disassemble_with(&rom[offset..], offset, options);
+                writeln!(out, "x  {}", line)?;
+                offset += line.length();
+                continue;
+            }
+            // Up to 8 bytes used alike, stopping at an instruction or symbol
+            let usage = self.usage(address);
+            let mut next = offset + 1;
+            while next < end
+                && next - offset < 8
+                && self.marks[next] & START == 0
+                && self.usage(next as u16) == usage
+                && symbols.get(next as u16).is_none()
+            {
+                next += 1;
+            }
+            let marker = match usage {
+                Use::Code => 'x',
+                Use::Data => 'r',
+                Use::Untouched => '.',
+            };
+            let bytes = rom[offset..next].iter().map(|b| format!("${:02x}", b)).collect::<Vec<_>>();
+            writeln!(out, "{}  {:04x}\tDB\t{}", marker, address, bytes.join(","))?;
+            offset = next;
+        }
+        Ok(())
+    }
+}
+
+// A bus that remembers what was read from it
+struct Reads<'a, B: Bus> {
+    bus: &'a mut B,
+    reads: Vec<(u16, u8)>,
+}
+
+impl<'a, B: Bus> Bus for Reads<'a, B> {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.bus.read(address);
+        self.reads.push((address, value));
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.bus.write(address, value)
+    }
+
+    fn port_in(&mut self, port: u8) -> u8 {
+        self.bus.port_in(port)
+    }
+
+    fn port_out(&mut self, port: u8, value: u8) {
+        self.bus.port_out(port, value)
+    }
+}
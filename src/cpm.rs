@@ -0,0 +1,486 @@
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::bus::{Bus, Memory};
+use crate::cpu::{step, CPUState, StepOutcome, MEMORY_SIZE};
+use crate::error::EmulationError;
+use crate::helpers::pop_from_stack;
+use crate::trace::Tracer;
+
+// CP/M loads programs here, after the zero page
+pub const TPA_START: u16 = 0x0100;
+// Programs CALL 5 to reach the BDOS and JMP 0 to warm boot back to the CCP
+pub const BDOS_ENTRY: u16 = 0x0005;
+pub const WARM_BOOT: u16 = 0x0000;
+// Where the zero page says the BDOS starts, programs use it as the top of memory
+pub const BDOS_BASE: u16 = 0xfe00;
+// The most a program can be, from TPA_START up to the BDOS
+pub const TPA_SIZE: usize = (BDOS_BASE - TPA_START) as usize;
+// The CCP parses the first two arguments into these and copies the rest of the
+// command line to DEFAULT_DMA
+pub const DEFAULT_FCB: u16 = 0x005c;
+pub const DEFAULT_FCB2: u16 = 0x006c;
+pub const DEFAULT_DMA: u16 = 0x0080;
+pub const RECORD_SIZE: usize = 128;
+
+/// Why `Cpm::run` stopped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CpmExit {
+    /// The program jumped to 0x0000 or called BDOS function 0.
+    WarmBoot,
+    /// The program executed HLT.
+    Halted,
+    /// `max_instructions` ran out first.
+    InstructionLimit,
+}
+
+/// Why a program could not be loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CpmError {
+    /// The program is `size` bytes, more than `TPA_SIZE`.
+    TooLarge { size: usize },
+}
+
+impl fmt::Display for CpmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpmError::TooLarge { size } => {
+                write!(f, "the program is {} bytes, the TPA holds {}", size, TPA_SIZE)
+            }
+        }
+    }
+}
+
+impl error::Error for CpmError {}
+
+/// The terminal behind the BDOS console functions.
+pub trait Console {
+    fn put(&mut self, byte: u8);
+    /// Blocks until a byte is available, `None` once input is exhausted.
+    fn get(&mut self) -> Option<u8>;
+
+    fn ready(&mut self) -> bool {
+        false
+    }
+}
+
+/// A console that reads from a queue and collects everything printed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BufferConsole {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl Console for BufferConsole {
+    fn put(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+
+    fn get(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn ready(&mut self) -> bool {
+        !self.input.is_empty()
+    }
+}
+
+/// The host's stdin and stdout. Line feeds read from stdin are handed to the
+/// program as carriage returns, which is what CP/M programs wait for.
+///
+/// Stdin is read by a thread of its own, so that `ready` can tell whether a
+/// byte has come in without blocking.
+pub struct StdioConsole {
+    input: Receiver<u8>,
+    // A byte `ready` took from `input` and `get` has not handed over yet
+    next: Option<u8>,
+}
+
+impl StdioConsole {
+    pub fn new() -> StdioConsole {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut byte = [0];
+            while let Ok(1) = io::stdin().read(&mut byte) {
+                let byte = if byte[0] == b'\n' { b'\r' } else { byte[0] };
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        StdioConsole { input, next: None }
+    }
+}
+
+impl Default for StdioConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Console for StdioConsole {
+    fn put(&mut self, byte: u8) {
+        let _ = io::stdout().write_all(&[byte]);
+    }
+
+    fn get(&mut self) -> Option<u8> {
+        let _ = io::stdout().flush();
+        self.next.take().or_else(|| self.input.recv().ok())
+    }
+
+    // At the end of stdin there is always something to read: the end of file
+    fn ready(&mut self) -> bool {
+        let _ = io::stdout().flush();
+        if self.next.is_none() {
+            match self.input.try_recv() {
+                Ok(byte) => self.next = Some(byte),
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {}
+            }
+        }
+        true
+    }
+}
+
+/// Just enough of CP/M 2.2 to run `.COM` programs: the program at 0x0100 and
+/// a BDOS with console I/O and sequential file access.
+///
+/// The BDOS is trapped in Rust rather than emulated. Files live in a host
+/// directory, a file named `FOO.TXT` in the FCB maps to `foo.txt` (or any
+/// other case) there. Only drive A: exists.
+pub struct Cpm<C: Console = BufferConsole> {
+    pub cpu: CPUState,
+    pub memory: Memory,
+    pub console: C,
+    pub directory: Option<PathBuf>,
+    /// Where to write each instruction before running it, if anywhere.
+    pub tracer: Option<Tracer>,
+    dma: u16,
+    // Open files by the address of their FCB
+    files: HashMap<u16, File>,
+}
+
+impl Cpm<BufferConsole> {
+    pub fn new(program: &[u8]) -> Result<Cpm<BufferConsole>, CpmError> {
+        Cpm::with_console(program, BufferConsole::default())
+    }
+
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.console.output).into_owned()
+    }
+}
+
+impl<C: Console> Cpm<C> {
+    pub fn with_console(program: &[u8], console: C) -> Result<Cpm<C>, CpmError> {
+        if program.len() > TPA_SIZE {
+            return Err(CpmError::TooLarge { size: program.len() });
+        }
+        let mut memory = Memory::new();
+        memory.load(program, TPA_START as usize);
+        // JMP BDOS_BASE at 0x0005, which is never executed but tells
+        // programs how much memory they have
+        memory.load(&[0xc3, BDOS_BASE as u8, (BDOS_BASE >> 8) as u8], BDOS_ENTRY as usize);
+        let cpu = CPUState {
+            pc: TPA_START,
+            sp: BDOS_BASE,
+            ..CPUState::new()
+        };
+        Ok(Cpm {
+            cpu,
+            memory,
+            console,
+            directory: None,
+            tracer: None,
+            dma: DEFAULT_DMA,
+            files: HashMap::new(),
+        })
+    }
+
+    /// Lets the program open, create and delete files in `directory`.
+    pub fn with_directory<P: AsRef<Path>>(mut self, directory: P) -> Cpm<C> {
+        self.directory = Some(directory.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_tracer(mut self, tracer: Tracer) -> Cpm<C> {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Does what the CCP does with the arguments after the program name.
+    pub fn set_arguments(&mut self, arguments: &[String]) {
+        let tail = arguments
+            .iter()
+            .map(|argument| format!(" {}", argument.to_uppercase()))
+            .collect::<String>();
+        let tail = &tail.as_bytes()[..tail.len().min(RECORD_SIZE - 1)];
+        self.memory.write(DEFAULT_DMA, tail.len() as u8);
+        self.memory.load(tail, DEFAULT_DMA as usize + 1);
+
+        let fcb1 = arguments.first().map(|a| a.as_str()).unwrap_or("");
+        let fcb2 = arguments.get(1).map(|a| a.as_str()).unwrap_or("");
+        self.memory.load(&fcb_name(fcb1), DEFAULT_FCB as usize);
+        self.memory.load(&fcb_name(fcb2), DEFAULT_FCB2 as usize);
+    }
+
+    /// Runs the program until it exits or `max_instructions` is reached.
+    pub fn run(&mut self, max_instructions: u64) -> Result<CpmExit, EmulationError> {
+        for _ in 0..max_instructions {
+            match self.cpu.pc {
+                WARM_BOOT => return Ok(CpmExit::WarmBoot),
+                BDOS_ENTRY => {
+                    if self.bdos() {
+                        return Ok(CpmExit::WarmBoot);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(tracer) = &mut self.tracer {
+                tracer.trace(&self.cpu, &mut self.memory);
+            }
+            if step(&mut self.cpu, &mut self.memory)? == StepOutcome::Halted {
+                return Ok(CpmExit::Halted);
+            }
+        }
+        Ok(CpmExit::InstructionLimit)
+    }
+
+    // Handles the BDOS call in C and returns to the caller, true means exit
+    fn bdos(&mut self) -> bool {
+        let de = (self.cpu.d as u16) << 8 | self.cpu.e as u16;
+        let result: u16 = match self.cpu.c {
+            0 => return true,
+            1 => {
+                let character = self.console.get().unwrap_or(0x1a);
+                self.console.put(character);
+                character as u16
+            }
+            2 => {
+                self.console.put(self.cpu.e);
+                0
+            }
+            6 if self.cpu.e == 0xff => {
+                if self.console.ready() {
+                    self.console.get().unwrap_or(0) as u16
+                } else {
+                    0
+                }
+            }
+            6 => {
+                self.console.put(self.cpu.e);
+                0
+            }
+            9 => {
+                // Once round memory at most, should there be no $ in it
+                for i in 0..MEMORY_SIZE {
+                    let character = self.memory.read(de.wrapping_add(i as u16));
+                    if character == b'$' {
+                        break;
+                    }
+                    self.console.put(character);
+                }
+                0
+            }
+            10 => {
+                self.read_line(de);
+                0
+            }
+            11 => {
+                if self.console.ready() {
+                    0xff
+                } else {
+                    0
+                }
+            }
+            // CP/M 2.2
+            12 => 0x0022,
+            // Reset disk system, select disk, current disk
+            13 | 14 | 25 => 0,
+            15 => self.open_file(de),
+            16 => {
+                self.files.remove(&de);
+                0
+            }
+            19 => self.delete_file(de),
+            20 => self.read_record(de),
+            21 => self.write_record(de),
+            22 => self.make_file(de),
+            26 => {
+                self.dma = de;
+                0
+            }
+            _ => 0xff,
+        };
+        // BDOS results come back in HL, and in A and B for 8 bit callers
+        let (cpu, return_address) = pop_from_stack(self.cpu, &mut self.memory);
+        self.cpu = CPUState {
+            pc: return_address,
+            h: (result >> 8) as u8,
+            l: result as u8,
+            a: result as u8,
+            b: (result >> 8) as u8,
+            ..cpu
+        };
+        false
+    }
+
+    fn read_line(&mut self, buffer: u16) {
+        let max = self.memory.read(buffer);
+        let mut count: u8 = 0;
+        while count < max {
+            match self.console.get() {
+                None | Some(b'\r') => break,
+                Some(character) => {
+                    self.memory.write(buffer.wrapping_add(2 + count as u16), character);
+                    count += 1;
+                }
+            }
+        }
+        self.memory.write(buffer.wrapping_add(1), count);
+        self.console.put(b'\r');
+        self.console.put(b'\n');
+    }
+
+    // The host file an FCB names, matching the name without regard to case
+    fn host_path(&mut self, fcb: u16) -> Option<PathBuf> {
+        let name = self.fcb_file_name(fcb);
+        let directory = self.directory.as_ref()?;
+        let existing = fs::read_dir(directory).ok()?.filter_map(|entry| entry.ok()).find(|entry| {
+            entry.file_name().to_string_lossy().eq_ignore_ascii_case(&name)
+        });
+        match existing {
+            Some(entry) => Some(entry.path()),
+            None => Some(directory.join(name.to_lowercase())),
+        }
+    }
+
+    fn fcb_file_name(&mut self, fcb: u16) -> String {
+        let mut field = |start: u16, length: u16| {
+            (start..start + length)
+                .map(|offset| (self.memory.read(fcb.wrapping_add(offset)) & 0x7f) as char)
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        };
+        let name = field(1, 8);
+        let extension = field(9, 3);
+        if extension.is_empty() {
+            name
+        } else {
+            format!("{}.{}", name, extension)
+        }
+    }
+
+    fn open_with(&mut self, fcb: u16, options: &OpenOptions) -> u16 {
+        let file = self.host_path(fcb).and_then(|path| options.open(path).ok());
+        match file {
+            Some(file) => {
+                self.files.insert(fcb, file);
+                // Start at extent 0, record 0
+                self.memory.write(fcb.wrapping_add(12), 0);
+                self.memory.write(fcb.wrapping_add(32), 0);
+                0
+            }
+            None => 0xff,
+        }
+    }
+
+    fn open_file(&mut self, fcb: u16) -> u16 {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        if self.host_path(fcb).map(|path| path.is_file()) != Some(true) {
+            return 0xff;
+        }
+        self.open_with(fcb, &options)
+    }
+
+    fn make_file(&mut self, fcb: u16) -> u16 {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true).truncate(true);
+        self.open_with(fcb, &options)
+    }
+
+    fn delete_file(&mut self, fcb: u16) -> u16 {
+        self.files.remove(&fcb);
+        match self.host_path(fcb).map(fs::remove_file) {
+            Some(Ok(())) => 0,
+            _ => 0xff,
+        }
+    }
+
+    // The record the FCB points at, and moves it on to the next one
+    fn next_record(&mut self, fcb: u16) -> u64 {
+        let extent = self.memory.read(fcb.wrapping_add(12)) as u64;
+        let record = self.memory.read(fcb.wrapping_add(32)) as u64;
+        if record == 127 {
+            self.memory.write(fcb.wrapping_add(12), (extent + 1) as u8);
+            self.memory.write(fcb.wrapping_add(32), 0);
+        } else {
+            self.memory.write(fcb.wrapping_add(32), (record + 1) as u8);
+        }
+        extent * 128 + record
+    }
+
+    fn read_record(&mut self, fcb: u16) -> u16 {
+        let position = (self.memory.read(fcb.wrapping_add(12)) as u64 * 128
+            + self.memory.read(fcb.wrapping_add(32)) as u64)
+            * RECORD_SIZE as u64;
+        let mut record = [0x1a; RECORD_SIZE];
+        let read = match self.files.get_mut(&fcb) {
+            Some(file) => file.seek(SeekFrom::Start(position)).and_then(|_| file.read(&mut record)),
+            None => return 0xff,
+        };
+        match read {
+            Ok(0) | Err(_) => 1,
+            Ok(_) => {
+                self.next_record(fcb);
+                // The DMA address is the program's, so the record may wrap
+                for (i, &byte) in record.iter().enumerate() {
+                    self.memory.write(self.dma.wrapping_add(i as u16), byte);
+                }
+                0
+            }
+        }
+    }
+
+    fn write_record(&mut self, fcb: u16) -> u16 {
+        let record: Vec<u8> = (0..RECORD_SIZE as u16)
+            .map(|offset| self.memory.read(self.dma.wrapping_add(offset)))
+            .collect();
+        let position = self.next_record(fcb) * RECORD_SIZE as u64;
+        let written = match self.files.get_mut(&fcb) {
+            Some(file) => file.seek(SeekFrom::Start(position)).and_then(|_| file.write_all(&record)),
+            None => return 0xff,
+        };
+        match written {
+            Ok(()) => 0,
+            // Disk full
+            Err(_) => 2,
+        }
+    }
+}
+
+// An unopened FCB for a file name like "foo.txt"
+fn fcb_name(argument: &str) -> [u8; 16] {
+    let mut fcb = [b' '; 16];
+    fcb[0] = 0;
+    fcb[12..].copy_from_slice(&[0, 0, 0, 0]);
+    let argument = argument.to_uppercase();
+    let mut parts = argument.splitn(2, '.');
+    let name = parts.next().unwrap_or("").as_bytes();
+    let extension = parts.next().unwrap_or("").as_bytes();
+    for (i, byte) in name.iter().take(8).enumerate() {
+        fcb[1 + i] = *byte;
+    }
+    for (i, byte) in extension.iter().take(3).enumerate() {
+        fcb[9 + i] = *byte;
+    }
+    fcb
+}
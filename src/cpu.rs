@@ -1,8 +1,12 @@
 #![allow(dead_code)]
+use crate::bus::Bus;
 use crate::condition_codes::{Flags as ConditionCodes};
+use crate::error::EmulationError;
 use crate::op_arithmetic::*;
 use crate::op_data_transfer::*;
 use crate::helpers::get_value_memory;
+use crate::instruction::{decode, length, Condition, Instruction, Operation, Register, RegisterPair};
+use crate::symbols::SymbolTable;
 use std::fmt;
 use crate::op_logical::*;
 use crate::op_branch::*;
@@ -10,6 +14,9 @@ use crate::op_stack::*;
 use crate::op_special_io::*;
 
 pub const MEMORY_SIZE: usize = 0x10000;
+// Conditional CALLs and RETs cost this much more than the table says when
+// the condition holds (Ccc: 11/17, Rcc: 5/11)
+pub const CONDITIONAL_TAKEN_CYCLES: u8 = 6;
 pub const CYCLES8080: [u8;256] = [
                 4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, //0x00..0x0f
                 4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, //0x10..0x1f
@@ -26,10 +33,10 @@ pub const CYCLES8080: [u8;256] = [
                 4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
                 4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
 
-                11, 10, 10, 10, 17, 11, 7, 11, 11, 10, 10, 10, 10, 17, 7, 11, //0xc0..0xcf
-                11, 10, 10, 10, 17, 11, 7, 11, 11, 10, 10, 10, 10, 17, 7, 11,
-                11, 10, 10, 18, 17, 11, 7, 11, 11, 5, 10, 5, 17, 17, 7, 11,
-                11, 10, 10, 4, 17, 11, 7, 11, 11, 5, 10, 4, 17, 17, 7, 11
+                5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11, //0xc0..0xcf
+                5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11,
+                5, 10, 10, 18, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11,
+                5, 10, 10, 4, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11
             ];
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -57,7 +64,7 @@ pub enum Registers {
     L,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct CPUState {
     pub a: u8,
     pub b: u8,
@@ -68,10 +75,28 @@ pub struct CPUState {
     pub l: u8,
     pub sp: u16,
     pub pc: u16,
+    // T-states taken by the last instruction
     pub cycles: u8,
-    pub memory: Vec<u8>,
+    // T-states since reset, never wraps in practice (~290000 years at 2MHz)
+    pub total_cycles: u64,
     pub cc: ConditionCodes,
     pub int_enable: bool,
+    // Set by HLT, cleared when an interrupt is taken
+    pub halted: bool,
+    pub checks: Checks,
+}
+
+/// Faults `step` reports on top of what the 8080 does. All are off by
+/// default: real programs wrap SP and use the undocumented opcodes.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Checks {
+    /// Report the undocumented aliases of NOP, JMP, CALL and RET as
+    /// `IllegalOpcode`.
+    pub documented_only: bool,
+    /// The lowest and highest SP the program may have: a push that would
+    /// take SP below the first is a `StackOverflow`, a pop that would take
+    /// it above the second a `StackUnderflow`.
+    pub stack: Option<(u16, u16)>,
 }
 
 impl CPUState {
@@ -87,347 +112,305 @@ impl CPUState {
             sp: 0,
             pc: 0,
             cycles: 0,
-            memory: vec![0; MEMORY_SIZE],
+            total_cycles: 0,
             cc: ConditionCodes::new(),
             int_enable: false,
+            halted: false,
+            checks: Checks::default(),
         }
     }
+}
 
-    pub fn load_memory(&mut self, rom: &Vec<u8>, size: usize) {
-        self.memory[..size].copy_from_slice(rom);
+impl Default for CPUState {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl fmt::Display for CPUState {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-                write!(f, "Registers -> AF: {:02x}00, BC: {:02x}{:02x}, DE: {:02x}{:02x}, HL: {:02x}{:02x} \n
-Flags -> Z: {:02x} S: {:02x} P: {:02x} CY: {:02x}\n
-PC/SP -> PC: {:04x}, SP: {:04x}\n -----------------------------------------------------------------------------------",
-                self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.cc.z, self.cc.s, self.cc.p, self.cc.cy, self.pc, self.sp)
+                write_state(f, self, |address| format!("{:04x}", address))
         }
 }
 
-pub fn emulate_8080_op(cpu: CPUState) -> CPUState {
-    let opcode: u8 = cpu.memory[cpu.pc as usize];
-    let next_opcode: u8 = cpu.memory[cpu.pc.wrapping_add(1) as usize];
-    let next_next_opcode: u8 = cpu.memory[cpu.pc.wrapping_add(2) as usize];
-    let new_cpu_state = match opcode {
-        0x00 => nop(cpu),
-        // LXI OPS
-        0x01 => lxi(cpu, ('b', 'c'), next_opcode, next_next_opcode),
-        0x11 => lxi(cpu, ('d', 'e'), next_opcode, next_next_opcode),
-        0x21 => lxi(cpu, ('h', 'l'), next_opcode, next_next_opcode),
-        0x31 => lxi(cpu, ('s', 'p'), next_opcode, next_next_opcode),
-
-        // INX OPS
-        0x03 => inx(cpu, WithSPPairs::BC),
-        0x13 => inx(cpu, WithSPPairs::DE),
-        0x23 => inx(cpu, WithSPPairs::HL),
-        0x33 => inx(cpu, WithSPPairs::SP),
-
-        // DCX OPS
-        0x0b => dcx(cpu, WithSPPairs::BC),
-        0x1b => dcx(cpu, WithSPPairs::DE),
-        0x2b => dcx(cpu, WithSPPairs::HL),
-        0x3b => dcx(cpu, WithSPPairs::SP),
-
-        //DAD OPS
-        0x09 => dad(cpu, ('b', 'c')),
-        0x19 => dad(cpu, ('d', 'e')),
-        0x29 => dad(cpu, ('h', 'l')),
-        0x39 => dad(cpu, ('s', 'p')),
-
-        // INR OPS
-        0x3c => inr_r(cpu, 'a'),
-        0x04 => inr_r(cpu, 'b'),
-        0x0c => inr_r(cpu, 'c'),
-        0x14 => inr_r(cpu, 'd'),
-        0x1c => inr_r(cpu, 'e'),
-        0x24 => inr_r(cpu, 'h'),
-        0x2c => inr_r(cpu, 'l'),
-        0x34 => inr_m(cpu),
-
-        // DCR OPS
-        0x3d => dcr_r(cpu, 'a'),
-        0x05 => dcr_r(cpu, 'b'),
-        0x0d => dcr_r(cpu, 'c'),
-        0x15 => dcr_r(cpu, 'd'),
-        0x1d => dcr_r(cpu, 'e'),
-        0x25 => dcr_r(cpu, 'h'),
-        0x2d => dcr_r(cpu, 'l'),
-        0x35 => dcr_m(cpu),
-
-        // MVI
-        0x06 => mvi_r(cpu, 'b', next_opcode),
-        0x0e => mvi_r(cpu, 'c', next_opcode),
-        0x16 => mvi_r(cpu, 'd', next_opcode),
-        0x1e => mvi_r(cpu, 'e', next_opcode),
-        0x26 => mvi_r(cpu, 'h', next_opcode),
-        0x2e => mvi_r(cpu, 'l', next_opcode),
-        0x36 => mvi_m(cpu, next_opcode),
-        0x3e => mvi_r(cpu, 'a', next_opcode),
-
-        // STAX OPS
-        0x02 => stax(cpu, ('b', 'c')),
-        0x12 => stax(cpu, ('d', 'e')),
-
-        // LDAX
-        0x0a => ldax(cpu, ('b', 'c')),
-        0x1a => ldax(cpu, ('d', 'e')),
-
-        // STA
-        0x32 => sta(cpu),
-
-        // LDA
-        0x3a => lda(cpu, next_opcode, next_next_opcode),
-
-        // SHLD
-        0x22 => shld(cpu),
-
-        // LHLD
-        0x2a => lhld(cpu),
-
-        // MOV OPS
-        0x41 => mov_r_r(Registers::B, cpu.c, cpu),
-        0x42 => mov_r_r(Registers::B, cpu.d, cpu),
-        0x43 => mov_r_r(Registers::B, cpu.e, cpu),
-        0x44 => mov_r_r(Registers::B, cpu.h, cpu),
-        0x45 => mov_r_r(Registers::B, cpu.l, cpu),
-        0x46 => mov_r_m(cpu, Registers::B),
-        0x47 => mov_r_r(Registers::B, cpu.a, cpu),
-        0x48 => mov_r_r(Registers::C, cpu.b, cpu),
-        0x4a => mov_r_r(Registers::C, cpu.d, cpu),
-        0x4b => mov_r_r(Registers::C, cpu.e, cpu),
-        0x4c => mov_r_r(Registers::C, cpu.h, cpu),
-        0x4d => mov_r_r(Registers::C, cpu.l, cpu),
-        0x4e => mov_r_m(cpu, Registers::C),
-        0x4f => mov_r_r(Registers::C, cpu.a, cpu),
-        0x50 => mov_r_r(Registers::D, cpu.b, cpu),
-        0x51 => mov_r_r(Registers::D, cpu.c, cpu),
-        0x53 => mov_r_r(Registers::D, cpu.e, cpu),
-        0x54 => mov_r_r(Registers::D, cpu.h, cpu),
-        0x55 => mov_r_r(Registers::D, cpu.l, cpu),
-        0x56 => mov_r_m(cpu, Registers::D),
-        0x57 => mov_r_r(Registers::D, cpu.a, cpu),
-        0x58 => mov_r_r(Registers::E, cpu.b, cpu),
-        0x59 => mov_r_r(Registers::E, cpu.c, cpu),
-        0x5a => mov_r_r(Registers::E, cpu.d, cpu),
-        0x5c => mov_r_r(Registers::E, cpu.h, cpu),
-        0x5d => mov_r_r(Registers::E, cpu.l, cpu),
-        0x5e => mov_r_m(cpu, Registers::E),
-        0x5f => mov_r_r(Registers::E, cpu.a, cpu),
-        0x60 => mov_r_r(Registers::H, cpu.b, cpu),
-        0x61 => mov_r_r(Registers::H, cpu.c, cpu),
-        0x62 => mov_r_r(Registers::H, cpu.d, cpu),
-        0x63 => mov_r_r(Registers::H, cpu.e, cpu),
-        0x65 => mov_r_r(Registers::H, cpu.l, cpu),
-        0x66 => mov_r_m(cpu, Registers::H),
-        0x67 => mov_r_r(Registers::H, cpu.a, cpu),
-        0x68 => mov_r_r(Registers::L, cpu.b, cpu),
-        0x69 => mov_r_r(Registers::L, cpu.c, cpu),
-        0x6a => mov_r_r(Registers::L, cpu.d, cpu),
-        0x6b => mov_r_r(Registers::L, cpu.e, cpu),
-        0x6c => mov_r_r(Registers::L, cpu.h, cpu),
-        0x6e => mov_r_m(cpu, Registers::L),
-        0x6f => mov_r_r(Registers::L, cpu.a, cpu),
-        0x70 => mov_m_r(cpu, Registers::B),
-        0x71 => mov_m_r(cpu, Registers::C),
-        0x72 => mov_m_r(cpu, Registers::D),
-        0x73 => mov_m_r(cpu, Registers::E),
-        0x74 => mov_m_r(cpu, Registers::H),
-        0x75 => mov_m_r(cpu, Registers::L),
-        0x77 => mov_m_r(cpu, Registers::A),
-        0x78 => mov_r_r(Registers::A, cpu.b, cpu),
-        0x79 => mov_r_r(Registers::A, cpu.c, cpu),
-        0x7a => mov_r_r(Registers::A, cpu.d, cpu),
-        0x7b => mov_r_r(Registers::A, cpu.e, cpu),
-        0x7c => mov_r_r(Registers::A, cpu.h, cpu),
-        0x7d => mov_r_r(Registers::A, cpu.l, cpu),
-        0x7f => mov_r_r(Registers::A, cpu.a, cpu),
-        0x7e => mov_r_m(cpu, Registers::A),
-
-        //PUSH
-        0xc5 => push(cpu, StackPairs::BC),
-        0xd5 => push(cpu, StackPairs::DE),
-        0xe5 => push(cpu, StackPairs::HL),
-        0xf5 => push_psw(cpu),
-
-        //POP
-        0xc1 => pop(cpu, StackPairs::BC),
-        0xd1 => pop(cpu, StackPairs::DE),
-        0xe1 => pop(cpu, StackPairs::HL),
-        0xf1 => pop_psw(cpu),
-
-        //XTHL
-        0xe3 => xthl(cpu),
-        //SPHL
-        0xf9 => sphl(cpu),
-
-        // ADD OPS
-        0x80 => add(cpu.b, 1, cpu),
-        0x81 => add(cpu.c, 1, cpu),
-        0x82 => add(cpu.d, 1, cpu),
-        0x83 => add(cpu.e, 1, cpu),
-        0x84 => add(cpu.h, 1, cpu),
-        0x85 => add(cpu.l, 1, cpu),
-        0x86 => add(get_value_memory(&cpu.memory, cpu.h, cpu.l), 2, cpu),
-        0x87 => add(cpu.a, 1, cpu),
-        // ADC OPS
-        0x88 => add((cpu.b).wrapping_add(cpu.cc.cy), 2, cpu),
-        0x89 => add((cpu.c).wrapping_add(cpu.cc.cy), 2, cpu),
-        0x8a => add((cpu.d).wrapping_add(cpu.cc.cy), 2, cpu),
-        0x8b => add((cpu.e).wrapping_add(cpu.cc.cy), 2, cpu),
-        0x8c => add((cpu.h).wrapping_add(cpu.cc.cy), 2, cpu),
-        0x8d => add((cpu.l).wrapping_add(cpu.cc.cy), 2, cpu),
-        0x8e => add(get_value_memory(&cpu.memory, cpu.h, cpu.l).wrapping_add(cpu.cc.cy), 2, cpu),
-        0x8f => add((cpu.a).wrapping_add(cpu.cc.cy), 2, cpu),
-
-        // SUB OPS
-        0x90 => sub(cpu.b, 2, cpu),
-        0x91 => sub(cpu.c, 2, cpu),
-        0x92 => sub(cpu.d, 2, cpu),
-        0x93 => sub(cpu.e, 2, cpu),
-        0x94 => sub(cpu.h, 2, cpu),
-        0x95 => sub(cpu.l, 2, cpu),
-        0x96 => sub(get_value_memory(&cpu.memory, cpu.h, cpu.l), 2, cpu),
-        0x97 => sub(cpu.a, 2, cpu),
+/// A `CPUState` dump with PC and HL shown next to the symbol they point at.
+pub struct SymbolicState<'a> {
+    cpu: &'a CPUState,
+    symbols: &'a SymbolTable,
+}
 
-        // SUBB OPS
-        0x98 => sub((cpu.b).wrapping_add(cpu.cc.cy), 1, cpu),
-        0x99 => sub((cpu.c).wrapping_add(cpu.cc.cy), 1, cpu),
-        0x9a => sub((cpu.d).wrapping_add(cpu.cc.cy), 1, cpu),
-        0x9b => sub((cpu.e).wrapping_add(cpu.cc.cy), 1, cpu),
-        0x9c => sub((cpu.h).wrapping_add(cpu.cc.cy), 1, cpu),
-        0x9d => sub((cpu.l).wrapping_add(cpu.cc.cy), 1, cpu),
-        0x9e => sub(get_value_memory(&cpu.memory, cpu.h, cpu.l).wrapping_add(cpu.cc.cy), 1, cpu),
-        0x9f => sub((cpu.a).wrapping_add(cpu.cc.cy), 1, cpu),
+impl CPUState {
+    pub fn with_symbols<'a>(&'a self, symbols: &'a SymbolTable) -> SymbolicState<'a> {
+        SymbolicState { cpu: self, symbols }
+    }
+}
 
-        // ADI OPS
-        0xc6 => adi(next_opcode, 2, cpu),
-        0xce => adi(next_opcode.wrapping_add(cpu.cc.cy), 2, cpu),
-        0xeb => xchg(cpu),
+impl<'a> fmt::Display for SymbolicState<'a> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write_state(f, self.cpu, |address| match self.symbols.describe(address) {
+                        Some(name) => format!("{:04x} ({})", address, name),
+                        None => format!("{:04x}", address),
+                })
+        }
+}
 
-        // SUI OPS
-        0xd6 => sui(next_opcode, 2, cpu),
-        0xde => sui(next_opcode.wrapping_add(cpu.cc.cy), 2, cpu),
+fn write_state<F: Fn(u16) -> String>(f: &mut fmt::Formatter<'_>, cpu: &CPUState, address: F) -> fmt::Result {
+        write!(f, "Registers -> AF: {:02x}{:02x}, BC: {:02x}{:02x}, DE: {:02x}{:02x}, HL: {} \n
+Flags -> Z: {:02x} S: {:02x} P: {:02x} CY: {:02x} AC: {:02x}\n
+PC/SP -> PC: {}, SP: {:04x}\n
+Interrupts -> {}{}\n
+Cycles -> {}\n -----------------------------------------------------------------------------------",
+        cpu.a, cpu.cc.psw(), cpu.b, cpu.c, cpu.d, cpu.e, address((cpu.h as u16) << 8 | cpu.l as u16),
+        cpu.cc.z, cpu.cc.s, cpu.cc.p, cpu.cc.cy, cpu.cc.ac,
+        address(cpu.pc), cpu.sp,
+        if cpu.int_enable { "enabled" } else { "disabled" }, if cpu.halted { ", halted" } else { "" },
+        cpu.total_cycles)
+}
 
-        //JMPS
-        0xc3 => jmp(cpu, next_opcode, next_next_opcode),
-        0xc2 => jnz(cpu, next_opcode, next_next_opcode),
-        0xca => jz(cpu, next_opcode, next_next_opcode),
-        0xe2 => jpo(cpu, next_opcode, next_next_opcode),
-        0xea => jpe(cpu, next_opcode, next_next_opcode),
-        0xf2 => jp(cpu, next_opcode, next_next_opcode),
-        0xfa => jm(cpu, next_opcode, next_next_opcode),
-        0xd2 => jnc(cpu, next_opcode, next_next_opcode),
-        0xda => jc(cpu, next_opcode, next_next_opcode),
+/// What happened when `step` executed an instruction.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StepOutcome {
+    Executed,
+    /// The instruction was HLT, or the CPU was already halted and idled for
+    /// 4 cycles waiting for an interrupt.
+    Halted,
+}
 
-        //CALLS
-        0xcd => call(cpu, next_opcode, next_next_opcode),
-        0xdc => cc(cpu, next_opcode, next_next_opcode),
-        0xd4 => cnc(cpu, next_opcode, next_next_opcode),
-        0xcc => cz(cpu, next_opcode, next_next_opcode),
-        0xc4 => cnz(cpu, next_opcode, next_next_opcode),
-        0xf4 => cp(cpu, next_opcode, next_next_opcode),
-        0xfc => cm(cpu, next_opcode, next_next_opcode),
-        0xec => cpe(cpu, next_opcode, next_next_opcode),
-        0xe4 => cpo(cpu, next_opcode, next_next_opcode),
+/// Executes one instruction, panicking on anything `step` reports as an error.
+pub fn emulate_8080_op<B: Bus>(mut cpu: CPUState, bus: &mut B) -> CPUState {
+    if let Err(error) = step(&mut cpu, bus) {
+        panic!("{}", error);
+    }
+    cpu
+}
 
-        //Rs
-        0xc9 => ret(cpu),
-        0xd8 => rc(cpu),
-        0xd0 => rnc(cpu),
-        0xc8 => rz(cpu),
-        0xc0 => rnz(cpu),
-        0xf0 => rp(cpu),
-        0xf8 => rm(cpu),
-        0xe8 => rpe(cpu),
-        0xe0 => rpo(cpu),
+/// Executes the instruction at `state.pc`.
+///
+/// The core runs every opcode and lets SP wrap, so errors only come from
+/// the faults `state.checks` asks for. They are found before the instruction
+/// runs: on error neither `state` nor the bus is modified.
+pub fn step<B: Bus>(state: &mut CPUState, bus: &mut B) -> Result<StepOutcome, EmulationError> {
+    if state.halted {
+        state.cycles = 4;
+        state.total_cycles += 4;
+        return Ok(StepOutcome::Halted);
+    }
+    let cpu = *state;
+    let opcode = bus.read(cpu.pc);
+    let mut bytes = [opcode, 0, 0];
+    for i in 1..length(opcode) {
+        bytes[i as usize] = bus.read(cpu.pc.wrapping_add(i as u16));
+    }
+    let instruction = decode(&bytes);
+    check(&cpu, &instruction)?;
+    let new_cpu_state = execute(cpu, bus, instruction.operation);
+    let mut cycles = instruction.cycles();
+    match instruction.operation {
+        Operation::Ccc(c, _) | Operation::Rcc(c) if c.holds(&cpu.cc) => cycles += CONDITIONAL_TAKEN_CYCLES,
+        _ => {}
+    }
+    *state = CPUState { cycles, total_cycles: cpu.total_cycles + cycles as u64, ..new_cpu_state };
+    if instruction.operation == Operation::Hlt {
+        Ok(StepOutcome::Halted)
+    } else {
+        Ok(StepOutcome::Executed)
+    }
+}
 
-        //PHCL
-        0xe9 => pchl(cpu),
+// Does what `operation` says, starting from the opcode at `cpu.pc`
+fn execute<B: Bus>(cpu: CPUState, bus: &mut B, operation: Operation) -> CPUState {
+    let [lo, hi] = operation.operand().unwrap_or(0).to_le_bytes();
+    match operation {
+        Operation::Nop => nop(cpu),
+        Operation::Lxi(rp, _) => lxi(cpu, pair_names(rp), lo, hi),
+        Operation::Stax(rp) => stax(cpu, bus, pair_names(rp)),
+        Operation::Ldax(rp) => ldax(cpu, bus, pair_names(rp)),
+        Operation::Shld(_) => shld(cpu, bus, lo, hi),
+        Operation::Lhld(_) => lhld(cpu, bus, lo, hi),
+        Operation::Sta(_) => sta(cpu, bus, lo, hi),
+        Operation::Lda(_) => lda(cpu, bus, lo, hi),
+        Operation::Inx(rp) => inx(cpu, with_sp_pair(rp)),
+        Operation::Dcx(rp) => dcx(cpu, with_sp_pair(rp)),
+        Operation::Dad(rp) => dad(cpu, pair_names(rp)),
+        Operation::Inr(Register::M) => inr_m(cpu, bus),
+        Operation::Inr(r) => inr_r(cpu, register_name(r)),
+        Operation::Dcr(Register::M) => dcr_m(cpu, bus),
+        Operation::Dcr(r) => dcr_r(cpu, register_name(r)),
+        Operation::Mvi(Register::M, value) => mvi_m(cpu, bus, value),
+        Operation::Mvi(r, value) => mvi_r(cpu, register_name(r), value),
+        Operation::Rlc => rlc(cpu),
+        Operation::Rrc => rrc(cpu),
+        Operation::Ral => ral(cpu),
+        Operation::Rar => rar(cpu),
+        Operation::Daa => daa(cpu),
+        Operation::Cma => cma(cpu),
+        Operation::Stc => stc(cpu),
+        Operation::Cmc => cmc(cpu),
+        Operation::Mov(Register::M, s) => mov_m_r(cpu, bus, registers(s)),
+        Operation::Mov(d, Register::M) => mov_r_m(cpu, bus, registers(d)),
+        Operation::Mov(d, s) => mov_r_r(registers(d), register_value(cpu, bus, s), cpu),
+        Operation::Hlt => hlt(cpu),
+        Operation::Add(r) => add(register_value(cpu, bus, r), cpu),
+        Operation::Adc(r) => adc(register_value(cpu, bus, r), cpu),
+        Operation::Sub(r) => sub(register_value(cpu, bus, r), cpu),
+        Operation::Sbb(r) => sbb(register_value(cpu, bus, r), cpu),
+        Operation::Ana(r) => ana(register_value(cpu, bus, r), cpu),
+        Operation::Xra(r) => xra(register_value(cpu, bus, r), cpu),
+        Operation::Ora(r) => ora(register_value(cpu, bus, r), cpu),
+        Operation::Cmp(r) => cmp(register_value(cpu, bus, r), cpu),
+        Operation::Adi(value) => adi(value, cpu),
+        Operation::Aci(value) => aci(value, cpu),
+        Operation::Sui(value) => sui(value, cpu),
+        Operation::Sbi(value) => sbi(value, cpu),
+        Operation::Ani(value) => ani(value, cpu),
+        Operation::Xri(value) => xri(value, cpu),
+        Operation::Ori(value) => ori(value, cpu),
+        Operation::Cpi(value) => cpi(value, cpu),
+        Operation::Jmp(_) => jmp(cpu, lo, hi),
+        Operation::Jcc(c, _) => match c {
+            Condition::NZ => jnz(cpu, lo, hi),
+            Condition::Z => jz(cpu, lo, hi),
+            Condition::NC => jnc(cpu, lo, hi),
+            Condition::C => jc(cpu, lo, hi),
+            Condition::PO => jpo(cpu, lo, hi),
+            Condition::PE => jpe(cpu, lo, hi),
+            Condition::P => jp(cpu, lo, hi),
+            Condition::M => jm(cpu, lo, hi),
+        },
+        Operation::Call(_) => call(cpu, bus, lo, hi),
+        Operation::Ccc(c, _) => match c {
+            Condition::NZ => cnz(cpu, bus, lo, hi),
+            Condition::Z => cz(cpu, bus, lo, hi),
+            Condition::NC => cnc(cpu, bus, lo, hi),
+            Condition::C => cc(cpu, bus, lo, hi),
+            Condition::PO => cpo(cpu, bus, lo, hi),
+            Condition::PE => cpe(cpu, bus, lo, hi),
+            Condition::P => cp(cpu, bus, lo, hi),
+            Condition::M => cm(cpu, bus, lo, hi),
+        },
+        Operation::Ret => ret(cpu, bus),
+        Operation::Rcc(c) => match c {
+            Condition::NZ => rnz(cpu, bus),
+            Condition::Z => rz(cpu, bus),
+            Condition::NC => rnc(cpu, bus),
+            Condition::C => rc(cpu, bus),
+            Condition::PO => rpo(cpu, bus),
+            Condition::PE => rpe(cpu, bus),
+            Condition::P => rp(cpu, bus),
+            Condition::M => rm(cpu, bus),
+        },
+        Operation::Rst(n) => rst(cpu, bus, n),
+        Operation::Pchl => pchl(cpu),
+        Operation::Push(RegisterPair::PSW) => push_psw(cpu, bus),
+        Operation::Push(rp) => push(cpu, bus, stack_pair(rp)),
+        Operation::Pop(RegisterPair::PSW) => pop_psw(cpu, bus),
+        Operation::Pop(rp) => pop(cpu, bus, stack_pair(rp)),
+        Operation::Xthl => xthl(cpu, bus),
+        Operation::Sphl => sphl(cpu),
+        Operation::Xchg => xchg(cpu),
+        Operation::In(port) => op_in(cpu, bus, port),
+        Operation::Out(port) => out(cpu, bus, port),
+        Operation::Ei => ei(cpu),
+        Operation::Di => di(cpu),
+    }
+}
 
-        //RSTs
-        0xc7 => rst(cpu, 0),
-        0xcf => rst(cpu, 1),
-        0xd7 => rst(cpu, 2),
-        0xdf => rst(cpu, 3),
-        0xe7 => rst(cpu, 4),
-        0xef => rst(cpu, 5),
-        0xf7 => rst(cpu, 6),
-        0xff => rst(cpu, 7),
+// The op_* functions still name registers the way they did before decode()
+
+fn register_name(r: Register) -> char {
+    match r {
+        Register::A => 'a',
+        Register::B => 'b',
+        Register::C => 'c',
+        Register::D => 'd',
+        Register::E => 'e',
+        Register::H => 'h',
+        Register::L => 'l',
+        Register::M => unreachable!("M is handled by the *_m functions"),
+    }
+}
 
-        // ANA OPS
-        0xa0 => ana(cpu.b, 1, cpu),
-        0xa1 => ana(cpu.c, 1, cpu),
-        0xa2 => ana(cpu.d, 1, cpu),
-        0xa3 => ana(cpu.e, 1, cpu),
-        0xa4 => ana(cpu.h, 1, cpu),
-        0xa5 => ana(cpu.l, 1, cpu),
-        0xa6 => ana(get_value_memory(&cpu.memory, cpu.h, cpu.l), 2, cpu),
-        0xa7 => ana(cpu.a, 1, cpu),
+fn registers(r: Register) -> Registers {
+    match r {
+        Register::A => Registers::A,
+        Register::B => Registers::B,
+        Register::C => Registers::C,
+        Register::D => Registers::D,
+        Register::E => Registers::E,
+        Register::H => Registers::H,
+        Register::L => Registers::L,
+        Register::M => unreachable!("M is handled by the *_m functions"),
+    }
+}
 
-        // XRA OPS
-        0xa8 => xra(cpu.b, 1, cpu),
-        0xa9 => xra(cpu.c, 1, cpu),
-        0xaa => xra(cpu.d, 1, cpu),
-        0xab => xra(cpu.e, 1, cpu),
-        0xac => xra(cpu.h, 1, cpu),
-        0xad => xra(cpu.l, 1, cpu),
-        0xae => xra(get_value_memory(&cpu.memory, cpu.h, cpu.l), 2, cpu),
-        0xaf => xra(cpu.a, 1, cpu),
+fn register_value<B: Bus>(cpu: CPUState, bus: &mut B, r: Register) -> u8 {
+    match r {
+        Register::A => cpu.a,
+        Register::B => cpu.b,
+        Register::C => cpu.c,
+        Register::D => cpu.d,
+        Register::E => cpu.e,
+        Register::H => cpu.h,
+        Register::L => cpu.l,
+        Register::M => get_value_memory(bus, cpu.h, cpu.l),
+    }
+}
 
-        // ORA OPS
-        0xb0 => ora(cpu.b, 1, cpu),
-        0xb1 => ora(cpu.c, 1, cpu),
-        0xb2 => ora(cpu.d, 1, cpu),
-        0xb3 => ora(cpu.e, 1, cpu),
-        0xb4 => ora(cpu.h, 1, cpu),
-        0xb5 => ora(cpu.l, 1, cpu),
-        0xb6 => ora(get_value_memory(&cpu.memory, cpu.h, cpu.l), 2, cpu),
-        0xb7 => ora(cpu.a, 1, cpu),
+fn pair_names(rp: RegisterPair) -> (char, char) {
+    match rp {
+        RegisterPair::BC => ('b', 'c'),
+        RegisterPair::DE => ('d', 'e'),
+        RegisterPair::HL => ('h', 'l'),
+        RegisterPair::SP | RegisterPair::PSW => ('s', 'p'),
+    }
+}
 
-        // CMP OPS
-        0xb8 => cmp(cpu.b, 1, cpu),
-        0xb9 => cmp(cpu.c, 1, cpu),
-        0xba => cmp(cpu.d, 1, cpu),
-        0xbb => cmp(cpu.e, 1, cpu),
-        0xbc => cmp(cpu.h, 1, cpu),
-        0xbd => cmp(cpu.l, 1, cpu),
-        0xbe => cmp(get_value_memory(&cpu.memory, cpu.h, cpu.l), 2, cpu),
-        0xbf => cmp(cpu.a, 1, cpu),
+fn with_sp_pair(rp: RegisterPair) -> WithSPPairs {
+    match rp {
+        RegisterPair::BC => WithSPPairs::BC,
+        RegisterPair::DE => WithSPPairs::DE,
+        RegisterPair::HL => WithSPPairs::HL,
+        RegisterPair::SP | RegisterPair::PSW => WithSPPairs::SP,
+    }
+}
 
-        //ANI
-        0xe6 => ani(next_opcode, cpu),
-        //XRI
-        0xee => xri(next_opcode, cpu),
-        //ORI
-        0xf6 => ori(next_opcode, cpu),
-        //CPI
-        0xfe => cpi(next_opcode, cpu),
-        //RLC
-        0x07 => rlc(cpu),
-        //RRC
-        0x0f => rrc(cpu),
-        //RAL
-        0x17 => ral(cpu),
-        //RAR
-        0x1f => rar(cpu),
-        //CMA
-        0x2f => cma(cpu),
-        //CMC
-        0x3f => cmc(cpu),
-        //STC
-        0x37 => stc(cpu),
+fn stack_pair(rp: RegisterPair) -> StackPairs {
+    match rp {
+        RegisterPair::BC => StackPairs::BC,
+        RegisterPair::DE => StackPairs::DE,
+        _ => StackPairs::HL,
+    }
+}
 
-        //IO SPECIAL
-        0xfb => ei(cpu),
-        0x27 => daa(cpu),
-        0xf3 => di(cpu),
-        0x76 => panic!(),
-        _ => panic!("Unimplemented Opcode: 0x{:02x}", opcode),
+// The condition encoded in bits 3-5 of a Jcc/Ccc/Rcc opcode: NZ, Z, NC, C, PO, PE, P, M
+pub fn condition(cc: &ConditionCodes, opcode: u8) -> bool {
+    Condition::from_opcode(opcode).holds(cc)
+}
 
+// The faults `cpu.checks` asks for that `instruction` would commit
+fn check(cpu: &CPUState, instruction: &Instruction) -> Result<(), EmulationError> {
+    if cpu.checks.documented_only && instruction.is_undocumented() {
+        return Err(EmulationError::IllegalOpcode { pc: cpu.pc, opcode: instruction.opcode });
+    }
+    let (lowest, highest) = match cpu.checks.stack {
+        Some(bounds) => bounds,
+        None => return Ok(()),
+    };
+    let pushes = match instruction.operation {
+        Operation::Push(_) | Operation::Call(_) | Operation::Rst(_) => true,
+        Operation::Ccc(c, _) => c.holds(&cpu.cc),
+        _ => false,
     };
-    let cycles: u8 = CYCLES8080[(opcode) as usize];
-    CPUState { cycles: cycles, ..new_cpu_state }
+    let pops = match instruction.operation {
+        Operation::Pop(_) | Operation::Ret => true,
+        Operation::Rcc(c) => c.holds(&cpu.cc),
+        _ => false,
+    };
+    if pushes && (cpu.sp as u32) < lowest as u32 + 2 {
+        return Err(EmulationError::StackOverflow { pc: cpu.pc, sp: cpu.sp });
+    }
+    if pops && cpu.sp as u32 + 2 > highest as u32 {
+        return Err(EmulationError::StackUnderflow { pc: cpu.pc, sp: cpu.sp });
+    }
+    Ok(())
 }
@@ -0,0 +1,755 @@
+use crate::call_stack::{CallStack, Mismatch};
+use crate::coverage::Coverage;
+use crate::cpu::{step, CPUState, StepOutcome};
+use crate::dissassembler::{disassemble_with, Options};
+use crate::error::EmulationError;
+use crate::helpers::generate_interrupt;
+use crate::history::History;
+use crate::instruction::{decode, Operation};
+use crate::profiler::Profiler;
+use crate::invaders::{Machine, CYCLES_PER_FRAME, INVADERS_MEMORY_SIZE, ROM_SIZE};
+use crate::symbols::SymbolTable;
+use crate::trace::{Filter, Tracer};
+use crate::traps::{Access, Direction, Expression, Recorder, Trap};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+step [N]                  s  execute N instructions (1), entering calls
+next [N]                  n  like step, running called routines to their return
+continue [N]              c  run until a trap, HLT or an error, or for N instructions
+until ADDRESS             u  run until PC reaches ADDRESS
+break ADDRESS [if EXPR]   b  stop before ADDRESS, when EXPR holds: A == 0A && !Z
+watch START[-END] [read|write|access]
+                             stop after an access to memory, writes by default
+trap in|out|io PORT          stop after IN or OUT on PORT
+traps                        list the breakpoints, watchpoints and traps
+delete N...|all              remove them
+registers                 r  show the registers and flags
+memory ADDRESS [N]        x  dump N bytes (64) from ADDRESS
+write ADDRESS BYTE...     w  store bytes, ROM included
+disassemble [ADDRESS] [N] d  N instructions (10) around PC, or from ADDRESS
+trace FILE [pc START-END] [count FIRST-LAST]
+                             write each instruction run to FILE, from here on
+trace off                    stop tracing and close the file
+interrupt N               i  take RST N now, as the video hardware does
+backtrace                 bt the routines PC is in, and where each was called from
+stack-check on|off           stop when a RET or POP does not match the calls
+profile on|off               count instructions and cycles by address and routine
+profile [N]                  show the N routines and addresses (10) that took longest
+profile write FILE FOLDED    write the whole report to FILE, and the call stacks
+                             for a flame graph to FOLDED
+coverage on|off              mark the ROM bytes executed and read
+coverage                     show how much of each ROM chip was executed and read
+coverage write FILE          write the ROM disassembled, marked with how it was used
+reverse-step [N]          rs undo the last N instructions (1)
+reverse-continue [N]      rc run backwards to a breakpoint or a write a watchpoint
+                             covers, or for N instructions
+rewind [N]                   go back to the start of this frame, or N frames before
+history [N]                  show how far back the machine can go, or keep N
+                             frames (0 keeps none)
+video on|off                 whether the video interrupts fire by themselves
+help                      h
+quit                      q
+Addresses and bytes are hex or symbol names, counts are decimal. An empty
+line repeats the last command.";
+
+// Frames of history kept by default, a second of the game's time
+const HISTORY_FRAMES: usize = 60;
+
+/// Why the machine stopped running.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stop {
+    /// Ran the number of instructions asked for.
+    Done,
+    /// PC reached the address asked for.
+    Reached(u16),
+    /// HLT with nothing left to wake the CPU up.
+    Halted,
+    /// Running backwards, reached the oldest state in the history.
+    HistoryStart,
+    /// The last instruction did something to the stack that calls and
+    /// returns do not explain, and `check_stack` is on.
+    Mismatch(Mismatch),
+    Error(EmulationError),
+    /// The trap numbered `number` fired, set off by `access` unless it is
+    /// a breakpoint.
+    Trap { number: usize, access: Option<Access> },
+}
+
+/// What `Debugger::execute` wants the caller to do next.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    Text(String),
+    Quit,
+}
+
+// When the next video interrupt is due, and which one it is
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Video {
+    next_interrupt: u64,
+    top_half: bool,
+}
+
+/// A Space Invaders machine under the control of text commands, for a
+/// terminal or a test. The video interrupts fire every half frame while it
+/// runs, as they do in the windowed emulator, unless turned off.
+pub struct Debugger {
+    pub machine: Machine,
+    pub symbols: SymbolTable,
+    pub video_interrupts: bool,
+    /// Breakpoints, watchpoints and port traps, by number.
+    pub traps: BTreeMap<usize, Trap>,
+    /// What the last instruction read and wrote, fetching itself aside.
+    pub accesses: Vec<Access>,
+    /// Where to write each instruction before running it, if anywhere.
+    pub tracer: Option<Tracer>,
+    /// The routines the program is in, followed through every call, return
+    /// and interrupt.
+    pub calls: CallStack,
+    /// What the last instruction did wrong to the call stack, if anything.
+    pub mismatch: Option<Mismatch>,
+    /// Whether running stops on a mismatch.
+    pub check_stack: bool,
+    /// Where the time goes while profiling.
+    pub profiler: Option<Profiler>,
+    /// Which ROM bytes were executed and read, while measuring coverage.
+    pub coverage: Option<Coverage>,
+    video: Video,
+    history: History<(Video, CallStack)>,
+    last_command: String,
+    // Trap numbers are never reused
+    traps_added: usize,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine, symbols: SymbolTable) -> Debugger {
+        let video = Video { next_interrupt: machine.cpu.total_cycles + CYCLES_PER_FRAME / 2, top_half: true };
+        Debugger {
+            machine,
+            symbols,
+            video_interrupts: true,
+            traps: BTreeMap::new(),
+            accesses: Vec::new(),
+            tracer: None,
+            calls: CallStack::new(),
+            mismatch: None,
+            check_stack: false,
+            profiler: None,
+            coverage: None,
+            video,
+            history: History::new(HISTORY_FRAMES),
+            last_command: String::new(),
+            traps_added: 0,
+        }
+    }
+
+    /// Reads commands from `input` until it ends or a quit command.
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, output: &mut W) -> io::Result<()> {
+        write!(output, "{}\n(8080) ", self.current_line())?;
+        output.flush()?;
+        for line in input.lines() {
+            match self.execute(&line?) {
+                Reply::Text(text) if text.is_empty() => {}
+                Reply::Text(text) => writeln!(output, "{}", text)?,
+                Reply::Quit => return Ok(()),
+            }
+            write!(output, "(8080) ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    /// Runs one command line and returns what to show for it.
+    pub fn execute(&mut self, line: &str) -> Reply {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, arguments) = match words.split_first() {
+            Some((command, arguments)) => (*command, arguments),
+            None => return Reply::Text(String::new()),
+        };
+        let text = match command {
+            "q" | "quit" => return Reply::Quit,
+            "h" | "help" => Ok(HELP.to_string()),
+            "s" | "step" => count(arguments.first(), 1).map(|n| {
+                let stop = self.step(n);
+                self.report(stop)
+            }),
+            "n" | "next" => count(arguments.first(), 1).map(|n| {
+                let stop = self.next(n);
+                self.report(stop)
+            }),
+            "c" | "continue" => count(arguments.first(), u64::MAX).map(|n| {
+                let stop = self.run(n, |_| false);
+                self.report(stop)
+            }),
+            "u" | "until" => argument(arguments, 0).and_then(|a| self.address(a)).map(|target| {
+                let stop = self.run(u64::MAX, |machine| machine.cpu.pc == target);
+                self.report(stop)
+            }),
+            "b" | "break" => self.add_breakpoint(arguments),
+            "watch" => self.add_watchpoint(arguments),
+            "trap" => self.add_port_trap(arguments),
+            "traps" => Ok(self.traps.iter().map(|(n, t)| format!("{}\t{}", n, t)).collect::<Vec<_>>().join("\n")),
+            "delete" => self.delete(arguments),
+            "r" | "registers" => Ok(self.machine.cpu.with_symbols(&self.symbols).to_string()),
+            "x" | "memory" => self.memory(arguments),
+            "w" | "write" => self.write(arguments),
+            "d" | "disassemble" => self.disassemble(arguments),
+            "i" | "interrupt" => self.interrupt(arguments),
+            "bt" | "backtrace" => Ok(self.calls.backtrace(self.machine.cpu.pc, &self.symbols)),
+            "profile" => self.profile(arguments),
+            "coverage" => self.coverage(arguments),
+            "stack-check" => match arguments {
+                ["on"] => {
+                    self.check_stack = true;
+                    Ok(String::new())
+                }
+                ["off"] => {
+                    self.check_stack = false;
+                    Ok(String::new())
+                }
+                _ => Err("stack-check takes on or off".to_string()),
+            },
+            "trace" => self.trace(arguments),
+            "rs" | "reverse-step" => count(arguments.first(), 1).map(|n| {
+                let stop = self.reverse(n, |_| false);
+                self.report(stop)
+            }),
+            "rc" | "reverse-continue" => count(arguments.first(), u64::MAX).map(|n| {
+                let stop = self.reverse(n, |_| false);
+                self.report(stop)
+            }),
+            "rewind" => count(arguments.first(), 0).and_then(|n| {
+                self.rewind(n as usize)?;
+                Ok(format!("Rewound to cycle {}\n{}", self.machine.cpu.total_cycles, self.current_line()))
+            }),
+            "history" => count(arguments.first(), self.history.limit() as u64).map(|n| {
+                self.history.set_limit(n as usize);
+                let oldest = self.history.oldest().map(|c| format!(", back to cycle {}", c)).unwrap_or_default();
+                format!(
+                    "{} frames of {} kept, {} instructions{}",
+                    self.history.frames(),
+                    self.history.limit(),
+                    self.history.instructions(),
+                    oldest
+                )
+            }),
+            "video" => match arguments {
+                ["on"] => {
+                    self.video_interrupts = true;
+                    self.video.next_interrupt = self.machine.cpu.total_cycles + CYCLES_PER_FRAME / 2;
+                    Ok(String::new())
+                }
+                ["off"] => {
+                    self.video_interrupts = false;
+                    Ok(String::new())
+                }
+                _ => Err("video takes on or off".to_string()),
+            },
+            _ => Err(format!("unknown command {:?}, try help", command)),
+        };
+        Reply::Text(text.unwrap_or_else(|error| error))
+    }
+
+    /// A symbol name, or a hex number with an optional 0x, $ or h, and an
+    /// optional +offset.
+    pub fn address(&self, text: &str) -> Result<u16, String> {
+        if let Some(address) = self.symbols.lookup(text) {
+            return Ok(address);
+        }
+        let lower = text.to_lowercase();
+        let hex = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')).or_else(|| lower.strip_suffix('h'));
+        if let Ok(value) = u16::from_str_radix(hex.unwrap_or(&lower), 16) {
+            return Ok(value);
+        }
+        let (name, offset) = match text.find('+') {
+            Some(i) => (&text[..i], self.address(&text[i + 1..])?),
+            None => (text, 0),
+        };
+        match self.symbols.lookup(name) {
+            Some(address) => Ok(address.wrapping_add(offset)),
+            None => Err(format!("bad address {:?}", text)),
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.machine.memory[address as usize % INVADERS_MEMORY_SIZE]
+    }
+
+    fn bytes_at(&self, address: u16) -> [u8; 3] {
+        [self.peek(address), self.peek(address.wrapping_add(1)), self.peek(address.wrapping_add(2))]
+    }
+
+    /// Executes one instruction and any video interrupt that comes due,
+    /// keeping what they accessed in `accesses`.
+    pub fn step_once(&mut self) -> Result<StepOutcome, EmulationError> {
+        let mut cpu = self.machine.cpu;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&cpu, &mut self.machine);
+        }
+        self.history.before(&self.machine, (self.video, self.calls.clone()));
+        let (before, instruction) = (cpu, decode(&self.bytes_at(cpu.pc)));
+        let fetched = if cpu.halted { 0 } else { instruction.length() };
+        let mut recorder = Recorder::new(&mut self.machine);
+        let outcome = match step(&mut cpu, &mut recorder) {
+            Ok(outcome) => outcome,
+            Err(error) => {
+                // The registers are as they were, but keep whatever it wrote
+                // so that stepping back undoes it
+                self.history.after(&recorder.overwritten);
+                return Err(error);
+            }
+        };
+        recorder.forget_fetch(before.pc, fetched);
+        if let Some(profiler) = self.profiler.as_mut().filter(|_| !before.halted) {
+            profiler.record(before.pc, cpu.total_cycles - before.total_cycles, Some(&self.calls));
+        }
+        if let Some(coverage) = &mut self.coverage {
+            if !before.halted {
+                coverage.executed(before.pc, fetched);
+            }
+            for access in &recorder.accesses {
+                if let Access::Read(address, _) = access {
+                    coverage.read(*address);
+                }
+            }
+        }
+        self.mismatch = self.calls.execute(&before, &cpu, instruction.operation);
+        if self.video_interrupts && cpu.total_cycles >= self.video.next_interrupt {
+            if cpu.int_enable {
+                let number = if self.video.top_half { 1 } else { 2 };
+                let entered = generate_interrupt(cpu, &mut recorder, number);
+                self.calls.interrupt(&cpu, &entered, number as u8);
+                cpu = entered;
+            }
+            self.video.next_interrupt += CYCLES_PER_FRAME / 2;
+            self.video.top_half = !self.video.top_half;
+        }
+        self.history.after(&recorder.overwritten);
+        self.accesses = recorder.accesses;
+        self.machine.cpu = cpu;
+        Ok(outcome)
+    }
+
+    /// Writes `bytes` from `start`, ROM included, as an entry of the history
+    /// so that going back undoes it like an instruction.
+    pub fn poke(&mut self, start: u16, bytes: &[u8]) {
+        self.by_hand(|debugger| {
+            let mut overwritten = Vec::new();
+            for (i, &byte) in bytes.iter().enumerate() {
+                let address = start.wrapping_add(i as u16);
+                let index = address as usize % INVADERS_MEMORY_SIZE;
+                overwritten.push((address, debugger.machine.memory[index]));
+                debugger.machine.memory[index] = byte;
+            }
+            overwritten
+        });
+    }
+
+    /// Sets the registers, as an entry of the history like `poke`.
+    pub fn set_cpu(&mut self, cpu: CPUState) {
+        self.by_hand(|debugger| {
+            debugger.machine.cpu = cpu;
+            Vec::new()
+        });
+    }
+
+    // Makes a change between instructions the way step_once records one,
+    // `change` returning the address and previous value of what it wrote
+    fn by_hand<F: FnOnce(&mut Self) -> Vec<(u16, u8)>>(&mut self, change: F) {
+        self.history.before(&self.machine, (self.video, self.calls.clone()));
+        let overwritten = change(self);
+        self.history.after(&overwritten);
+        self.accesses.clear();
+    }
+
+    // The first trap that fires after the last instruction
+    fn fired(&self) -> Option<(usize, Option<Access>)> {
+        self.traps
+            .iter()
+            .find_map(|(&number, trap)| trap.fires(&self.machine.cpu, &self.accesses).map(|access| (number, access)))
+    }
+
+    /// Runs up to `limit` instructions, stopping early when `done` holds
+    /// after one of them.
+    pub fn run<F: Fn(&Machine) -> bool>(&mut self, limit: u64, done: F) -> Stop {
+        let mut executed = 0;
+        while executed < limit {
+            match self.step_once() {
+                Ok(StepOutcome::Halted) if !self.machine.cpu.int_enable || !self.video_interrupts => {
+                    return Stop::Halted
+                }
+                Ok(_) => {}
+                Err(error) => return Stop::Error(error),
+            }
+            executed += 1;
+            if let Some((number, access)) = self.fired() {
+                return Stop::Trap { number, access };
+            }
+            match self.mismatch {
+                Some(mismatch) if self.check_stack => return Stop::Mismatch(mismatch),
+                _ => {}
+            }
+            if done(&self.machine) {
+                return Stop::Reached(self.machine.cpu.pc);
+            }
+        }
+        Stop::Done
+    }
+
+    /// Undoes instructions, up to `limit` of them, stopping early when one
+    /// brings PC to a breakpoint, undoes a write a watchpoint covers or
+    /// makes `done` hold. Port traps and read watchpoints never fire.
+    pub fn reverse<F: Fn(&Machine) -> bool>(&mut self, limit: u64, done: F) -> Stop {
+        for _ in 0..limit {
+            match self.history.step_back(&mut self.machine) {
+                Some(((video, calls), undone)) => {
+                    self.video = video;
+                    self.calls = calls;
+                    self.mismatch = None;
+                    self.accesses = undone;
+                }
+                None => return Stop::HistoryStart,
+            }
+            if let Some((number, access)) = self.fired() {
+                return Stop::Trap { number, access };
+            }
+            if done(&self.machine) {
+                return Stop::Reached(self.machine.cpu.pc);
+            }
+        }
+        Stop::Done
+    }
+
+    /// Puts the whole machine back as it was at the start of the current
+    /// frame, or `frames` frames before that.
+    pub fn rewind(&mut self, frames: usize) -> Result<(), String> {
+        match self.history.rewind(frames, &mut self.machine) {
+            Some((video, calls)) => {
+                self.video = video;
+                self.calls = calls;
+                self.accesses.clear();
+                Ok(())
+            }
+            None => Err(format!("the history only has {} frames", self.history.frames())),
+        }
+    }
+
+    pub fn step(&mut self, count: u64) -> Stop {
+        self.run(count, |_| false)
+    }
+
+    /// Like `step`, but a CALL or RST counts as one instruction along with
+    /// the routine it calls.
+    pub fn next(&mut self, count: u64) -> Stop {
+        for _ in 0..count {
+            let pc = self.machine.cpu.pc;
+            let instruction = decode(&self.bytes_at(pc));
+            let stop = match instruction.operation {
+                Operation::Call(_) | Operation::Ccc(..) | Operation::Rst(_) => {
+                    let (back, sp) = (pc.wrapping_add(instruction.length() as u16), self.machine.cpu.sp);
+                    self.run(u64::MAX, |machine| machine.cpu.pc == back && machine.cpu.sp >= sp)
+                }
+                _ => self.run(1, |_| false),
+            };
+            match stop {
+                Stop::Done | Stop::Reached(_) => {}
+                stop => return stop,
+            }
+        }
+        Stop::Done
+    }
+
+    fn report(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Done | Stop::Reached(_) => self.current_line(),
+            Stop::Halted => format!("Halted\n{}", self.current_line()),
+            Stop::HistoryStart => format!("Start of history\n{}", self.current_line()),
+            Stop::Mismatch(mismatch) => format!("{}\n{}", mismatch, self.current_line()),
+            Stop::Error(error) => format!("{}\n{}", error, self.current_line()),
+            Stop::Trap { number, access } => {
+                let cause = access.map(|a| format!("{}, ", a)).unwrap_or_default();
+                format!("{}{} ({})\n{}", cause, number, self.traps[&number], self.current_line())
+            }
+        }
+    }
+
+    // The instruction at PC, and where it is
+    fn current_line(&self) -> String {
+        let pc = self.machine.cpu.pc;
+        let options = Options { symbols: Some(&self.symbols), ..Options::default() };
+        let line = disassemble_with(&self.bytes_at(pc), pc as usize, options);
+        match self.symbols.describe(pc) {
+            Some(name) => format!("=> {}\t; {}", line, name),
+            None => format!("=> {}", line),
+        }
+    }
+
+    /// Adds a trap under a new number, which it returns.
+    pub fn add_trap(&mut self, trap: Trap) -> usize {
+        self.traps_added += 1;
+        self.traps.insert(self.traps_added, trap);
+        self.traps_added
+    }
+
+    fn add(&mut self, trap: Trap) -> Result<String, String> {
+        let text = trap.to_string();
+        Ok(format!("{}\t{}", self.add_trap(trap), text))
+    }
+
+    fn add_breakpoint(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let address = self.address(argument(arguments, 0)?)?;
+        let condition = match arguments.get(1..) {
+            Some([]) | None => None,
+            Some(["if", condition @ ..]) => Some(Expression::parse(&condition.join(" "))?),
+            _ => return Err("break takes an address and if CONDITION".to_string()),
+        };
+        self.add(Trap::Breakpoint { address, condition })
+    }
+
+    fn add_watchpoint(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let (start, end) = self.range(argument(arguments, 0)?)?;
+        let direction = match arguments.get(1).copied() {
+            Some("read") => Direction::In,
+            Some("write") | None => Direction::Out,
+            Some("access") => Direction::Both,
+            Some(other) => return Err(format!("watch takes read, write or access, not {:?}", other)),
+        };
+        self.add(Trap::Watchpoint { start, end, direction })
+    }
+
+    // START-END, both included, or a single address
+    fn range(&self, text: &str) -> Result<(u16, u16), String> {
+        let (start, end) = match text.find('-') {
+            Some(i) => (self.address(&text[..i])?, self.address(&text[i + 1..])?),
+            None => (self.address(text)?, self.address(text)?),
+        };
+        if end < start {
+            return Err(format!("{} ends before it starts", text));
+        }
+        Ok((start, end))
+    }
+
+    fn add_port_trap(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let direction = match argument(arguments, 0)? {
+            "in" => Direction::In,
+            "out" => Direction::Out,
+            "io" => Direction::Both,
+            other => return Err(format!("trap takes in, out or io, not {:?}", other)),
+        };
+        let port = self.address(argument(arguments, 1)?)?;
+        if port > 0xff {
+            return Err(format!("{:x} is not a port", port));
+        }
+        self.add(Trap::Port { port: port as u8, direction })
+    }
+
+    fn delete(&mut self, arguments: &[&str]) -> Result<String, String> {
+        if arguments == ["all"] {
+            self.traps.clear();
+            return Ok(String::new());
+        }
+        for text in arguments {
+            let number = text.parse().map_err(|_| format!("bad number {:?}", text))?;
+            self.traps.remove(&number).ok_or_else(|| format!("no trap {}", number))?;
+        }
+        Ok(String::new())
+    }
+
+    fn memory(&self, arguments: &[&str]) -> Result<String, String> {
+        let start = match arguments.first() {
+            Some(text) => self.address(text)?,
+            None => self.machine.cpu.pc,
+        };
+        // Past the whole address space the rows only repeat
+        let length = count(arguments.get(1), 64)?.min(0x10000);
+        let mut text = String::new();
+        for row in (0..length).step_by(16) {
+            let address = start.wrapping_add(row as u16);
+            let bytes: Vec<u8> = (row..length.min(row + 16)).map(|i| self.peek(start.wrapping_add(i as u16))).collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String =
+                bytes.iter().map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' }).collect();
+            let _ = writeln!(text, "{:04x}  {:<47}  {}", address, hex.join(" "), ascii);
+        }
+        text.pop();
+        Ok(text)
+    }
+
+    fn write(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let start = self.address(argument(arguments, 0)?)?;
+        if arguments.len() < 2 {
+            return Err("write needs an address and bytes".to_string());
+        }
+        let mut bytes = Vec::new();
+        for text in &arguments[1..] {
+            let value = self.address(text)?;
+            if value > 0xff {
+                return Err(format!("{} is not a byte", text));
+            }
+            bytes.push(value as u8);
+        }
+        self.poke(start, &bytes);
+        Ok(String::new())
+    }
+
+    fn disassemble(&self, arguments: &[&str]) -> Result<String, String> {
+        let pc = self.machine.cpu.pc;
+        let (start, count) = match arguments.first() {
+            Some(text) => (self.address(text)?, count(arguments.get(1), 10)?),
+            None => (self.start_before(pc, 3), 10),
+        };
+        let options = Options { symbols: Some(&self.symbols), ..Options::default() };
+        let mut text = String::new();
+        let mut address = start;
+        for _ in 0..count {
+            if let Some(symbol) = self.symbols.get(address) {
+                let _ = writeln!(text, "{}:", symbol.name);
+            }
+            let line = disassemble_with(&self.bytes_at(address), address as usize, options);
+            let marker = if address == pc { "=>" } else { "  " };
+            let _ = writeln!(text, "{} {}", marker, line);
+            address = address.wrapping_add(line.length() as u16);
+        }
+        text.pop();
+        Ok(text)
+    }
+
+    // Instructions have no fixed size, so going back from PC means finding
+    // an earlier address from which decoding lands on PC. The furthest such
+    // address is the most likely to be an instruction boundary.
+    fn start_before(&self, pc: u16, lines: usize) -> u16 {
+        for back in (1..=lines as u16 * 3).rev() {
+            let mut starts = Vec::new();
+            let mut address = pc.wrapping_sub(back);
+            while address != pc && starts.len() <= lines * 3 {
+                starts.push(address);
+                address = address.wrapping_add(decode(&self.bytes_at(address)).length() as u16);
+                if pc.wrapping_sub(address) > back {
+                    break;
+                }
+            }
+            if address == pc {
+                return starts[starts.len().saturating_sub(lines)];
+            }
+        }
+        pc
+    }
+
+    fn trace(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let path = argument(arguments, 0)?;
+        let finished = match self.tracer.take() {
+            Some(tracer) => tracer.finish().map_err(|e| format!("trace: {}", e)),
+            None => Ok(()),
+        };
+        if path == "off" {
+            return finished.map(|_| String::new());
+        }
+        finished?;
+        let mut filter = Filter::default();
+        for pair in arguments[1..].chunks(2) {
+            match pair {
+                ["pc", range] => filter.pc_range = Some(self.range(range)?),
+                ["count", window] => filter.window = Some(Filter::parse_window(window)?),
+                _ => return Err("trace takes a file, pc START-END and count FIRST-LAST".to_string()),
+            }
+        }
+        self.tracer = Some(Tracer::create(path, filter).map_err(|e| format!("{}: {}", path, e))?);
+        Ok(String::new())
+    }
+
+    fn profile(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let profiler = match arguments {
+            ["on"] => {
+                self.profiler = Some(Profiler::new());
+                return Ok(String::new());
+            }
+            ["off"] => {
+                self.profiler = None;
+                return Ok(String::new());
+            }
+            _ => self.profiler.as_ref().ok_or_else(|| "not profiling, try profile on".to_string())?,
+        };
+        match arguments {
+            ["write", report, folded] => write_profile(profiler, &self.symbols, report, folded)
+                .map(|_| String::new())
+                .map_err(|e| format!("profile write: {}", e)),
+            [] | [_] => {
+                let mut text = Vec::new();
+                profiler.write_report(&mut text, &self.symbols, count(arguments.first(), 10)? as usize).unwrap();
+                Ok(String::from_utf8_lossy(&text).trim_end().to_string())
+            }
+            _ => Err("profile takes on, off, a count or write FILE FOLDED".to_string()),
+        }
+    }
+
+    fn coverage(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let coverage = match arguments {
+            ["on"] => {
+                self.coverage = Some(Coverage::new());
+                return Ok(String::new());
+            }
+            ["off"] => {
+                self.coverage = None;
+                return Ok(String::new());
+            }
+            _ => self.coverage.as_ref().ok_or_else(|| "not measuring coverage, try coverage on".to_string())?,
+        };
+        let mut text = Vec::new();
+        match arguments {
+            [] => coverage.write_summary(&mut text).unwrap(),
+            ["write", path] => {
+                let rom = &self.machine.memory[..ROM_SIZE];
+                File::create(path)
+                    .map(io::BufWriter::new)
+                    .and_then(|mut file| coverage.write_listing(&mut file, rom, &self.symbols).and_then(|_| file.flush()))
+                    .map_err(|e| format!("{}: {}", path, e))?;
+            }
+            _ => return Err("coverage takes on, off or write FILE".to_string()),
+        }
+        Ok(String::from_utf8_lossy(&text).trim_end().to_string())
+    }
+
+    fn interrupt(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let number = count(Some(&argument(arguments, 0)?), 0)?;
+        if number > 7 {
+            return Err("interrupt takes 0 to 7".to_string());
+        }
+        let enabled = self.machine.cpu.int_enable;
+        let cpu = self.machine.cpu;
+        self.by_hand(|debugger| {
+            let mut recorder = Recorder::new(&mut debugger.machine);
+            let entered = generate_interrupt(cpu, &mut recorder, number as u32);
+            let overwritten = recorder.overwritten;
+            debugger.machine.cpu = entered;
+            debugger.calls.interrupt(&cpu, &entered, number as u8);
+            overwritten
+        });
+        let warning = if enabled { "" } else { "Interrupts were disabled\n" };
+        Ok(format!("{}{}", warning, self.current_line()))
+    }
+}
+
+fn write_profile(profiler: &Profiler, symbols: &SymbolTable, report: &str, folded: &str) -> io::Result<()> {
+    let mut file = io::BufWriter::new(File::create(report)?);
+    profiler.write_report(&mut file, symbols, usize::MAX)?;
+    file.flush()?;
+    let mut file = io::BufWriter::new(File::create(folded)?);
+    profiler.write_folded(&mut file, symbols)?;
+    file.flush()
+}
+
+fn argument<'a>(arguments: &[&'a str], index: usize) -> Result<&'a str, String> {
+    arguments.get(index).copied().ok_or_else(|| "missing argument, try help".to_string())
+}
+
+fn count(argument: Option<&&str>, default: u64) -> Result<u64, String> {
+    match argument {
+        Some(text) => text.parse().map_err(|_| format!("bad count {:?}", text)),
+        None => Ok(default),
+    }
+}
@@ -1,262 +1,216 @@
-#[allow(dead_code)]
-pub fn disassemble(buffer: &[u8], pc: usize) -> usize {
-    print!("{:04x}\t", pc);
-    match buffer[0] {
-        0x00 => { println!("NOP"); 1 },
-        0x01 => { println!("LXI\tB, #${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0x02 => { println!("STAX\tB"); 1},
-        0x03 => { println!("INX\tB"); 1},
-        0x04 => { println!("INR\tB"); 1},
-        0x05 => { println!("DCR\tB"); 1},
-        0x06 => { println!("MVI\tB, #${:02x}", buffer[1]); 2},
-        0x07 => { println!("RLC"); 1},
-        0x08 => { println!("NOP"); 1},
-        0x09 => { println!("DAD\tB"); 1},
-        0x0a => { println!("LDAX\tB"); 1},
-        0x0b => { println!("DCX\tB"); 1},
-        0x0c => { println!("INR\tC"); 1},
-        0x0d => { println!("DCR\tC"); 1},
-        0x0e => { println!("MVI\tC, #${:02x}", buffer[1]); 2},
-        0x0f => { println!("RRC"); 1},
-        0x10 => { println!("NOP"); 1 },
-        0x11 => { println!("LXI\tD, #${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0x12 => { println!("STAX\tD"); 1},
-        0x13 => { println!("INX\tD"); 1},
-        0x14 => { println!("INR\tD"); 1},
-        0x15 => { println!("DCR\tD"); 1},
-        0x16 => { println!("MVI\tD, #${:02x}", buffer[1]); 2},
-        0x17 => { println!("RAL"); 1},
-        0x18 => { println!("NOP"); 1},
-        0x19 => { println!("DAD\tD"); 1},
-        0x1a => { println!("LDAX\tD"); 1},
-        0x1b => { println!("DCX\tD"); 1},
-        0x1c => { println!("INR\tE"); 1},
-        0x1d => { println!("DCR\tE"); 1},
-        0x1e => { println!("MVI\tE, #${:02x}", buffer[1]); 2},
-        0x1f => { println!("RAR"); 1},
-        0x20 => { println!("NOP"); 1 },
-        0x21 => { println!("LXI\tH, #${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0x22 => { println!("SHLD\t#${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0x23 => { println!("INX\tH"); 1},
-        0x24 => { println!("INR\tH"); 1},
-        0x25 => { println!("DCR\tH"); 1},
-        0x26 => { println!("MVI\tH, #${:02x}", buffer[1]); 2},
-        0x27 => { println!("DAA"); 1},
-        0x28 => { println!("NOP"); 1},
-        0x29 => { println!("DAD\tH"); 1},
-        0x2a => { println!("LHLD\t#${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0x2b => { println!("DCX\tH"); 1},
-        0x2c => { println!("INR\tL"); 1},
-        0x2d => { println!("DCR\tL"); 1},
-        0x2e => { println!("MVI\tL, #${:02x}", buffer[1]); 2},
-        0x2f => { println!("CMA"); 1},
-        0x30 => { println!("NOP"); 1 },
-        0x31 => { println!("LXI\tSP, #${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0x32 => { println!("STA\t#${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0x33 => { println!("INX\tSP"); 1},
-        0x34 => { println!("INR\tM"); 1},
-        0x35 => { println!("DCR\tM"); 1},
-        0x36 => { println!("MVI\tM, #${:02x}", buffer[1]); 2},
-        0x37 => { println!("STC"); 1},
-        0x38 => { println!("NOP"); 1},
-        0x39 => { println!("DAD\tSP"); 1},
-        0x3a => { println!("LDA\t#${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0x3b => { println!("DCX\tSP"); 1},
-        0x3c => { println!("INR\tA"); 1},
-        0x3d => { println!("DCR\tA"); 1},
-        0x3e => { println!("MVI\tA, #${:02x}", buffer[1]); 2},
-        0x3f => { println!("CMC"); 1},
-        0x40 => { println!("MOV\tB,B"); 1},
-        0x41 => { println!("MOV\tB,C"); 1},
-        0x42 => { println!("MOV\tB,D"); 1},
-        0x43 => { println!("MOV\tB,E"); 1},
-        0x44 => { println!("MOV\tB,H"); 1},
-        0x45 => { println!("MOV\tB,L"); 1},
-        0x46 => { println!("MOV\tB,M"); 1},
-        0x47 => { println!("MOV\tB,A"); 1},
-        0x48 => { println!("MOV\tC,B"); 1},
-        0x49 => { println!("MOV\tC,C"); 1},
-        0x4a => { println!("MOV\tC,D"); 1},
-        0x4b => { println!("MOV\tC,E"); 1},
-        0x4c => { println!("MOV\tC,H"); 1},
-        0x4d => { println!("MOV\tC,L"); 1},
-        0x4e => { println!("MOV\tC,M"); 1},
-        0x4f => { println!("MOV\tC,A"); 1},
-        0x50 => { println!("MOV\tD,B"); 1},
-        0x51 => { println!("MOV\tD,C"); 1},
-        0x52 => { println!("MOV\tD,D"); 1},
-        0x53 => { println!("MOV\tD,E"); 1},
-        0x54 => { println!("MOV\tD,H"); 1},
-        0x55 => { println!("MOV\tD,L"); 1},
-        0x56 => { println!("MOV\tD,M"); 1},
-        0x57 => { println!("MOV\tD,A"); 1},
-        0x58 => { println!("MOV\tE,B"); 1},
-        0x59 => { println!("MOV\tE,C"); 1},
-        0x5a => { println!("MOV\tE,D"); 1},
-        0x5b => { println!("MOV\tE,E"); 1},
-        0x5c => { println!("MOV\tE,H"); 1},
-        0x5d => { println!("MOV\tE,L"); 1},
-        0x5e => { println!("MOV\tE,M"); 1},
-        0x5f => { println!("MOV\tE,A"); 1},
-        0x60 => { println!("MOV\tH,B"); 1},
-        0x61 => { println!("MOV\tH,C"); 1},
-        0x62 => { println!("MOV\tH,D"); 1},
-        0x63 => { println!("MOV\tH,E"); 1},
-        0x64 => { println!("MOV\tH,H"); 1},
-        0x65 => { println!("MOV\tH,L"); 1},
-        0x66 => { println!("MOV\tH,M"); 1},
-        0x67 => { println!("MOV\tH,A"); 1},
-        0x68 => { println!("MOV\tL,B"); 1},
-        0x69 => { println!("MOV\tL,C"); 1},
-        0x6a => { println!("MOV\tL,D"); 1},
-        0x6b => { println!("MOV\tL,E"); 1},
-        0x6c => { println!("MOV\tL,H"); 1},
-        0x6d => { println!("MOV\tL,L"); 1},
-        0x6e => { println!("MOV\tL,M"); 1},
-        0x6f => { println!("MOV\tL,A"); 1},
-        0x70 => { println!("MOV\tM,B"); 1},
-        0x71 => { println!("MOV\tM,C"); 1},
-        0x72 => { println!("MOV\tM,D"); 1},
-        0x73 => { println!("MOV\tM,E"); 1},
-        0x74 => { println!("MOV\tM,H"); 1},
-        0x75 => { println!("MOV\tM,L"); 1},
-        0x76 => { println!("HLT"); 1},
-        0x77 => { println!("MOV\tM,A"); 1},
-        0x78 => { println!("MOV\tA,B"); 1},
-        0x79 => { println!("MOV\tA,C"); 1},
-        0x7a => { println!("MOV\tA,D"); 1},
-        0x7b => { println!("MOV\tA,E"); 1},
-        0x7c => { println!("MOV\tA,H"); 1},
-        0x7d => { println!("MOV\tA,L"); 1},
-        0x7e => { println!("MOV\tA,M"); 1},
-        0x7f => { println!("MOV\tA,A"); 1},
-        0x80 => { println!("ADD\tB"); 1},
-        0x81 => { println!("ADD\tC"); 1},
-        0x82 => { println!("ADD\tD"); 1},
-        0x83 => { println!("ADD\tE"); 1},
-        0x84 => { println!("ADD\tH"); 1},
-        0x85 => { println!("ADD\tL"); 1},
-        0x86 => { println!("ADD\tM"); 1},
-        0x87 => { println!("ADD\tA"); 1},
-        0x88 => { println!("ADC\tB"); 1},
-        0x89 => { println!("ADC\tC"); 1},
-        0x8a => { println!("ADC\tD"); 1},
-        0x8b => { println!("ADC\tE"); 1},
-        0x8c => { println!("ADC\tH"); 1},
-        0x8d => { println!("ADC\tL"); 1},
-        0x8e => { println!("ADC\tM"); 1},
-        0x8f => { println!("ADC\tA"); 1},
-        0x90 => { println!("SUB\tB"); 1},
-        0x91 => { println!("SUB\tC"); 1},
-        0x92 => { println!("SUB\tD"); 1},
-        0x93 => { println!("SUB\tE"); 1},
-        0x94 => { println!("SUB\tH"); 1},
-        0x95 => { println!("SUB\tL"); 1},
-        0x96 => { println!("SUB\tM"); 1},
-        0x97 => { println!("SUB\tA"); 1},
-        0x98 => { println!("SBB\tB"); 1},
-        0x99 => { println!("SBB\tC"); 1},
-        0x9a => { println!("SBB\tD"); 1},
-        0x9b => { println!("SBB\tE"); 1},
-        0x9c => { println!("SBB\tH"); 1},
-        0x9d => { println!("SBB\tL"); 1},
-        0x9e => { println!("SBB\tM"); 1},
-        0x9f => { println!("SBB\tA"); 1},
-        0xa0 => { println!("ANA\tB"); 1},
-        0xa1 => { println!("ANA\tC"); 1},
-        0xa2 => { println!("ANA\tD"); 1},
-        0xa3 => { println!("ANA\tE"); 1},
-        0xa4 => { println!("ANA\tH"); 1},
-        0xa5 => { println!("ANA\tL"); 1},
-        0xa6 => { println!("ANA\tM"); 1},
-        0xa7 => { println!("ANA\tA"); 1},
-        0xa8 => { println!("XRA\tB"); 1},
-        0xa9 => { println!("XRA\tC"); 1},
-        0xaa => { println!("XRA\tD"); 1},
-        0xab => { println!("XRA\tE"); 1},
-        0xac => { println!("XRA\tH"); 1},
-        0xad => { println!("XRA\tL"); 1},
-        0xae => { println!("XRA\tM"); 1},
-        0xaf => { println!("XRA\tA"); 1},
-        0xb0 => { println!("ORA\tB"); 1},
-        0xb1 => { println!("ORA\tC"); 1},
-        0xb2 => { println!("ORA\tD"); 1},
-        0xb3 => { println!("ORA\tE"); 1},
-        0xb4 => { println!("ORA\tH"); 1},
-        0xb5 => { println!("ORA\tL"); 1},
-        0xb6 => { println!("ORA\tM"); 1},
-        0xb7 => { println!("ORA\tA"); 1},
-        0xb8 => { println!("CMP\tB"); 1},
-        0xb9 => { println!("CMP\tC"); 1},
-        0xba => { println!("CMP\tD"); 1},
-        0xbb => { println!("CMP\tE"); 1},
-        0xbc => { println!("CMP\tH"); 1},
-        0xbd => { println!("CMP\tL"); 1},
-        0xbe => { println!("CMP\tM"); 1},
-        0xbf => { println!("CMP\tA"); 1},
-        0xc0 => { println!("RNZ"); 1},
-        0xc1 => { println!("POP\tB"); 1},
-        0xc2 => { println!("JNZ\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xc3 => { println!("JMP\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xc4 => { println!("CNZ\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xc5 => { println!("PUSH\tB"); 1},
-        0xc6 => { println!("ADI\t{:02x}", buffer[1]); 2},
-        0xc7 => { println!("RST\t0"); 1},
-        0xc8 => { println!("RZ\t"); 1},
-        0xc9 => { println!("RET\t"); 1},
-        0xca => { println!("JZ\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xcb => { println!("NOP"); 1},
-        0xcc => { println!("CZ\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xcd => { println!("CALL\t${:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xce => { println!("ACI\t{:02x}", buffer[1]); 2},
-        0xcf => { println!("RST\t1"); 1},
-        0xd0 => { println!("RNC"); 1},
-        0xd1 => { println!("POP\tD"); 1},
-        0xd2 => { println!("JNC\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xd3 => { println!("OUT\t{:02x}", buffer[1]); 2},
-        0xd4 => { println!("CNC\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xd5 => { println!("PUSH\tD"); 1},
-        0xd6 => { println!("SUI\t{:02x}", buffer[1]); 2},
-        0xd7 => { println!("RST\t2"); 1},
-        0xd8 => { println!("RC\t"); 1},
-        0xd9 => { println!("NOP"); 1},
-        0xda => { println!("JC\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xdb => { println!("IN\t{:02x}", buffer[1]); 2},
-        0xdc => { println!("CC\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xdd => { println!("NOP"); 1},
-        0xde => { println!("SBI\t{:02x}", buffer[1]); 2},
-        0xdf => { println!("RST\t3"); 1},
-        0xe0 => { println!("RPO"); 1},
-        0xe1 => { println!("POP\tH"); 1},
-        0xe2 => { println!("JPO\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xe3 => { println!("XHTL"); 1},
-        0xe4 => { println!("CPO\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xe5 => { println!("PUSH\tH"); 1},
-        0xe6 => { println!("ANI\t{:02x}", buffer[1]); 2},
-        0xe7 => { println!("RST\t4"); 1},
-        0xe8 => { println!("RPE\t"); 1},
-        0xe9 => { println!("PCHL"); 1},
-        0xea => { println!("JPE\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xeb => { println!("XCHG"); 1},
-        0xec => { println!("CPE\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xed => { println!("NOP"); 1},
-        0xee => { println!("XRI\t{:02x}", buffer[1]); 2},
-        0xef => { println!("RST\t5"); 1},
-        0xf0 => { println!("RP"); 1},
-        0xf1 => { println!("POP\tPSW"); 1},
-        0xf2 => { println!("JP\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xf3 => { println!("DI"); 1},
-        0xf4 => { println!("CP\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xf5 => { println!("PUSH\tPSW"); 1},
-        0xf6 => { println!("ORI\t{:02x}", buffer[1]); 2},
-        0xf7 => { println!("RST\t6"); 1},
-        0xf8 => { println!("RM\t"); 1},
-        0xf9 => { println!("SPHL"); 1},
-        0xfa => { println!("JM\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xfb => { println!("EI"); 1},
-        0xfc => { println!("CM\t{:02x}{:02x}", buffer[2], buffer[1]); 3},
-        0xfd => { println!("NOP"); 1},
-        0xfe => { println!("CPI\t{:02x}", buffer[1]); 2},
-        0xff => { println!("RST\t7"); 1},
+use crate::instruction::{decode, Instruction, Operation, Register, RegisterPair};
+use crate::symbols::SymbolTable;
+use std::fmt;
+use std::io;
+
+/// Which mnemonics to print.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Syntax {
+    /// The 8080's own: `MOV A,M`, `LXI H,#$2400`, `JNZ $0010`.
+    Intel,
+    /// The Z80 names for the same instructions: `LD A,(HL)`, `LD HL,2400h`,
+    /// `JP NZ,0010h`.
+    Zilog,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Options<'a> {
+    pub syntax: Syntax,
+    /// Show the instruction's bytes in a column between the address and the
+    /// mnemonic.
+    pub hex_dump: bool,
+    /// Names to show instead of the addresses they stand for.
+    pub symbols: Option<&'a SymbolTable>,
+}
+
+impl<'a> Default for Options<'a> {
+    fn default() -> Self {
+        Options { syntax: Syntax::Intel, hex_dump: false, symbols: None }
+    }
+}
+
+/// One disassembled instruction, formatted by its `Display` impl.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Line<'a> {
+    pub address: u16,
+    pub instruction: Instruction,
+    pub options: Options<'a>,
+}
+
+impl<'a> Line<'a> {
+    pub fn length(&self) -> usize {
+        self.instruction.length() as usize
+    }
+}
+
+impl<'a> fmt::Display for Line<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}\t", self.address)?;
+        if self.options.hex_dump {
+            let bytes = self.instruction.bytes().iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>();
+            write!(f, "{:<8}\t", bytes.join(" "))?;
+        }
+        let operation = &self.instruction.operation;
+        let text = match self.options.syntax {
+            Syntax::Intel => operation.to_string(),
+            Syntax::Zilog => Zilog(operation).to_string(),
+        };
+        // Swap a 16 bit operand for the symbol it points at
+        let value = operation.operand().filter(|_| self.instruction.length() == 3);
+        let name = match (value, self.options.symbols) {
+            (Some(value), Some(symbols)) => symbols.describe(value).map(|name| (value, name)),
+            _ => None,
+        };
+        if let Some((value, name)) = name {
+            let numbers = match self.options.syntax {
+                Syntax::Intel => vec![format!("#${:04x}", value), format!("${:04x}", value)],
+                Syntax::Zilog => vec![Hex16(value).to_string()],
+            };
+            if let Some(number) = numbers.iter().find(|n| text.contains(n.as_str())) {
+                return f.write_str(&text.replacen(number.as_str(), &name, 1));
+            }
+        }
+        f.write_str(&text)
+    }
+}
+
+/// Decodes the instruction at the start of `buffer`, which sits at `pc`.
+pub fn disassemble(buffer: &[u8], pc: usize) -> Line<'static> {
+    disassemble_with(buffer, pc, Options::default())
+}
+
+pub fn disassemble_with<'a>(buffer: &[u8], pc: usize, options: Options<'a>) -> Line<'a> {
+    Line { address: pc as u16, instruction: decode(buffer), options }
+}
+
+/// Writes one line per instruction for all of `buffer`, which is loaded at
+/// `origin`. Each symbol in `options` gets a `name:` line before its address.
+pub fn write_listing<W: io::Write>(out: &mut W, buffer: &[u8], origin: usize, options: Options) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < buffer.len() {
+        let line = disassemble_with(&buffer[offset..], origin + offset, options);
+        if let Some(symbol) = options.symbols.and_then(|symbols| symbols.get(line.address)) {
+            match &symbol.comment {
+                Some(comment) => writeln!(out, "{}:\t\t; {}", symbol.name, comment)?,
+                None => writeln!(out, "{}:", symbol.name)?,
+            }
+        }
+        writeln!(out, "{}", line)?;
+        offset += line.length();
+    }
+    Ok(())
+}
+
+// Zilog's operand names and number format
+struct Zilog<'a>(&'a Operation);
+
+struct Hex8(u8);
+struct Hex16(u16);
+
+// Zilog numbers end in h and must not start with a letter: 0FFh
+impl fmt::Display for Hex8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 >= 0xa0 {
+            write!(f, "0{:02X}h", self.0)
+        } else {
+            write!(f, "{:02X}h", self.0)
+        }
+    }
+}
+
+impl fmt::Display for Hex16 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 >= 0xa000 {
+            write!(f, "0{:04X}h", self.0)
+        } else {
+            write!(f, "{:04X}h", self.0)
+        }
+    }
+}
+
+fn register(r: Register) -> &'static str {
+    match r {
+        Register::A => "A",
+        Register::B => "B",
+        Register::C => "C",
+        Register::D => "D",
+        Register::E => "E",
+        Register::H => "H",
+        Register::L => "L",
+        Register::M => "(HL)",
+    }
+}
+
+fn pair(rp: RegisterPair) -> &'static str {
+    match rp {
+        RegisterPair::BC => "BC",
+        RegisterPair::DE => "DE",
+        RegisterPair::HL => "HL",
+        RegisterPair::SP => "SP",
+        RegisterPair::PSW => "AF",
+    }
+}
+
+impl<'a> fmt::Display for Zilog<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self.0 {
+            Operation::Nop => write!(f, "NOP"),
+            Operation::Lxi(rp, v) => write!(f, "LD\t{},{}", pair(rp), Hex16(v)),
+            Operation::Stax(rp) => write!(f, "LD\t({}),A", pair(rp)),
+            Operation::Ldax(rp) => write!(f, "LD\tA,({})", pair(rp)),
+            Operation::Shld(a) => write!(f, "LD\t({}),HL", Hex16(a)),
+            Operation::Lhld(a) => write!(f, "LD\tHL,({})", Hex16(a)),
+            Operation::Sta(a) => write!(f, "LD\t({}),A", Hex16(a)),
+            Operation::Lda(a) => write!(f, "LD\tA,({})", Hex16(a)),
+            Operation::Inx(rp) => write!(f, "INC\t{}", pair(rp)),
+            Operation::Dcx(rp) => write!(f, "DEC\t{}", pair(rp)),
+            Operation::Dad(rp) => write!(f, "ADD\tHL,{}", pair(rp)),
+            Operation::Inr(r) => write!(f, "INC\t{}", register(r)),
+            Operation::Dcr(r) => write!(f, "DEC\t{}", register(r)),
+            Operation::Mvi(r, v) => write!(f, "LD\t{},{}", register(r), Hex8(v)),
+            Operation::Rlc => write!(f, "RLCA"),
+            Operation::Rrc => write!(f, "RRCA"),
+            Operation::Ral => write!(f, "RLA"),
+            Operation::Rar => write!(f, "RRA"),
+            Operation::Daa => write!(f, "DAA"),
+            Operation::Cma => write!(f, "CPL"),
+            Operation::Stc => write!(f, "SCF"),
+            Operation::Cmc => write!(f, "CCF"),
+            Operation::Mov(d, s) => write!(f, "LD\t{},{}", register(d), register(s)),
+            Operation::Hlt => write!(f, "HALT"),
+            Operation::Add(r) => write!(f, "ADD\tA,{}", register(r)),
+            Operation::Adc(r) => write!(f, "ADC\tA,{}", register(r)),
+            Operation::Sub(r) => write!(f, "SUB\t{}", register(r)),
+            Operation::Sbb(r) => write!(f, "SBC\tA,{}", register(r)),
+            Operation::Ana(r) => write!(f, "AND\t{}", register(r)),
+            Operation::Xra(r) => write!(f, "XOR\t{}", register(r)),
+            Operation::Ora(r) => write!(f, "OR\t{}", register(r)),
+            Operation::Cmp(r) => write!(f, "CP\t{}", register(r)),
+            Operation::Adi(v) => write!(f, "ADD\tA,{}", Hex8(v)),
+            Operation::Aci(v) => write!(f, "ADC\tA,{}", Hex8(v)),
+            Operation::Sui(v) => write!(f, "SUB\t{}", Hex8(v)),
+            Operation::Sbi(v) => write!(f, "SBC\tA,{}", Hex8(v)),
+            Operation::Ani(v) => write!(f, "AND\t{}", Hex8(v)),
+            Operation::Xri(v) => write!(f, "XOR\t{}", Hex8(v)),
+            Operation::Ori(v) => write!(f, "OR\t{}", Hex8(v)),
+            Operation::Cpi(v) => write!(f, "CP\t{}", Hex8(v)),
+            Operation::Jmp(a) => write!(f, "JP\t{}", Hex16(a)),
+            Operation::Jcc(c, a) => write!(f, "JP\t{},{}", c, Hex16(a)),
+            Operation::Call(a) => write!(f, "CALL\t{}", Hex16(a)),
+            Operation::Ccc(c, a) => write!(f, "CALL\t{},{}", c, Hex16(a)),
+            Operation::Ret => write!(f, "RET"),
+            Operation::Rcc(c) => write!(f, "RET\t{}", c),
+            Operation::Rst(n) => write!(f, "RST\t{}", Hex8(n * 8)),
+            Operation::Pchl => write!(f, "JP\t(HL)"),
+            Operation::Push(rp) => write!(f, "PUSH\t{}", pair(rp)),
+            Operation::Pop(rp) => write!(f, "POP\t{}", pair(rp)),
+            Operation::Xthl => write!(f, "EX\t(SP),HL"),
+            Operation::Sphl => write!(f, "LD\tSP,HL"),
+            Operation::Xchg => write!(f, "EX\tDE,HL"),
+            Operation::In(p) => write!(f, "IN\tA,({})", Hex8(p)),
+            Operation::Out(p) => write!(f, "OUT\t({}),A", Hex8(p)),
+            Operation::Ei => write!(f, "EI"),
+            Operation::Di => write!(f, "DI"),
+        }
     }
 }
@@ -0,0 +1,39 @@
+use std::error;
+use std::fmt;
+
+/// Why the CPU could not execute the instruction at `pc`.
+///
+/// The 8080 itself has no faults, so `step` only returns these for the
+/// checks a front end turns on in `CPUState::checks`. The registers are left
+/// as they were before the faulting instruction, so the caller can inspect
+/// or dump them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EmulationError {
+    /// The byte at `pc` is one of the undocumented opcodes, with
+    /// `Checks::documented_only` on.
+    IllegalOpcode { pc: u16, opcode: u8 },
+    /// A push (PUSH, CALL, RST) would take SP below the lowest one
+    /// `Checks::stack` allows.
+    StackOverflow { pc: u16, sp: u16 },
+    /// A pop (POP, RET) would take SP above the highest one `Checks::stack`
+    /// allows.
+    StackUnderflow { pc: u16, sp: u16 },
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EmulationError::IllegalOpcode { pc, opcode } => {
+                write!(f, "illegal opcode 0x{:02x} at {:04x}", opcode, pc)
+            }
+            EmulationError::StackOverflow { pc, sp } => {
+                write!(f, "stack overflow at {:04x} (SP: {:04x})", pc, sp)
+            }
+            EmulationError::StackUnderflow { pc, sp } => {
+                write!(f, "stack underflow at {:04x} (SP: {:04x})", pc, sp)
+            }
+        }
+    }
+}
+
+impl error::Error for EmulationError {}
@@ -0,0 +1,394 @@
+use crate::bus::Bus;
+use crate::cpu::CPUState;
+use crate::debugger::{Debugger, Stop};
+use crate::helpers::set_psw;
+use crate::traps::{Access, Direction, Trap};
+use std::collections::BTreeSet;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+
+// GDB knows nothing of the 8080, so the register layout is described to it:
+// the 8 bit registers, then the flags as PUSH PSW stores them, SP and PC
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.i8080.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="c" bitsize="8" type="uint8"/>
+    <reg name="d" bitsize="8" type="uint8"/>
+    <reg name="e" bitsize="8" type="uint8"/>
+    <reg name="h" bitsize="8" type="uint8"/>
+    <reg name="l" bitsize="8" type="uint8"/>
+    <reg name="flags" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Instructions run between checks for a Ctrl-C from GDB while continuing
+const CHUNK: u64 = 10_000;
+
+// The longest packet GDB is told it may send, also the most bytes an m
+// packet may read
+const PACKET_SIZE: usize = 0x1000;
+
+/// What to do after a packet.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Packet(String),
+    /// GDB killed or detached from the machine.
+    Close(Option<String>),
+}
+
+/// A GDB remote serial protocol stub for the machine in a `Debugger`.
+///
+/// Registers are A, B, C, D, E, H, L, the flags, SP and PC, which GDB learns
+/// from the target description. Memory can be read and written (ROM too),
+/// execution stepped or continued, and Ctrl-C stops it. Software breakpoints
+/// (Z0) and write, read and access watchpoints (Z2, Z3, Z4) are supported,
+/// and so are reverse step and continue (bs, bc) over the debugger's
+/// history.
+pub struct GdbServer {
+    pub debugger: Debugger,
+    pub breakpoints: BTreeSet<u16>,
+}
+
+impl GdbServer {
+    pub fn new(debugger: Debugger) -> GdbServer {
+        GdbServer { debugger, breakpoints: BTreeSet::new() }
+    }
+
+    /// Serves GDB connections on `listener`, one at a time, until one of
+    /// them kills the machine.
+    pub fn serve(&mut self, listener: &TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            if self.session(stream?)? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    // One connection. Returns whether GDB killed the machine.
+    fn session(&mut self, mut stream: TcpStream) -> io::Result<bool> {
+        stream.set_nodelay(true)?;
+        // What came in while continuing that was not a Ctrl-C
+        let mut pending = Vec::new();
+        loop {
+            let mut input = Cursor::new(std::mem::take(&mut pending)).chain(&mut stream);
+            let packet = read_packet(&mut input)?;
+            let (rest, _) = input.into_inner();
+            pending = rest.get_ref()[rest.position() as usize..].to_vec();
+            let packet = match packet {
+                Some(packet) => packet,
+                None => return Ok(false),
+            };
+            stream.write_all(b"+")?;
+            let mut check = || interrupted(&mut stream, &mut pending);
+            match self.handle(&packet, &mut check) {
+                Response::Packet(reply) => write_packet(&mut stream, &reply)?,
+                Response::Close(reply) => {
+                    if let Some(reply) = reply {
+                        write_packet(&mut stream, &reply)?;
+                    }
+                    return Ok(packet == "k");
+                }
+            }
+        }
+    }
+
+    /// Answers one packet, without the `$` and checksum. `interrupted` is
+    /// asked now and then while continuing whether GDB wants it stopped.
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Response {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'g') => self.registers(),
+            Some(b'G') => self.set_registers(&packet[1..]),
+            Some(b'p') => self.register(&packet[1..]),
+            Some(b'P') => self.set_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            // s and c may say where to resume
+            Some(b's') | Some(b'c') if packet.len() > 1 => match u16::from_str_radix(&packet[1..], 16) {
+                Ok(address) => {
+                    let cpu = self.debugger.machine.cpu;
+                    self.debugger.set_cpu(CPUState { pc: address, ..cpu });
+                    return self.handle(&packet[..1], interrupted);
+                }
+                Err(_) => "E01".to_string(),
+            },
+            Some(b's') => {
+                let stop = self.debugger.step(1);
+                self.stop_reply(stop)
+            }
+            Some(b'c') => self.resume(interrupted),
+            Some(b'b') if packet == "bs" => {
+                let stop = self.debugger.reverse(1, |_| false);
+                self.stop_reply(stop)
+            }
+            Some(b'b') if packet == "bc" => self.resume_backwards(interrupted),
+            Some(b'Z') | Some(b'z') => self.breakpoint(packet),
+            Some(b'H') => "OK".to_string(),
+            Some(b'k') => return Response::Close(None),
+            Some(b'D') => return Response::Close(Some("OK".to_string())),
+            _ if packet.starts_with("qSupported") => format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;ReverseStep+;ReverseContinue+",
+                PACKET_SIZE
+            ),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                read_chunk(TARGET_XML, &packet["qXfer:features:read:target.xml:".len()..])
+            }
+            _ if packet == "qAttached" => "1".to_string(),
+            // Anything else is not supported, which an empty reply says
+            _ => String::new(),
+        };
+        Response::Packet(reply)
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Reached(_) => "T05swbreak:;".to_string(),
+            Stop::Trap { number, access: Some(access) } => {
+                // Z4 watchpoints stop on both and are reported as such
+                let both = matches!(
+                    self.debugger.traps.get(&number),
+                    Some(Trap::Watchpoint { direction: Direction::Both, .. })
+                );
+                match access {
+                    Access::Read(address, _) | Access::Write(address, _) if both => {
+                        format!("T05awatch:{:x};", address)
+                    }
+                    Access::Read(address, _) => format!("T05rwatch:{:x};", address),
+                    Access::Write(address, _) => format!("T05watch:{:x};", address),
+                    _ => "S05".to_string(),
+                }
+            }
+            // SIGILL for the bad programs step reports
+            Stop::Error(_) => "S04".to_string(),
+            Stop::HistoryStart => "T05replaylog:begin;".to_string(),
+            _ => "S05".to_string(),
+        }
+    }
+
+    fn resume(&mut self, interrupted: &mut dyn FnMut() -> bool) -> String {
+        loop {
+            let breakpoints = &self.breakpoints;
+            match self.debugger.run(CHUNK, |machine| breakpoints.contains(&machine.cpu.pc)) {
+                Stop::Done if interrupted() => return "S02".to_string(),
+                Stop::Done => {}
+                stop => return self.stop_reply(stop),
+            }
+        }
+    }
+
+    fn resume_backwards(&mut self, interrupted: &mut dyn FnMut() -> bool) -> String {
+        loop {
+            let breakpoints = &self.breakpoints;
+            match self.debugger.reverse(CHUNK, |machine| breakpoints.contains(&machine.cpu.pc)) {
+                Stop::Done if interrupted() => return "S02".to_string(),
+                Stop::Done => {}
+                stop => return self.stop_reply(stop),
+            }
+        }
+    }
+
+    fn registers(&self) -> String {
+        let cpu = &self.debugger.machine.cpu;
+        let bytes = [cpu.a, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, cpu.cc.psw()];
+        let mut text: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        for value in &[cpu.sp, cpu.pc] {
+            text += &format!("{:02x}{:02x}", *value as u8, value >> 8);
+        }
+        text
+    }
+
+    fn set_registers(&mut self, hex: &str) -> String {
+        let bytes = match decode_hex(hex) {
+            Some(bytes) if bytes.len() == 12 => bytes,
+            _ => return "E01".to_string(),
+        };
+        let mut cpu = self.debugger.machine.cpu;
+        cpu.a = bytes[0];
+        cpu.b = bytes[1];
+        cpu.c = bytes[2];
+        cpu.d = bytes[3];
+        cpu.e = bytes[4];
+        cpu.h = bytes[5];
+        cpu.l = bytes[6];
+        cpu.cc = set_psw(bytes[7]);
+        cpu.sp = u16::from_le_bytes([bytes[8], bytes[9]]);
+        cpu.pc = u16::from_le_bytes([bytes[10], bytes[11]]);
+        self.debugger.set_cpu(cpu);
+        "OK".to_string()
+    }
+
+    fn register(&self, number: &str) -> String {
+        match register_range(number) {
+            Some(range) => self.registers()[range].to_string(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn set_register(&mut self, assignment: &str) -> String {
+        let mut registers = self.registers();
+        let (number, value) = match assignment.split_once('=') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let range = match register_range(number) {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        if value.len() != range.len() {
+            return "E01".to_string();
+        }
+        registers.replace_range(range, value);
+        self.set_registers(&registers)
+    }
+
+    fn read_memory(&mut self, arguments: &str) -> String {
+        match address_and_length(arguments) {
+            Some((_, length)) if length > PACKET_SIZE => "E01".to_string(),
+            Some((address, length)) => (0..length)
+                .map(|i| format!("{:02x}", self.debugger.machine.read(address.wrapping_add(i as u16))))
+                .collect(),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let (range, data) = match arguments.split_once(':') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        match (address_and_length(range), decode_hex(data)) {
+            (Some((address, length)), Some(bytes)) if bytes.len() == length => {
+                self.debugger.poke(address, &bytes);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Zt,address,kind inserts and zt,address,kind removes
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let insert = packet.starts_with('Z');
+        let fields: Vec<&str> = packet[1..].split(',').collect();
+        let address = match fields.get(1).and_then(|a| u16::from_str_radix(a, 16).ok()) {
+            Some(address) => address,
+            None => return "E01".to_string(),
+        };
+        let length = fields.get(2).and_then(|l| u16::from_str_radix(l, 16).ok()).unwrap_or(1).max(1);
+        let direction = match fields[0] {
+            "0" => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            "2" => Direction::Out,
+            "3" => Direction::In,
+            "4" => Direction::Both,
+            _ => return String::new(),
+        };
+        let trap = Trap::Watchpoint { start: address, end: address.wrapping_add(length - 1), direction };
+        if insert {
+            self.debugger.add_trap(trap);
+        } else {
+            self.debugger.traps.retain(|_, t| *t != trap);
+        }
+        "OK".to_string()
+    }
+}
+
+// Where register `number` is in the reply to g
+fn register_range(number: &str) -> Option<Range<usize>> {
+    match usize::from_str_radix(number, 16) {
+        Ok(n) if n < 8 => Some(n * 2..n * 2 + 2),
+        Ok(n) if n < 10 => Some(16 + (n - 8) * 4..20 + (n - 8) * 4),
+        _ => None,
+    }
+}
+
+fn address_and_length(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+// None for anything but pairs of hex digits
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// qXfer replies: m and more to come, or l and the last of it
+fn read_chunk(document: &str, range: &str) -> String {
+    let (offset, length) = match range.split_once(',') {
+        Some((o, l)) => (usize::from_str_radix(o, 16).unwrap_or(0), usize::from_str_radix(l, 16).unwrap_or(0)),
+        None => return "E01".to_string(),
+    };
+    let start = offset.min(document.len());
+    let end = (start + length).min(document.len());
+    let marker = if end == document.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, &document[start..end])
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn write_packet<W: Write>(out: &mut W, data: &str) -> io::Result<()> {
+    write!(out, "${}#{:02x}", data, checksum(data))?;
+    out.flush()
+}
+
+// The next packet's data, skipping acknowledgements and Ctrl-Cs outside a
+// continue. None when GDB hangs up.
+fn read_packet<R: Read>(input: &mut R) -> io::Result<Option<String>> {
+    let mut byte = [0u8];
+    loop {
+        if input.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+    let mut data = Vec::new();
+    loop {
+        if input.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'#' {
+            break;
+        }
+        data.push(byte[0]);
+    }
+    // The checksum is not checked: TCP already does
+    let mut sum = [0u8; 2];
+    input.read_exact(&mut sum)?;
+    Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+}
+
+// Whether GDB has sent a Ctrl-C (0x03) since the last look. Any other byte
+// is kept in `pending` for the next packet.
+fn interrupted(stream: &mut TcpStream, pending: &mut Vec<u8>) -> bool {
+    let mut byte = [0u8];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let interrupted = match stream.read(&mut byte) {
+        Ok(1) if byte[0] == 0x03 => true,
+        Ok(1) => {
+            pending.push(byte[0]);
+            false
+        }
+        _ => false,
+    };
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
@@ -1,15 +1,15 @@
-use std::fs::File;
+use crate::bus::Bus;
 use crate::invaders::Machine;
-use crate::cpu::CPUState;
+use crate::cpu::{CPUState, CYCLES8080};
 use crate::condition_codes::{Flags as ConditionCodes};
-use std::io::Read;
+use crate::rom;
 
+/// A machine with the ROM from `rom::DEFAULT_PATH` loaded, and the ROM.
+/// Panics when it cannot be loaded; use `rom::load` to handle that.
 pub fn new_machine() -> (Machine, Vec<u8>) {
-    let mut buffer = Vec::new();
-    let mut f = File::open("invaders").unwrap();
-    f.read_to_end(&mut buffer).unwrap();
+    let buffer = rom::load(rom::DEFAULT_PATH).unwrap_or_else(|e| panic!("{}", e));
     let mut machine = Machine::new();
-    machine.load_rom(0x00);
+    machine.load_rom(&buffer);
     (machine , buffer)
 }
 
@@ -27,7 +27,8 @@ pub fn arith_flags(answer: u16) -> (u8, u8, u8, u8, u8) {
     let z = if answer & 0xff == 0 { 1 } else { 0 };
     let s = if answer & 0x80 == 0x80 { 1 } else { 0 };
     let p = if parity(answer) == 1 { 1 } else { 0 };
-    let ac = if answer > 0xf { 1 } else { 0 };
+    // AC depends on the operands, not on the answer, see add_flags/sub_flags
+    let ac = 0;
     (z, s, cy, p, ac)
 }
 
@@ -36,7 +37,7 @@ pub fn arith_flags_logs(answer: u16) -> (u8, u8, u8, u8, u8) {
     let z = if answer & 0xff == 0 { 1 } else { 0 };
     let s = if answer & (1 << 7) != 0 { 1 } else { 0 };
     let p = if parity(answer) == 1 { 1 } else { 0 };
-    let ac = if answer > 0xf { 1 } else { 0 };
+    let ac = 0;
     (z, s, cy, p, ac)
 }
 
@@ -64,42 +65,54 @@ pub fn set_all_flags(answer: u16) -> ConditionCodes {
     create_cc_with_arg(arith_flags(answer))
 }
 
-pub fn get_value_memory(memory: &Vec<u8>, hr: u8, lr: u8) -> u8 {
-    let address: u16 = (hr as u16) << 8 | lr as u16;
-    memory[address as usize]
+// Flags for A + value + carry, AC is the carry out of bit 3
+pub fn add_flags(a: u8, value: u8, carry: u8) -> ConditionCodes {
+    let answer: u16 = a as u16 + value as u16 + carry as u16;
+    let ac = if (a & 0xf) + (value & 0xf) + carry > 0xf { 1 } else { 0 };
+    ConditionCodes { ac, ..set_all_flags(answer) }
+}
+
+// Flags for A - value - borrow. The 8080 subtracts by adding the complement,
+// so AC is the carry out of bit 3 of A + !value + !borrow, not a half borrow
+pub fn sub_flags(a: u8, value: u8, borrow: u8) -> ConditionCodes {
+    let answer: u16 = (a as u16).wrapping_sub(value as u16).wrapping_sub(borrow as u16);
+    let ac = if (a & 0xf) + (!value & 0xf) + (1 - borrow) > 0xf { 1 } else { 0 };
+    ConditionCodes { ac, ..set_all_flags(answer) }
 }
 
-pub fn write_memory(mut memory: Vec<u8>, address: u16, value: u8) -> Vec<u8> {
-    memory[address as usize] = value;
-    memory
+pub fn get_value_memory<B: Bus>(bus: &mut B, hr: u8, lr: u8) -> u8 {
+    let address: u16 = (hr as u16) << 8 | lr as u16;
+    bus.read(address)
 }
 
-pub fn generate_interrupt(cpu: CPUState, interrupt_num: u32) -> CPUState {
+pub fn generate_interrupt<B: Bus>(cpu: CPUState, bus: &mut B, interrupt_num: u32) -> CPUState {
     //println!("Pushing to Stack(Interrupt): {:04x}",cpu.pc);
     //println!("Pushing to Stack(Interrupt/CY): {:04x}{:04x}{:04x}", cpu.pc, cpu.cc.cy, interrupt_num);
     let pc = cpu.pc;
-    let cpu = push_to_stack_addr(cpu, pc);
+    let cpu = push_to_stack_addr(cpu, bus, pc);
     //println!("Pushing to Stack(Interrupt): {:04x}",8*(interrupt_num));
-    let x = CPUState {
+    // The interrupting device jams an RST onto the bus, which costs what RST does
+    let cycles = CYCLES8080[0xc7];
+    CPUState {
         pc: 8*(interrupt_num as u16),
         int_enable: false,
+        halted: false,
+        cycles,
+        total_cycles: cpu.total_cycles + cycles as u64,
         ..cpu
-    };
-    x
+    }
 }
 
-pub fn pop_from_stack(cpu: CPUState) -> (CPUState, u16) {
-    let val = ((cpu.memory[cpu.sp as usize + 1] as u16) << 8) | (cpu.memory[cpu.sp as usize] as u16);
+pub fn pop_from_stack<B: Bus>(cpu: CPUState, bus: &mut B) -> (CPUState, u16) {
+    let val = ((bus.read(cpu.sp.wrapping_add(1)) as u16) << 8) | (bus.read(cpu.sp) as u16);
     (CPUState { sp: cpu.sp.wrapping_add(2), ..cpu}, val)
 }
 
-pub fn push_to_stack_addr(cpu: CPUState, addr : u16) -> CPUState {
+pub fn push_to_stack_addr<B: Bus>(cpu: CPUState, bus: &mut B, addr : u16) -> CPUState {
     //println!("Pushing to stack addr: {:04x} at SP: {:08x}",addr, cpu.sp);
-    let mut memory = cpu.memory;
-    memory[cpu.sp as usize - 1] = (addr >> 8) as u8;
-    memory[cpu.sp as usize - 2] = addr as u8;
+    bus.write(cpu.sp.wrapping_sub(1), (addr >> 8) as u8);
+    bus.write(cpu.sp.wrapping_sub(2), addr as u8);
     CPUState {
-        memory,
         sp: cpu.sp.wrapping_sub(2),
         ..cpu
     }
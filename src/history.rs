@@ -0,0 +1,154 @@
+use crate::cpu::CPUState;
+use crate::invaders::{Machine, CYCLES_PER_FRAME, INVADERS_MEMORY_SIZE};
+use crate::traps::Access;
+use std::collections::VecDeque;
+
+/// The whole machine as it was at the start of a frame, and whatever the
+/// owner of the history keeps along with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot<T> {
+    pub machine: Machine,
+    pub extra: T,
+}
+
+// What one instruction changed, enough to undo it
+#[derive(Clone, Debug)]
+struct Delta<T> {
+    cpu: CPUState,
+    // shift0, shift1, shift_offset, in_port1 and in_port2
+    ports: [u8; 5],
+    extra: T,
+    // Where this instruction's writes end in the frame's `overwritten`
+    writes_end: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Frame<T> {
+    snapshot: Snapshot<T>,
+    deltas: Vec<Delta<T>>,
+    // The address and previous value of each write, oldest first
+    overwritten: Vec<(u16, u8)>,
+}
+
+/// How the machine got to where it is, for going back: a snapshot per frame
+/// (`CYCLES_PER_FRAME` cycles) and, since each, what every instruction
+/// changed. Only the last `limit` frames are kept.
+#[derive(Clone, Debug)]
+pub struct History<T: Clone> {
+    frames: VecDeque<Frame<T>>,
+    limit: usize,
+    // The state before the instruction being executed
+    pending: Option<Delta<T>>,
+}
+
+impl<T: Clone> History<T> {
+    pub fn new(limit: usize) -> History<T> {
+        History { frames: VecDeque::new(), limit, pending: None }
+    }
+
+    /// Frames kept; 0 records nothing.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Keeps `limit` frames from now on, dropping the oldest ones if there
+    /// are more.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.frames.len() > limit {
+            self.frames.pop_front();
+        }
+    }
+
+    /// Call before executing an instruction on `machine`, or changing it in
+    /// any other way going back should undo, with the owner's state to
+    /// restore along with it. Takes a snapshot if a frame's worth of cycles
+    /// went by since the last one.
+    pub fn before(&mut self, machine: &Machine, extra: T) {
+        if self.limit == 0 {
+            return;
+        }
+        let cycles = machine.cpu.total_cycles;
+        let due = match self.frames.back() {
+            Some(frame) => cycles >= frame.snapshot.machine.cpu.total_cycles + CYCLES_PER_FRAME,
+            None => true,
+        };
+        if due {
+            let snapshot = Snapshot { machine: machine.clone(), extra: extra.clone() };
+            self.frames.push_back(Frame { snapshot, deltas: Vec::new(), overwritten: Vec::new() });
+        }
+        self.set_limit(self.limit);
+        let ports = [machine.shift0, machine.shift1, machine.shift_offset, machine.in_port1, machine.in_port2];
+        self.pending = Some(Delta { cpu: machine.cpu, ports, extra, writes_end: 0 });
+    }
+
+    /// Call once the instruction announced by `before` executed, with the
+    /// address and previous value of everything it wrote.
+    pub fn after(&mut self, overwritten: &[(u16, u8)]) {
+        if let (Some(mut delta), Some(frame)) = (self.pending.take(), self.frames.back_mut()) {
+            frame.overwritten.extend_from_slice(overwritten);
+            delta.writes_end = frame.overwritten.len();
+            frame.deltas.push(delta);
+        }
+    }
+
+    /// Undoes the last instruction recorded, returning the owner's state
+    /// from before it and the writes it made, or None when there is nothing
+    /// left to undo.
+    pub fn step_back(&mut self, machine: &mut Machine) -> Option<(T, Vec<Access>)> {
+        loop {
+            let frame = self.frames.back_mut()?;
+            let delta = match frame.deltas.pop() {
+                Some(delta) => delta,
+                None => {
+                    self.frames.pop_back();
+                    continue;
+                }
+            };
+            let start = frame.deltas.last().map(|d| d.writes_end).unwrap_or(0);
+            let mut undone = Vec::new();
+            for &(address, value) in frame.overwritten[start..].iter().rev() {
+                let index = address as usize % INVADERS_MEMORY_SIZE;
+                undone.push(Access::Write(address, machine.memory[index]));
+                machine.memory[index] = value;
+            }
+            frame.overwritten.truncate(start);
+            undone.reverse();
+            machine.cpu = delta.cpu;
+            let [shift0, shift1, shift_offset, in_port1, in_port2] = delta.ports;
+            machine.shift0 = shift0;
+            machine.shift1 = shift1;
+            machine.shift_offset = shift_offset;
+            machine.in_port1 = in_port1;
+            machine.in_port2 = in_port2;
+            return Some((delta.extra, undone));
+        }
+    }
+
+    /// Puts `machine` back as it was at the start of the current frame, or
+    /// `frames` frames before that, forgetting everything since. None if
+    /// the history does not go back that far.
+    pub fn rewind(&mut self, frames: usize, machine: &mut Machine) -> Option<T> {
+        let index = self.frames.len().checked_sub(frames + 1)?;
+        self.frames.truncate(index + 1);
+        let frame = &mut self.frames[index];
+        frame.deltas.clear();
+        frame.overwritten.clear();
+        *machine = frame.snapshot.machine.clone();
+        Some(frame.snapshot.extra.clone())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Instructions that can be undone.
+    pub fn instructions(&self) -> usize {
+        self.frames.iter().map(|frame| frame.deltas.len()).sum()
+    }
+
+    /// The cycle count of the oldest state kept.
+    pub fn oldest(&self) -> Option<u64> {
+        self.frames.front().map(|frame| frame.snapshot.machine.cpu.total_cycles)
+    }
+}
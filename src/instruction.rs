@@ -0,0 +1,508 @@
+use crate::condition_codes::Flags;
+use crate::cpu::CYCLES8080;
+use std::fmt;
+
+/// An 8 bit operand, as encoded in bits 0-2 (source) or 3-5 (destination) of
+/// an opcode. `M` is the byte at the address in HL.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Register {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    M,
+    A,
+}
+
+/// A 16 bit operand, as encoded in bits 4-5 of an opcode. PUSH and POP use
+/// `PSW` (A and the flags) where the other instructions use `SP`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RegisterPair {
+    BC,
+    DE,
+    HL,
+    SP,
+    PSW,
+}
+
+/// The condition of a Jcc, Ccc or Rcc, as encoded in bits 3-5 of the opcode.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Condition {
+    NZ,
+    Z,
+    NC,
+    C,
+    PO,
+    PE,
+    P,
+    M,
+}
+
+/// What an instruction does, with its operands.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Nop,
+    Lxi(RegisterPair, u16),
+    Stax(RegisterPair),
+    Ldax(RegisterPair),
+    Shld(u16),
+    Lhld(u16),
+    Sta(u16),
+    Lda(u16),
+    Inx(RegisterPair),
+    Dcx(RegisterPair),
+    Dad(RegisterPair),
+    Inr(Register),
+    Dcr(Register),
+    Mvi(Register, u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Daa,
+    Cma,
+    Stc,
+    Cmc,
+    /// Destination first, as in the mnemonic.
+    Mov(Register, Register),
+    Hlt,
+    Add(Register),
+    Adc(Register),
+    Sub(Register),
+    Sbb(Register),
+    Ana(Register),
+    Xra(Register),
+    Ora(Register),
+    Cmp(Register),
+    Adi(u8),
+    Aci(u8),
+    Sui(u8),
+    Sbi(u8),
+    Ani(u8),
+    Xri(u8),
+    Ori(u8),
+    Cpi(u8),
+    Jmp(u16),
+    Jcc(Condition, u16),
+    Call(u16),
+    Ccc(Condition, u16),
+    Ret,
+    Rcc(Condition),
+    Rst(u8),
+    Pchl,
+    Push(RegisterPair),
+    Pop(RegisterPair),
+    Xthl,
+    Sphl,
+    Xchg,
+    In(u8),
+    Out(u8),
+    Ei,
+    Di,
+}
+
+/// A decoded instruction: the opcode byte it came from and its operation.
+///
+/// Several undocumented opcodes decode to the same operation as a documented
+/// one (0xcb is JMP, 0xd9 RET, 0x08 NOP...), `opcode` tells them apart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    pub opcode: u8,
+    pub operation: Operation,
+}
+
+const REGISTERS: [Register; 8] = [
+    Register::B,
+    Register::C,
+    Register::D,
+    Register::E,
+    Register::H,
+    Register::L,
+    Register::M,
+    Register::A,
+];
+
+const CONDITIONS: [Condition; 8] = [
+    Condition::NZ,
+    Condition::Z,
+    Condition::NC,
+    Condition::C,
+    Condition::PO,
+    Condition::PE,
+    Condition::P,
+    Condition::M,
+];
+
+/// How many bytes the instruction starting with `opcode` takes.
+pub fn length(opcode: u8) -> u8 {
+    match opcode {
+        // LXI, SHLD, LHLD, STA, LDA
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2a | 0x32 | 0x3a => 3,
+        // MVI
+        _ if opcode & 0xc7 == 0x06 => 2,
+        // Jcc, JMP and its alias, Ccc, CALL and its aliases
+        _ if opcode & 0xc7 == 0xc2 || opcode & 0xc7 == 0xc4 => 3,
+        0xc3 | 0xcb | 0xcd | 0xdd | 0xed | 0xfd => 3,
+        // Immediate ALU ops, OUT, IN
+        _ if opcode & 0xc7 == 0xc6 => 2,
+        0xd3 | 0xdb => 2,
+        _ => 1,
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`.
+///
+/// Operand bytes past the end of `bytes` are taken as zero, so a truncated
+/// instruction still decodes; check `length()` against what was available.
+pub fn decode(bytes: &[u8]) -> Instruction {
+    let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+    let opcode = byte(0);
+    let data = byte(1);
+    let address = (byte(2) as u16) << 8 | byte(1) as u16;
+    let destination = REGISTERS[(opcode >> 3) as usize & 7];
+    let source = REGISTERS[opcode as usize & 7];
+    let condition = Condition::from_opcode(opcode);
+    let pair = [RegisterPair::BC, RegisterPair::DE, RegisterPair::HL, RegisterPair::SP][(opcode >> 4) as usize & 3];
+    let stack_pair = if pair == RegisterPair::SP { RegisterPair::PSW } else { pair };
+
+    let operation = match opcode {
+        0x00..=0x3f => match opcode & 0x0f {
+            0x01 => Operation::Lxi(pair, address),
+            0x09 => Operation::Dad(pair),
+            0x03 => Operation::Inx(pair),
+            0x0b => Operation::Dcx(pair),
+            0x02 if opcode < 0x20 => Operation::Stax(pair),
+            0x0a if opcode < 0x20 => Operation::Ldax(pair),
+            0x02 if opcode == 0x22 => Operation::Shld(address),
+            0x0a if opcode == 0x2a => Operation::Lhld(address),
+            0x02 => Operation::Sta(address),
+            0x0a => Operation::Lda(address),
+            _ => match opcode & 0x07 {
+                0x04 => Operation::Inr(destination),
+                0x05 => Operation::Dcr(destination),
+                0x06 => Operation::Mvi(destination, data),
+                0x07 => [
+                    Operation::Rlc,
+                    Operation::Rrc,
+                    Operation::Ral,
+                    Operation::Rar,
+                    Operation::Daa,
+                    Operation::Cma,
+                    Operation::Stc,
+                    Operation::Cmc,
+                ][(opcode >> 3) as usize],
+                // 0x00 and its undocumented aliases
+                _ => Operation::Nop,
+            },
+        },
+        0x76 => Operation::Hlt,
+        0x40..=0x7f => Operation::Mov(destination, source),
+        0x80..=0xbf => match (opcode >> 3) & 7 {
+            0 => Operation::Add(source),
+            1 => Operation::Adc(source),
+            2 => Operation::Sub(source),
+            3 => Operation::Sbb(source),
+            4 => Operation::Ana(source),
+            5 => Operation::Xra(source),
+            6 => Operation::Ora(source),
+            _ => Operation::Cmp(source),
+        },
+        _ => match opcode & 0x07 {
+            0x00 => Operation::Rcc(condition),
+            0x02 => Operation::Jcc(condition, address),
+            0x04 => Operation::Ccc(condition, address),
+            0x06 => [
+                Operation::Adi(data),
+                Operation::Aci(data),
+                Operation::Sui(data),
+                Operation::Sbi(data),
+                Operation::Ani(data),
+                Operation::Xri(data),
+                Operation::Ori(data),
+                Operation::Cpi(data),
+            ][((opcode >> 3) & 7) as usize],
+            0x07 => Operation::Rst((opcode >> 3) & 7),
+            0x01 => match opcode {
+                0xc9 | 0xd9 => Operation::Ret,
+                0xe9 => Operation::Pchl,
+                0xf9 => Operation::Sphl,
+                _ => Operation::Pop(stack_pair),
+            },
+            0x05 => match opcode {
+                0xc5 | 0xd5 | 0xe5 | 0xf5 => Operation::Push(stack_pair),
+                _ => Operation::Call(address),
+            },
+            _ => match opcode {
+                0xc3 | 0xcb => Operation::Jmp(address),
+                0xd3 => Operation::Out(data),
+                0xdb => Operation::In(data),
+                0xe3 => Operation::Xthl,
+                0xeb => Operation::Xchg,
+                0xf3 => Operation::Di,
+                _ => Operation::Ei,
+            },
+        },
+    };
+    Instruction { opcode, operation }
+}
+
+impl Register {
+    fn code(self) -> u8 {
+        REGISTERS.iter().position(|r| *r == self).unwrap() as u8
+    }
+}
+
+impl RegisterPair {
+    fn code(self) -> u8 {
+        match self {
+            RegisterPair::BC => 0,
+            RegisterPair::DE => 1,
+            RegisterPair::HL => 2,
+            RegisterPair::SP | RegisterPair::PSW => 3,
+        }
+    }
+}
+
+impl Condition {
+    /// The condition in bits 3-5 of a Jcc, Ccc or Rcc opcode.
+    pub fn from_opcode(opcode: u8) -> Condition {
+        CONDITIONS[(opcode >> 3) as usize & 7]
+    }
+
+    pub fn holds(self, cc: &Flags) -> bool {
+        match self {
+            Condition::NZ => cc.z == 0,
+            Condition::Z => cc.z == 1,
+            Condition::NC => cc.cy == 0,
+            Condition::C => cc.cy == 1,
+            Condition::PO => cc.p == 0,
+            Condition::PE => cc.p == 1,
+            Condition::P => cc.s == 0,
+            Condition::M => cc.s == 1,
+        }
+    }
+
+    fn code(self) -> u8 {
+        CONDITIONS.iter().position(|c| *c == self).unwrap() as u8
+    }
+}
+
+impl Operation {
+    /// The documented opcode for this operation.
+    pub fn opcode(&self) -> u8 {
+        match *self {
+            Operation::Nop => 0x00,
+            Operation::Lxi(rp, _) => 0x01 | rp.code() << 4,
+            Operation::Stax(rp) => 0x02 | rp.code() << 4,
+            Operation::Ldax(rp) => 0x0a | rp.code() << 4,
+            Operation::Shld(_) => 0x22,
+            Operation::Lhld(_) => 0x2a,
+            Operation::Sta(_) => 0x32,
+            Operation::Lda(_) => 0x3a,
+            Operation::Inx(rp) => 0x03 | rp.code() << 4,
+            Operation::Dcx(rp) => 0x0b | rp.code() << 4,
+            Operation::Dad(rp) => 0x09 | rp.code() << 4,
+            Operation::Inr(r) => 0x04 | r.code() << 3,
+            Operation::Dcr(r) => 0x05 | r.code() << 3,
+            Operation::Mvi(r, _) => 0x06 | r.code() << 3,
+            Operation::Rlc => 0x07,
+            Operation::Rrc => 0x0f,
+            Operation::Ral => 0x17,
+            Operation::Rar => 0x1f,
+            Operation::Daa => 0x27,
+            Operation::Cma => 0x2f,
+            Operation::Stc => 0x37,
+            Operation::Cmc => 0x3f,
+            Operation::Mov(d, s) => 0x40 | d.code() << 3 | s.code(),
+            Operation::Hlt => 0x76,
+            Operation::Add(r) => 0x80 | r.code(),
+            Operation::Adc(r) => 0x88 | r.code(),
+            Operation::Sub(r) => 0x90 | r.code(),
+            Operation::Sbb(r) => 0x98 | r.code(),
+            Operation::Ana(r) => 0xa0 | r.code(),
+            Operation::Xra(r) => 0xa8 | r.code(),
+            Operation::Ora(r) => 0xb0 | r.code(),
+            Operation::Cmp(r) => 0xb8 | r.code(),
+            Operation::Adi(_) => 0xc6,
+            Operation::Aci(_) => 0xce,
+            Operation::Sui(_) => 0xd6,
+            Operation::Sbi(_) => 0xde,
+            Operation::Ani(_) => 0xe6,
+            Operation::Xri(_) => 0xee,
+            Operation::Ori(_) => 0xf6,
+            Operation::Cpi(_) => 0xfe,
+            Operation::Jmp(_) => 0xc3,
+            Operation::Jcc(c, _) => 0xc2 | c.code() << 3,
+            Operation::Call(_) => 0xcd,
+            Operation::Ccc(c, _) => 0xc4 | c.code() << 3,
+            Operation::Ret => 0xc9,
+            Operation::Rcc(c) => 0xc0 | c.code() << 3,
+            Operation::Rst(n) => 0xc7 | (n & 7) << 3,
+            Operation::Pchl => 0xe9,
+            Operation::Push(rp) => 0xc5 | rp.code() << 4,
+            Operation::Pop(rp) => 0xc1 | rp.code() << 4,
+            Operation::Xthl => 0xe3,
+            Operation::Sphl => 0xf9,
+            Operation::Xchg => 0xeb,
+            Operation::In(_) => 0xdb,
+            Operation::Out(_) => 0xd3,
+            Operation::Ei => 0xfb,
+            Operation::Di => 0xf3,
+        }
+    }
+
+    /// The 8 or 16 bit operand that follows the opcode, if any.
+    pub fn operand(&self) -> Option<u16> {
+        match *self {
+            Operation::Mvi(_, v)
+            | Operation::Adi(v)
+            | Operation::Aci(v)
+            | Operation::Sui(v)
+            | Operation::Sbi(v)
+            | Operation::Ani(v)
+            | Operation::Xri(v)
+            | Operation::Ori(v)
+            | Operation::Cpi(v)
+            | Operation::In(v)
+            | Operation::Out(v) => Some(v as u16),
+            Operation::Lxi(_, v)
+            | Operation::Shld(v)
+            | Operation::Lhld(v)
+            | Operation::Sta(v)
+            | Operation::Lda(v)
+            | Operation::Jmp(v)
+            | Operation::Jcc(_, v)
+            | Operation::Call(v)
+            | Operation::Ccc(_, v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl Instruction {
+    /// The instruction's documented encoding.
+    pub fn new(operation: Operation) -> Instruction {
+        Instruction { opcode: operation.opcode(), operation }
+    }
+
+    pub fn length(&self) -> u8 {
+        length(self.opcode)
+    }
+
+    /// T-states taken, not counting the extra 6 of a Ccc or Rcc whose
+    /// condition holds.
+    pub fn cycles(&self) -> u8 {
+        CYCLES8080[self.opcode as usize]
+    }
+
+    /// Whether this is one of the undocumented aliases.
+    pub fn is_undocumented(&self) -> bool {
+        self.opcode != self.operation.opcode()
+    }
+
+    /// The instruction encoded back to `length()` bytes.
+    pub fn bytes(&self) -> Vec<u8> {
+        let operand = self.operation.operand().unwrap_or(0).to_le_bytes();
+        let mut bytes = vec![self.opcode];
+        bytes.extend(&operand[..self.length() as usize - 1]);
+        bytes
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for RegisterPair {
+    // Intel names a pair after its high register
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RegisterPair::BC => "B",
+            RegisterPair::DE => "D",
+            RegisterPair::HL => "H",
+            RegisterPair::SP => "SP",
+            RegisterPair::PSW => "PSW",
+        })
+    }
+}
+
+/// Intel mnemonics, with a tab between the mnemonic and the operands.
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Operation::Nop => write!(f, "NOP"),
+            Operation::Lxi(rp, v) => write!(f, "LXI\t{},#${:04x}", rp, v),
+            Operation::Stax(rp) => write!(f, "STAX\t{}", rp),
+            Operation::Ldax(rp) => write!(f, "LDAX\t{}", rp),
+            Operation::Shld(a) => write!(f, "SHLD\t${:04x}", a),
+            Operation::Lhld(a) => write!(f, "LHLD\t${:04x}", a),
+            Operation::Sta(a) => write!(f, "STA\t${:04x}", a),
+            Operation::Lda(a) => write!(f, "LDA\t${:04x}", a),
+            Operation::Inx(rp) => write!(f, "INX\t{}", rp),
+            Operation::Dcx(rp) => write!(f, "DCX\t{}", rp),
+            Operation::Dad(rp) => write!(f, "DAD\t{}", rp),
+            Operation::Inr(r) => write!(f, "INR\t{}", r),
+            Operation::Dcr(r) => write!(f, "DCR\t{}", r),
+            Operation::Mvi(r, v) => write!(f, "MVI\t{},#${:02x}", r, v),
+            Operation::Rlc => write!(f, "RLC"),
+            Operation::Rrc => write!(f, "RRC"),
+            Operation::Ral => write!(f, "RAL"),
+            Operation::Rar => write!(f, "RAR"),
+            Operation::Daa => write!(f, "DAA"),
+            Operation::Cma => write!(f, "CMA"),
+            Operation::Stc => write!(f, "STC"),
+            Operation::Cmc => write!(f, "CMC"),
+            Operation::Mov(d, s) => write!(f, "MOV\t{},{}", d, s),
+            Operation::Hlt => write!(f, "HLT"),
+            Operation::Add(r) => write!(f, "ADD\t{}", r),
+            Operation::Adc(r) => write!(f, "ADC\t{}", r),
+            Operation::Sub(r) => write!(f, "SUB\t{}", r),
+            Operation::Sbb(r) => write!(f, "SBB\t{}", r),
+            Operation::Ana(r) => write!(f, "ANA\t{}", r),
+            Operation::Xra(r) => write!(f, "XRA\t{}", r),
+            Operation::Ora(r) => write!(f, "ORA\t{}", r),
+            Operation::Cmp(r) => write!(f, "CMP\t{}", r),
+            Operation::Adi(v) => write!(f, "ADI\t#${:02x}", v),
+            Operation::Aci(v) => write!(f, "ACI\t#${:02x}", v),
+            Operation::Sui(v) => write!(f, "SUI\t#${:02x}", v),
+            Operation::Sbi(v) => write!(f, "SBI\t#${:02x}", v),
+            Operation::Ani(v) => write!(f, "ANI\t#${:02x}", v),
+            Operation::Xri(v) => write!(f, "XRI\t#${:02x}", v),
+            Operation::Ori(v) => write!(f, "ORI\t#${:02x}", v),
+            Operation::Cpi(v) => write!(f, "CPI\t#${:02x}", v),
+            Operation::Jmp(a) => write!(f, "JMP\t${:04x}", a),
+            Operation::Jcc(c, a) => write!(f, "J{}\t${:04x}", c, a),
+            Operation::Call(a) => write!(f, "CALL\t${:04x}", a),
+            Operation::Ccc(c, a) => write!(f, "C{}\t${:04x}", c, a),
+            Operation::Ret => write!(f, "RET"),
+            Operation::Rcc(c) => write!(f, "R{}", c),
+            Operation::Rst(n) => write!(f, "RST\t{}", n),
+            Operation::Pchl => write!(f, "PCHL"),
+            Operation::Push(rp) => write!(f, "PUSH\t{}", rp),
+            Operation::Pop(rp) => write!(f, "POP\t{}", rp),
+            Operation::Xthl => write!(f, "XTHL"),
+            Operation::Sphl => write!(f, "SPHL"),
+            Operation::Xchg => write!(f, "XCHG"),
+            Operation::In(p) => write!(f, "IN\t#${:02x}", p),
+            Operation::Out(p) => write!(f, "OUT\t#${:02x}", p),
+            Operation::Ei => write!(f, "EI"),
+            Operation::Di => write!(f, "DI"),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.operation, f)
+    }
+}
@@ -1,50 +1,28 @@
 use crate::invaders::Machine;
-use crate::cpu::CPUState;
-use crate::op_special_io::op_in;
 
-pub fn handle_interrupts(machine: Machine) -> Machine {
-    let opcode: u8 = machine.cpu.memory[machine.cpu.pc as usize];
-    let next_opcode: u8 = machine.cpu.memory[(machine.cpu.pc as usize) + 1];
-    match opcode {
-        0xdb => in_space_invaders(machine, next_opcode),
-        0xd3 => out_space_invaders(machine, next_opcode),
-        _ => { println!("WTF"); panic!() }
-    }
-}
-
-fn in_space_invaders(machine: Machine, port: u8) -> Machine {
+pub fn in_space_invaders(machine: &Machine, port: u8) -> u8 {
     match port {
-        0 => Machine { cpu: op_in(machine.cpu, 0xf), ..machine },
-        1 => Machine { cpu: op_in(machine.cpu, machine.in_port1), ..machine },
-        2 => Machine { cpu: op_in(machine.cpu, machine.in_port2), ..machine },
+        0 => 0xf,
+        1 => machine.in_port1,
+        2 => machine.in_port2,
         3 => {
             let v = ((machine.shift1 as u16) << 8) | (machine.shift0 as u16);
-            let value = (v >> (8-(machine.shift_offset as u16))) as u8;
-            Machine { cpu: op_in(machine.cpu, value), ..machine }
+            (v >> (8-(machine.shift_offset as u16))) as u8
         }
-        _ => Machine { cpu: op_in(machine.cpu, 0), ..machine }
+        _ => 0
     }
 }
 
-fn out_space_invaders(machine: Machine, port: u8) -> Machine {
-    let value = machine.cpu.a;
+pub fn out_space_invaders(machine: &mut Machine, port: u8, value: u8) {
     match port {
         2 => {
-            Machine { cpu: CPUState { pc: machine.cpu.pc.wrapping_add(2), cycles: 10, ..machine.cpu },
-                      shift_offset: value & 0x7,
-                      ..machine}
+            machine.shift_offset = value & 0x7;
         },
         4 => {
-            Machine {
-                shift0: machine.shift1,
-                shift1: value,
-                cpu: CPUState { pc: machine.cpu.pc.wrapping_add(2), cycles: 10, ..machine.cpu },
-                ..machine
-            }
+            machine.shift0 = machine.shift1;
+            machine.shift1 = value;
         },
-        6 => Machine { cpu: CPUState { pc: machine.cpu.pc.wrapping_add(2), cycles: 10, ..machine.cpu }, ..machine},
-        _ => {
-            Machine { cpu: CPUState { pc: machine.cpu.pc.wrapping_add(2), cycles: 10, ..machine.cpu }, ..machine}
-        }
+        // 3 and 5 drive the sound board, 6 is the watchdog
+        _ => {}
     }
 }
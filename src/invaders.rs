@@ -1,11 +1,25 @@
-use crate::cpu::CPUState;
-use crate::cpu::emulate_8080_op;
-use crate::interrupts::handle_interrupts;
-use std;
+use crate::bus::Bus;
+use crate::cpu::{CPUState, StepOutcome};
+use crate::cpu::{emulate_8080_op, step};
+use crate::error::EmulationError;
+use crate::interrupts::{in_space_invaders, out_space_invaders};
 
-#[derive(Debug, PartialEq)]
+// The board decodes only 14 address lines: 8KB of ROM followed by 8KB of RAM,
+// mirrored over the rest of the address space.
+pub const ROM_SIZE: usize = 0x2000;
+pub const INVADERS_MEMORY_SIZE: usize = 0x4000;
+// The ROM is four 2KB chips, named as in MAME, each with its address
+pub const ROM_CHIP_SIZE: usize = 0x800;
+pub const ROM_CHIPS: [(&str, u16); 4] =
+    [("invaders.h", 0x0000), ("invaders.g", 0x0800), ("invaders.f", 0x1000), ("invaders.e", 0x1800)];
+// The 8080 runs at 2MHz and the video hardware interrupts twice per 60Hz
+// frame: RST 1 when the beam reaches the middle of the screen, RST 2 at the end
+pub const CYCLES_PER_FRAME: u64 = 2_000_000 / 60;
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Machine {
     pub cpu: CPUState,
+    pub memory: Vec<u8>,
 
     //LSB of Space Invader's external shift hardware
     pub shift0: u8,
@@ -21,6 +35,7 @@ impl Machine {
     pub fn new() -> Machine {
         Machine {
             cpu: CPUState::new(),
+            memory: vec![0; INVADERS_MEMORY_SIZE],
             shift0: 0,
             shift1: 0,
             shift_offset: 0,
@@ -29,25 +44,51 @@ impl Machine {
         }
     }
 
-    pub fn load_rom(&mut self, start: usize) {
-        let x = std::include_bytes!("invaders.rom");
-        let mut i = 0;
-        if x.len() > start+0xffff {
-            panic!("PANIC: Rom size exceeds Memory!!");
-        } else {
-            while i< x.len() {
-                self.cpu.memory[start+i] = x[i];
-                i += 1;
-            }
-        }
+    /// Copies `rom`, as read by `rom::load`, to the start of memory where
+    /// the board has its ROM.
+    pub fn load_rom(&mut self, rom: &[u8]) {
+        assert!(rom.len() <= ROM_SIZE, "a {} byte ROM does not fit in {} bytes", rom.len(), ROM_SIZE);
+        self.memory[..rom.len()].copy_from_slice(rom);
+    }
+
+    /// Executes one instruction, leaving the registers untouched on error.
+    pub fn step(&mut self) -> Result<StepOutcome, EmulationError> {
+        let mut cpu = self.cpu;
+        let outcome = step(&mut cpu, self)?;
+        self.cpu = cpu;
+        Ok(outcome)
     }
+}
 
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-pub fn emulate_invaders(machine: Machine) -> Machine {
-    let opcode: u8 = machine.cpu.memory[machine.cpu.pc as usize];
-    match opcode {
-        0xdb | 0xd3 => handle_interrupts(machine),
-        _ => Machine { cpu: emulate_8080_op(machine.cpu), ..machine}
+impl Bus for Machine {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory[address as usize % INVADERS_MEMORY_SIZE]
     }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let address = address as usize % INVADERS_MEMORY_SIZE;
+        // Writes to the ROM chips go nowhere
+        if address >= ROM_SIZE {
+            self.memory[address] = value;
+        }
+    }
+
+    fn port_in(&mut self, port: u8) -> u8 {
+        in_space_invaders(self, port)
+    }
+
+    fn port_out(&mut self, port: u8, value: u8) {
+        out_space_invaders(self, port, value)
+    }
+}
+
+pub fn emulate_invaders(mut machine: Machine) -> Machine {
+    let cpu = emulate_8080_op(machine.cpu, &mut machine);
+    Machine { cpu, ..machine }
 }
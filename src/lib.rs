@@ -0,0 +1,820 @@
+//! An Intel 8080 emulator core, plus the hardware around it needed to run the
+//! 1978 Space Invaders arcade board.
+//!
+//! The crate is split in a few parts:
+//!
+//! * the CPU core: [`CPUState`] and [`step`], which execute one instruction
+//!   at a time against a [`Bus`] (memory and I/O ports) and, with the
+//!   [`Checks`] a front end turns on, report bad programs as an
+//!   [`EmulationError`] instead of panicking,
+//! * the Space Invaders [`Machine`], a [`Bus`] implementation with the ROM,
+//!   RAM, external shift register and input ports ([`emulate_invaders`]),
+//!   and a [`rom`] loader for merged images, MAME chip files and zip archives,
+//! * [`decode`], which turns bytes into a typed [`Instruction`]; [`step`] and
+//!   the [`disassemble`] function, which formats it in Intel or Zilog
+//!   [`Syntax`], both go through it,
+//! * an [`assemble`] function for Intel 8080 source, which reads the
+//!   disassembler's output back and lets tests be written as assembly,
+//! * a [`debugger`] for the machine, driven by text commands from a terminal
+//!   (`emulator8080 --debug`) or a test, which keeps a [`history`] to step
+//!   and run backwards through and a shadow [`call_stack`] for backtraces,
+//! * a GDB remote serial protocol stub, [`gdb`], so GDB and the front ends
+//!   built on it can debug the machine over TCP,
+//! * a [`profiler`] counting instructions and cycles by address and by
+//!   routine, with a text report and folded stacks for flame graphs,
+//! * [`coverage`] of the ROM, which bytes a run executed, read as data or
+//!   never touched, per chip and as an annotated disassembly listing,
+//! * a [`trace`] writer, a line per instruction with the registers and
+//!   flags in a fixed format that can be compared between runs,
+//! * symbol files ([`SymbolTable`]) naming routines and variables in
+//!   disassembly and register dumps, with one for Space Invaders built in,
+//! * [`xref`], who calls, jumps to, reads and writes each address of a
+//!   traced ROM, and its call graph in Graphviz format,
+//! * a minimal CP/M environment, [`Cpm`], to run `.COM` programs such as the
+//!   8080 CPU exercisers, also available as the `cpm8080` binary.
+//!
+//! None of it depends on SDL; the windowed front end lives in the
+//! `emulator8080` binary, built with the `sdl` feature.
+
+pub mod assembler;
+pub mod bus;
+pub mod call_stack;
+pub mod code_map;
+pub mod condition_codes;
+pub mod cpm;
+pub mod coverage;
+pub mod cpu;
+pub mod debugger;
+pub mod dissassembler;
+pub mod error;
+pub mod gdb;
+pub mod helpers;
+pub mod history;
+pub mod instruction;
+pub mod interrupts;
+pub mod profiler;
+pub mod rom;
+pub mod symbols;
+pub mod trace;
+pub mod traps;
+pub mod xref;
+pub mod invaders;
+
+mod op_arithmetic;
+mod op_branch;
+mod op_data_transfer;
+mod op_logical;
+mod op_special_io;
+mod op_stack;
+
+pub use crate::assembler::{assemble, Program};
+pub use crate::bus::{Bus, Memory};
+pub use crate::condition_codes::Flags as ConditionCodes;
+pub use crate::cpm::{Cpm, CpmError, CpmExit};
+pub use crate::cpu::{emulate_8080_op, step, CPUState, Checks, StepOutcome, CYCLES8080, MEMORY_SIZE};
+pub use crate::dissassembler::{disassemble, Syntax};
+pub use crate::error::EmulationError;
+pub use crate::helpers::{generate_interrupt, new_machine};
+pub use crate::instruction::{decode, Instruction, Operation};
+pub use crate::invaders::{emulate_invaders, Machine};
+pub use crate::symbols::SymbolTable;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::fs::File;
+
+    #[test]
+    fn test_37_410_instructions_cpu_pc() {
+        let mut cpu = CPUState::new();
+        let mut buffer = Vec::new();
+        let mut f = File::open("invaders").unwrap();
+        f.read_to_end(&mut buffer).unwrap();
+        let mut memory = Memory::new();
+        memory.load(&buffer, 0);
+        let mut n = 0;
+        while n < 37410 {
+            cpu = emulate_8080_op(cpu, &mut memory);
+            n += 1;
+        }
+        assert_eq!(cpu.pc , 0x090e)
+    }
+
+    #[test]
+    fn test_39_000_instructions_cpu_pc() {
+        let mut cpu = CPUState::new();
+        let mut buffer = Vec::new();
+        let mut f = File::open("invaders").unwrap();
+        f.read_to_end(&mut buffer).unwrap();
+        let mut memory = Memory::new();
+        memory.load(&buffer, 0);
+        let mut n = 0;
+        while n < 39000 {
+            cpu = emulate_8080_op(cpu, &mut memory);
+            n += 1;
+        }
+        assert_eq!(cpu.pc , 0x1442)
+    }
+
+    #[test]
+    fn test_37_410_instructions_cpu_sp() {
+        let mut cpu = CPUState::new();
+        let mut buffer = Vec::new();
+        let mut f = File::open("invaders").unwrap();
+        f.read_to_end(&mut buffer).unwrap();
+        let mut memory = Memory::new();
+        memory.load(&buffer, 0);
+        let mut n = 0;
+        while n < 37410 {
+            cpu = emulate_8080_op(cpu, &mut memory);
+            n += 1;
+        }
+        assert_eq!(cpu.sp , 0x23f8)
+    }
+
+    #[test]
+    fn test_cycles_over_sequence() {
+        let mut cpu = CPUState::new();
+        let mut memory = Memory::new();
+        memory.load(&[
+            0x00,             // NOP            4
+            0x31, 0x00, 0x24, // LXI SP, $2400 10
+            0x3e, 0x00,       // MVI A, $00     7
+            0xb7,             // ORA A          4
+            0xcd, 0x20, 0x00, // CALL $0020    17
+            0xc4, 0x20, 0x00, // CNZ $0020     11 (not taken)
+            0xcc, 0x28, 0x00, // CZ $0028      17 (taken)
+            0xc3, 0x30, 0x00, // JMP $0030     10
+        ], 0);
+        memory.load(&[0xc9], 0x20);       // RET  10
+        memory.load(&[0xc0, 0xc8], 0x28); // RNZ   5 (not taken), RZ 11 (taken)
+        memory.load(&[0x76], 0x30);       // HLT   7
+        let mut total: u32 = 0;
+        let mut steps = 0;
+        while !cpu.halted {
+            step(&mut cpu, &mut memory).unwrap();
+            total += cpu.cycles as u32;
+            steps += 1;
+        }
+        assert_eq!(steps, 12);
+        assert_eq!(total, 113);
+        assert_eq!(cpu.total_cycles, 113);
+
+        // Idling while halted still moves the clock
+        step(&mut cpu, &mut memory).unwrap();
+        assert_eq!(cpu.total_cycles, 117);
+    }
+
+    fn run_until_hlt(program: &[u8]) -> CPUState {
+        let mut cpu = CPUState::new();
+        let mut memory = Memory::new();
+        memory.load(program, 0);
+        cpu.sp = 0x2400;
+        while !cpu.halted {
+            step(&mut cpu, &mut memory).unwrap();
+        }
+        cpu
+    }
+
+    // Assembles `source` and runs it from its origin until HLT
+    fn run_source(source: &str) -> (CPUState, Memory) {
+        let program = assemble(source).unwrap();
+        let mut cpu = CPUState::new();
+        let mut memory = Memory::new();
+        memory.load(&program.bytes, program.origin as usize);
+        cpu.pc = program.origin;
+        cpu.sp = 0x2400;
+        while !cpu.halted {
+            step(&mut cpu, &mut memory).unwrap();
+        }
+        (cpu, memory)
+    }
+
+    #[test]
+    fn test_auxiliary_carry() {
+        // ADD: carry out of bit 3
+        assert_eq!(run_until_hlt(&[0x3e, 0x0f, 0x06, 0x01, 0x80, 0x76]).cc.ac, 1);
+        assert_eq!(run_until_hlt(&[0x3e, 0x10, 0x06, 0x10, 0x80, 0x76]).cc.ac, 0);
+        // SUB/CMP: carry out of bit 3 of A + !B + 1, set when there is no half borrow
+        assert_eq!(run_until_hlt(&[0x3e, 0x10, 0x06, 0x01, 0x90, 0x76]).cc.ac, 0);
+        assert_eq!(run_until_hlt(&[0x3e, 0x11, 0x06, 0x01, 0x90, 0x76]).cc.ac, 1);
+        assert_eq!(run_until_hlt(&[0x3e, 0x11, 0x06, 0x01, 0xb8, 0x76]).cc.ac, 1);
+        // INR/DCR
+        assert_eq!(run_until_hlt(&[0x06, 0x0f, 0x04, 0x76]).cc.ac, 1);
+        assert_eq!(run_until_hlt(&[0x06, 0x10, 0x05, 0x76]).cc.ac, 0);
+        assert_eq!(run_until_hlt(&[0x06, 0x11, 0x05, 0x76]).cc.ac, 1);
+        // ANA: bit 3 of A | B
+        assert_eq!(run_until_hlt(&[0x3e, 0x08, 0x06, 0x00, 0xa0, 0x76]).cc.ac, 1);
+        assert_eq!(run_until_hlt(&[0x3e, 0x07, 0x06, 0x00, 0xa0, 0x76]).cc.ac, 0);
+        // XRA/ORA clear it
+        assert_eq!(run_until_hlt(&[0x3e, 0x0f, 0x06, 0x01, 0x80, 0xb0, 0x76]).cc.ac, 0);
+    }
+
+    #[test]
+    fn test_daa_bcd_addition() {
+        // 09 + 01 = 10
+        let cpu = run_until_hlt(&[0x3e, 0x09, 0xc6, 0x01, 0x27, 0x76]);
+        assert_eq!((cpu.a, cpu.cc.cy), (0x10, 0));
+        // 38 + 29 = 67, the low digit carries through AC
+        let cpu = run_until_hlt(&[0x3e, 0x38, 0xc6, 0x29, 0x27, 0x76]);
+        assert_eq!((cpu.a, cpu.cc.cy), (0x67, 0));
+        // 99 + 01 = 100
+        let cpu = run_until_hlt(&[0x3e, 0x99, 0xc6, 0x01, 0x27, 0x76]);
+        assert_eq!((cpu.a, cpu.cc.cy, cpu.cc.z), (0x00, 1, 1));
+    }
+
+    // The first ADD, SUB, SBB, CMP, ANA, XRA, ORA and DAA rows are the worked
+    // examples in Intel's 8080/8085 Assembly Language Programming Manual. The
+    // rest were worked out by hand from the rules it gives, ANA setting AC to
+    // bit 3 of A | B among them, plus the one it leaves out: SUB, SBB, CMP and
+    // DCR add the complement, so AC is the carry out of bit 3 of that sum.
+    // 8080EXM, whose checksums were taken on a real 8080, covers them all
+    // when it is in tests/roms.
+    const FLAG_VECTORS: [(u8, u8, u8, u8, u8, u8, u8); 37] = [
+        // opcode, A, B, CY, AC before, then A and PSW after
+        (0x80, 0x6c, 0x2e, 0, 0, 0x9a, 0x96), // ADD B
+        (0x80, 0x0f, 0x01, 0, 0, 0x10, 0x12),
+        (0x80, 0xff, 0x01, 0, 0, 0x00, 0x57),
+        (0x80, 0x80, 0x80, 0, 0, 0x00, 0x47),
+        (0x88, 0x56, 0xbe, 0, 0, 0x14, 0x17), // ADC B
+        (0x88, 0x14, 0x42, 1, 0, 0x57, 0x02),
+        (0x88, 0x0f, 0x00, 1, 0, 0x10, 0x12),
+        (0x88, 0xff, 0xff, 1, 0, 0xff, 0x97),
+        (0x90, 0x3e, 0x3e, 0, 0, 0x00, 0x56), // SUB B
+        (0x90, 0x10, 0x01, 0, 0, 0x0f, 0x06),
+        (0x90, 0x00, 0x01, 0, 0, 0xff, 0x87),
+        (0x90, 0x80, 0x01, 0, 0, 0x7f, 0x02),
+        (0x98, 0x04, 0x02, 1, 0, 0x01, 0x12), // SBB B
+        (0x98, 0x00, 0x01, 1, 0, 0xfe, 0x83),
+        (0x98, 0x10, 0x00, 1, 0, 0x0f, 0x06),
+        (0x98, 0x00, 0x00, 1, 0, 0xff, 0x87),
+        (0xb8, 0x0a, 0x05, 0, 0, 0x0a, 0x16), // CMP B
+        (0xb8, 0x02, 0x05, 0, 0, 0x02, 0x83),
+        (0xb8, 0xe5, 0x05, 0, 0, 0xe5, 0x92),
+        (0xb8, 0x11, 0x01, 0, 0, 0x11, 0x12),
+        (0xa0, 0xfc, 0x0f, 0, 0, 0x0c, 0x16), // ANA B
+        (0xa0, 0x07, 0x70, 1, 0, 0x00, 0x46),
+        (0xa0, 0xf0, 0x08, 0, 0, 0x00, 0x56),
+        (0xa8, 0x5c, 0x78, 1, 1, 0x24, 0x06), // XRA B
+        (0xb0, 0x33, 0x0f, 1, 1, 0x3f, 0x06), // ORA B
+        (0x3c, 0x0f, 0x00, 1, 0, 0x10, 0x13), // INR A
+        (0x3c, 0xff, 0x00, 0, 0, 0x00, 0x56),
+        (0x3c, 0x7f, 0x00, 0, 0, 0x80, 0x92),
+        (0x3d, 0x10, 0x00, 0, 0, 0x0f, 0x06), // DCR A
+        (0x3d, 0x00, 0x00, 1, 0, 0xff, 0x87),
+        (0x3d, 0x01, 0x00, 0, 0, 0x00, 0x56),
+        (0x27, 0x9b, 0x00, 0, 0, 0x01, 0x13), // DAA
+        (0x27, 0x0a, 0x00, 0, 0, 0x10, 0x12),
+        (0x27, 0x00, 0x00, 0, 1, 0x06, 0x06),
+        (0x27, 0x99, 0x00, 1, 0, 0xf9, 0x87),
+        (0x27, 0x9a, 0x00, 0, 0, 0x00, 0x57),
+        (0x27, 0x66, 0x00, 1, 1, 0xcc, 0x87),
+    ];
+
+    #[test]
+    fn test_flag_vectors() {
+        for &(opcode, a, b, cy, ac, answer, psw) in FLAG_VECTORS.iter() {
+            let mut memory = Memory::new();
+            memory.load(&[opcode], 0);
+            let cc = ConditionCodes { z: 0, s: 0, p: 0, cy, ac };
+            let mut cpu = CPUState { a, b, cc, ..CPUState::new() };
+            step(&mut cpu, &mut memory).unwrap();
+            assert_eq!((cpu.a, cpu.cc.psw()), (answer, psw), "{:02x} on A={:02x} B={:02x} CY={} AC={}", opcode, a, b, cy, ac);
+        }
+    }
+
+    #[test]
+    fn test_daa_every_bcd_sum() {
+        // ADC then DAA adds two BCD bytes, whatever the digits and carry in
+        let bcd = |n: u32| (((n / 10) << 4) | (n % 10)) as u8;
+        for x in 0..100 {
+            for y in 0..100 {
+                for carry in 0..2 {
+                    let mut memory = Memory::new();
+                    memory.load(&[0x88, 0x27], 0);
+                    let cc = ConditionCodes { z: 0, s: 0, p: 0, cy: carry as u8, ac: 0 };
+                    let mut cpu = CPUState { a: bcd(x), b: bcd(y), cc, ..CPUState::new() };
+                    step(&mut cpu, &mut memory).unwrap();
+                    step(&mut cpu, &mut memory).unwrap();
+                    let sum = x + y + carry;
+                    assert_eq!((cpu.a, cpu.cc.cy), (bcd(sum % 100), (sum >= 100) as u8), "{} + {} + {}", x, y, carry);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_opcode_steps() {
+        for opcode in 0..=0xff {
+            let mut cpu = CPUState::new();
+            let mut memory = Memory::new();
+            memory.load(&[opcode, 0x00, 0x30], 0x100);
+            cpu.pc = 0x100;
+            cpu.sp = 0x2400;
+            assert!(step(&mut cpu, &mut memory).is_ok(), "opcode 0x{:02x}", opcode);
+            // With all flags clear only the NZ, NC, PO and P conditions hold
+            let conditional = opcode & 0xc7 == 0xc4 || opcode & 0xc7 == 0xc0;
+            let taken = conditional && cpu::condition(&ConditionCodes::new(), opcode);
+            let expected = CYCLES8080[opcode as usize] + if taken { cpu::CONDITIONAL_TAKEN_CYCLES } else { 0 };
+            assert_eq!(cpu.cycles, expected, "opcode 0x{:02x}", opcode);
+
+            // At the top of memory the operands and the next PC wrap to 0
+            let mut cpu = CPUState::new();
+            let mut memory = Memory::new();
+            memory.bytes[0xffff] = opcode;
+            memory.load(&[0x00, 0x30], 0);
+            cpu.pc = 0xffff;
+            cpu.sp = 0x2400;
+            assert!(step(&mut cpu, &mut memory).is_ok(), "opcode 0x{:02x} at ffff", opcode);
+        }
+    }
+
+    #[test]
+    fn test_carry_in_arithmetic() {
+        // STC; MVI A, $ff; ACI $00 -> 00 with carry out
+        let cpu = run_until_hlt(&[0x37, 0x3e, 0xff, 0xce, 0x00, 0x76]);
+        assert_eq!((cpu.a, cpu.cc.cy, cpu.cc.z), (0x00, 1, 1));
+        // STC; MVI A, $00; MVI B, $ff; SBB B -> 00 with borrow out
+        let cpu = run_until_hlt(&[0x37, 0x3e, 0x00, 0x06, 0xff, 0x98, 0x76]);
+        assert_eq!((cpu.a, cpu.cc.cy, cpu.cc.z), (0x00, 1, 1));
+        // STC; MVI A, $10; SBI $05 -> 0a
+        let cpu = run_until_hlt(&[0x37, 0x3e, 0x10, 0xde, 0x05, 0x76]);
+        assert_eq!((cpu.a, cpu.cc.cy), (0x0a, 0));
+    }
+
+    #[test]
+    fn test_rotates_and_carry() {
+        // (carry in, A, opcode) -> (A, carry out) for RLC, RRC, RAL and RAR
+        let cases = [
+            (0, 0x81, 0x07, 0x03, 1),
+            (1, 0x40, 0x07, 0x80, 0),
+            (0, 0x81, 0x0f, 0xc0, 1),
+            (1, 0x02, 0x0f, 0x01, 0),
+            (1, 0x80, 0x17, 0x01, 1),
+            (0, 0x40, 0x17, 0x80, 0),
+            (1, 0x01, 0x1f, 0x80, 1),
+            (0, 0x02, 0x1f, 0x01, 0),
+        ];
+        for &(carry, a, opcode, answer, carry_out) in cases.iter() {
+            // STC; CMC when no carry in; MVI A; the rotate; HLT
+            let cmc = if carry == 1 { 0x00 } else { 0x3f };
+            let cpu = run_until_hlt(&[0x37, cmc, 0x3e, a, opcode, 0x76]);
+            assert_eq!((cpu.a, cpu.cc.cy), (answer, carry_out), "{:02x} on {:02x}", opcode, a);
+        }
+        // STC; CMC; CMC
+        assert_eq!(run_until_hlt(&[0x37, 0x3f, 0x76]).cc.cy, 0);
+        assert_eq!(run_until_hlt(&[0x37, 0x3f, 0x3f, 0x76]).cc.cy, 1);
+    }
+
+    #[test]
+    fn test_rst_returns_past_itself() {
+        // RST n at 0x0100 pushes 0x0101 and jumps to 8 * n
+        for n in 0..8u8 {
+            let mut memory = Memory::new();
+            memory.load(&[0xc7 | n << 3], 0x100);
+            let mut cpu = CPUState { pc: 0x100, sp: 0x2400, ..CPUState::new() };
+            step(&mut cpu, &mut memory).unwrap();
+            assert_eq!((cpu.pc, cpu.sp), (8 * n as u16, 0x23fe));
+            assert_eq!((memory.read(0x23fe), memory.read(0x23ff)), (0x01, 0x01));
+        }
+        // RST 1 at 0x0010 comes back to 0x0011
+        let mut memory = Memory::new();
+        memory.load(&[0xc9], 0x08);
+        memory.load(&[0xcf, 0x76], 0x10);
+        let mut cpu = CPUState::new();
+        cpu.pc = 0x10;
+        cpu.sp = 0x2400;
+        while !cpu.halted {
+            step(&mut cpu, &mut memory).unwrap();
+        }
+        assert_eq!(cpu.pc, 0x12);
+    }
+
+    #[test]
+    fn test_undocumented_aliases() {
+        // 0xcb JMP $0010, 0xfd CALL $0020, 0xd9 RET
+        let mut memory = Memory::new();
+        memory.load(&[0xcb, 0x10, 0x00], 0);
+        memory.load(&[0xfd, 0x20, 0x00, 0x76], 0x10);
+        memory.load(&[0xd9], 0x20);
+        let mut cpu = CPUState::new();
+        cpu.sp = 0x2400;
+        while !cpu.halted {
+            step(&mut cpu, &mut memory).unwrap();
+        }
+        assert_eq!((cpu.pc, cpu.sp), (0x14, 0x2400));
+    }
+
+    #[test]
+    fn test_decode_round_trips() {
+        let undocumented = [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd];
+        for opcode in 0..=0xff {
+            let bytes = [opcode, 0x34, 0x12];
+            let instruction = decode(&bytes);
+            let length = instruction.length() as usize;
+            assert_eq!(instruction.bytes(), &bytes[..length], "opcode 0x{:02x}", opcode);
+            assert_eq!(instruction.cycles(), CYCLES8080[opcode as usize]);
+            assert_eq!(instruction.is_undocumented(), undocumented.contains(&opcode), "opcode 0x{:02x}", opcode);
+            let documented = Instruction::new(instruction.operation);
+            assert_eq!(decode(&documented.bytes()).operation, instruction.operation);
+        }
+    }
+
+    #[test]
+    fn test_decode_operands() {
+        use crate::instruction::{Condition, Register, RegisterPair};
+        assert_eq!(decode(&[0x21, 0x00, 0x24]).operation, Operation::Lxi(RegisterPair::HL, 0x2400));
+        assert_eq!(decode(&[0x77]).operation, Operation::Mov(Register::M, Register::A));
+        assert_eq!(decode(&[0xf5]).operation, Operation::Push(RegisterPair::PSW));
+        assert_eq!(decode(&[0xfa, 0x10, 0x00]).operation, Operation::Jcc(Condition::M, 0x0010));
+        assert_eq!(decode(&[0xcb, 0x10, 0x00]).operation, Operation::Jmp(0x0010));
+        assert_eq!(decode(&[0xdb, 0x01]).to_string(), "IN\t#$01");
+        assert_eq!(decode(&[0x3e]).length(), 2);
+    }
+
+    #[test]
+    fn test_disassembly_syntaxes() {
+        use crate::dissassembler::{disassemble_with, write_listing, Options};
+        let program = [0x21, 0x00, 0x24, 0x7e, 0xc2, 0x10, 0xa0, 0xdf];
+        let zilog = Options { syntax: Syntax::Zilog, ..Options::default() };
+        let hex = Options { hex_dump: true, ..Options::default() };
+
+        assert_eq!(disassemble(&program, 0x100).to_string(), "0100\tLXI\tH,#$2400");
+        assert_eq!(disassemble_with(&program, 0x100, zilog).to_string(), "0100\tLD\tHL,2400h");
+        assert_eq!(disassemble_with(&program, 0x100, hex).to_string(), "0100\t21 00 24\tLXI\tH,#$2400");
+        assert_eq!(disassemble_with(&program[3..], 0x103, zilog).to_string(), "0103\tLD\tA,(HL)");
+        assert_eq!(disassemble_with(&program[4..], 0x104, zilog).to_string(), "0104\tJP\tNZ,0A010h");
+        assert_eq!(disassemble_with(&program[7..], 0x107, zilog).to_string(), "0107\tRST\t18h");
+
+        let mut listing = Vec::new();
+        write_listing(&mut listing, &program, 0x100, Options::default()).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "0100\tLXI\tH,#$2400\n0103\tMOV\tA,M\n0104\tJNZ\t$a010\n0107\tRST\t3\n"
+        );
+    }
+
+    #[test]
+    fn test_code_map_separates_data() {
+        use crate::code_map::CodeMap;
+        let program = [
+            0xc3, 0x06, 0x01, // JMP $0106
+            b'H', b'I', b'$', // data
+            0x21, 0x03, 0x01, // LXI H,$0103
+            0xcd, 0x0e, 0x01, // CALL $010e
+            0xc9, // RET
+            0xff, // never reached
+            0xd9, // RET, undocumented
+        ];
+        let map = CodeMap::trace(&program, 0x100, &[0x100]);
+        assert!(map.is_code(0x100) && map.is_code(0x102) && !map.is_code(0x103) && !map.is_code(0x10d));
+        assert!(map.is_code(0x10e));
+
+        let mut listing = Vec::new();
+        map.write_listing(&mut listing, &program).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "\tORG\t0100H\n\
+             L_0100:\n\tJMP\tL_0106\t; 0100\n\
+             L_0103:\n\tDB\t48H,49H,24H\t; 0103\n\
+             L_0106:\n\tLXI\tH,L_0103\t; 0106\n\
+             \tCALL\tL_010e\t; 0109\n\
+             \tRET\t; 010c\n\
+             \tDB\t0FFH\t; 010d\n\
+             L_010e:\n\tDB\t0D9H\t; 010e\tRET\n\
+             \tEND\n"
+        );
+    }
+
+    #[test]
+    fn test_code_map_of_invaders() {
+        use crate::code_map::{CodeMap, INVADERS_ENTRY_POINTS};
+        let rom = std::fs::read("invaders").unwrap();
+        let map = CodeMap::trace(&rom, 0, &INVADERS_ENTRY_POINTS);
+        // The interrupt handlers, and the reset jump into the init code
+        assert!(map.is_code(0x0008) && map.is_code(0x0010) && map.is_code(0x18d4));
+        // The character set near the end of the ROM is data
+        assert!(!map.is_code(0x1e00));
+    }
+
+    #[test]
+    fn test_assembler_directives() {
+        let program = assemble(
+            "SIZE\tEQU\tEND_ - START ; defined after use\n\
+             \tORG\t100H\n\
+             START:\tLXI\tH,TABLE\n\
+             \tMVI\tB,SIZE\n\
+             \tJMP\t$\n\
+             TABLE:\tDB\t'Hi',0,-1,'A'+1\n\
+             \tDW\tSTART,HIGH TABLE\n\
+             \tDS\t2\n\
+             END_:\tDB\t(1 << 4) | 0FH, 1010B, 0x7f, $12\n\
+             \tEND\n\
+             \tDB\t1 ; ignored\n",
+        )
+        .unwrap();
+        assert_eq!(program.origin, 0x100);
+        assert_eq!(
+            program.bytes,
+            vec![
+                0x21, 0x08, 0x01, 0x06, 0x13, 0xc3, 0x05, 0x01, b'H', b'i', 0x00, 0xff, b'B', 0x00, 0x01,
+                0x01, 0x00, 0x00, 0x00, 0x1f, 0x0a, 0x7f, 0x12
+            ]
+        );
+        assert_eq!(program.labels["TABLE"], 0x108);
+        assert_eq!(program.symbol_table().name(0x100), Some("START"));
+    }
+
+    #[test]
+    fn test_assembler_errors() {
+        let line = |source: &str| assemble(source).unwrap_err().line;
+        assert_eq!(line("\tNOP\n\tFOO\n"), 2);
+        assert_eq!(line("\tJMP\tNOWHERE\n"), 1);
+        assert_eq!(line("A:\tNOP\nA:\tNOP\n"), 2);
+        assert_eq!(line("\tMVI\tA,100H\n"), 1);
+        assert_eq!(line("\tMOV\tA\n"), 1);
+        assert_eq!(line("\tPUSH\tSP\n"), 1);
+        assert_eq!(line("\tNOP\n\tDB\t'oops\n"), 2);
+        assert_eq!(line("\t\u{c9}I\n"), 1);
+        assert_eq!(line("\tMVI\tA,0FF80H\n"), 1);
+        assert_eq!(line("\tMVI\tA,-129\n"), 1);
+        assert_eq!(line("\tDB\t1,~0\n"), 1);
+        assert_eq!(assemble("\tMVI\tA,-128\n\tDB\t-1,255\n").unwrap().bytes, [0x3e, 0x80, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_assembler_reads_disassembly() {
+        for opcode in 0..=0xff {
+            let bytes = [opcode, 0x34, 0x12];
+            let instruction = decode(&bytes);
+            if instruction.is_undocumented() {
+                continue;
+            }
+            let source = format!("\t{}\n", disassemble(&bytes, 0).instruction.operation);
+            let program = assemble(&source).unwrap();
+            assert_eq!(program.bytes, instruction.bytes(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_assembler_reads_invaders_listing() {
+        use crate::code_map::{CodeMap, INVADERS_ENTRY_POINTS};
+        let rom = std::fs::read("invaders").unwrap();
+        let map = CodeMap::trace_with_symbols(&rom, 0, &INVADERS_ENTRY_POINTS, SymbolTable::invaders());
+        let mut listing = Vec::new();
+        map.write_listing(&mut listing, &rom).unwrap();
+        let program = assemble(std::str::from_utf8(&listing).unwrap()).unwrap();
+        assert_eq!((program.origin, program.bytes), (0, rom));
+        assert_eq!(program.labels["ClearScreen"], 0x1a5c);
+    }
+
+    #[test]
+    fn test_data_transfer_source() {
+        let (cpu, mut memory) = run_source(
+            "\tLXI\tH,1234H\n\
+             \tSHLD\tVALUE\n\
+             \tXCHG\n\
+             \tLHLD\tVALUE\n\
+             \tMVI\tM,0AAH\n\
+             \tMOV\tA,M\n\
+             \tHLT\n\
+             VALUE:\tDW\t0\n",
+        );
+        assert_eq!((cpu.a, cpu.h, cpu.l, cpu.d, cpu.e), (0xaa, 0x12, 0x34, 0x12, 0x34));
+        assert_eq!(memory.read(0x1234), 0xaa);
+    }
+
+    #[test]
+    fn test_branch_and_stack_source() {
+        let (cpu, _) = run_source(
+            "\tORG\t100H\n\
+             \tMVI\tB,5\n\
+             \tXRA\tA\n\
+             LOOP:\tCALL\tADD3\n\
+             \tDCR\tB\n\
+             \tJNZ\tLOOP\n\
+             \tHLT\n\
+             ADD3:\tPUSH\tB\n\
+             \tADI\t3\n\
+             \tPOP\tB\n\
+             \tRET\n",
+        );
+        assert_eq!((cpu.a, cpu.b, cpu.sp), (15, 0, 0x2400));
+    }
+
+    #[test]
+    fn test_arithmetic_source() {
+        let (cpu, _) = run_source(
+            "\tLXI\tH,1000H\n\
+             \tLXI\tD,2345H\n\
+             \tDAD\tD\n\
+             \tINX\tH\n\
+             \tDCX\tD\n\
+             \tMVI\tA,38H\n\
+             \tADI\t29H\n\
+             \tDAA\n\
+             \tMOV\tB,A\n\
+             \tMVI\tA,-1\n\
+             \tADD\tB\n\
+             \tACI\t0\n\
+             \tSUI\t7\n\
+             \tSBI\t-16\n\
+             \tINR\tC\n\
+             \tDCR\tB\n\
+             \tHLT\n",
+        );
+        assert_eq!((cpu.h, cpu.l, cpu.d, cpu.e), (0x33, 0x46, 0x23, 0x44));
+        // 38 + 29 = 67 in BCD, then 67 - 1 + 1 - 7 - 0F0H
+        assert_eq!((cpu.a, cpu.cc.cy, cpu.b, cpu.c), (0x70, 1, 0x66, 1));
+    }
+
+    #[test]
+    fn test_io_source() {
+        // The invaders shift register: two bytes in through port 4, an
+        // offset through port 2, the shifted byte back from port 3
+        let program = assemble(
+            "\tLXI\tSP,2400H\n\
+             \tDI\n\
+             \tMVI\tA,0AAH\n\
+             \tOUT\t4\n\
+             \tMVI\tA,0FFH\n\
+             \tOUT\t4\n\
+             \tMVI\tA,4\n\
+             \tOUT\t2\n\
+             \tIN\t3\n\
+             \tEI\n\
+             \tHLT\n",
+        )
+        .unwrap();
+        let mut machine = Machine::new();
+        machine.memory[..program.bytes.len()].copy_from_slice(&program.bytes);
+        while !machine.cpu.halted {
+            machine.step().unwrap();
+        }
+        assert_eq!((machine.cpu.a, machine.cpu.int_enable), (0xfa, true));
+        assert_eq!((machine.shift0, machine.shift1, machine.shift_offset), (0xaa, 0xff, 4));
+    }
+
+    #[test]
+    fn test_logical_source() {
+        let (cpu, _) = run_source("\tMVI\tA,0F0H\n\tANI\t3CH\n\tORI\t1\n\tCMA\n\tCPI\t0CEH\n\tHLT\n");
+        assert_eq!((cpu.a, cpu.cc.z), (0xce, 1));
+    }
+
+    #[test]
+    fn test_cross_reference() {
+        use crate::code_map::CodeMap;
+        use crate::xref::{CrossReference, Reference, ReferenceKind};
+        let program = assemble(
+            "\tORG\t100H\n\
+             START:\tCALL\tCLEAR\n\
+             \tLDA\tCOUNT\n\
+             \tJMP\tSTART\n\
+             CLEAR:\tLXI\tH,2400H\n\
+             LOOP:\tMVI\tM,0\n\
+             \tINX\tH\n\
+             \tLXI\tD,TABLE\n\
+             \tJMP\tDONE\n\
+             DONE:\tSTA\tCOUNT\n\
+             \tRET\n\
+             TABLE:\tDB\t1,2\n\
+             COUNT\tEQU\t2000H\n",
+        )
+        .unwrap();
+        let map = CodeMap::trace(&program.bytes, program.origin, &[program.origin]);
+        let xref = CrossReference::new(&map);
+        let (start, clear, table) = (0x100, program.labels["CLEAR"], program.labels["TABLE"]);
+        let references = |address: u16| xref.references[&address].iter().copied().collect::<Vec<_>>();
+
+        assert_eq!(references(clear), vec![Reference { from: 0x100, kind: ReferenceKind::Call }]);
+        assert_eq!(
+            references(0x2000),
+            vec![
+                Reference { from: 0x103, kind: ReferenceKind::Read },
+                Reference { from: program.labels["DONE"], kind: ReferenceKind::Write }
+            ]
+        );
+        // Written through HL, which was loaded with an LXI
+        assert_eq!(references(0x2400), vec![Reference { from: program.labels["LOOP"], kind: ReferenceKind::Write }]);
+        assert_eq!(references(table)[0].kind, ReferenceKind::Address);
+        assert!(xref.routines[&clear].contains(&program.labels["DONE"]));
+        assert_eq!(xref.calls.iter().copied().collect::<Vec<_>>(), vec![(start, clear)]);
+
+        let mut dot = Vec::new();
+        xref.write_dot(&mut dot).unwrap();
+        assert!(String::from_utf8(dot).unwrap().contains("\t\"L_0100\" -> \"L_0109\";\n"));
+    }
+
+    #[test]
+    fn test_symbol_file() {
+        use crate::symbols::SymbolKind;
+        let symbols = SymbolTable::parse("# Comment\n\n0100 code Start ; Entry point\n2400 data Screen\n").unwrap();
+        let start = symbols.get(0x100).unwrap();
+        assert_eq!((start.name.as_str(), start.kind, start.comment.as_deref()), ("Start", SymbolKind::Code, Some("Entry point")));
+        assert_eq!(symbols.lookup("Screen"), Some(0x2400));
+        assert_eq!(symbols.describe(0x103).as_deref(), Some("Start+3"));
+        // Offsets are only taken from code symbols
+        assert_eq!(symbols.describe(0x2401), None);
+
+        assert_eq!(SymbolTable::parse("0100 code\n").unwrap_err().line, 1);
+        assert_eq!(SymbolTable::parse("0100 code A\n0200 both B\n").unwrap_err().line, 2);
+        assert_eq!(SymbolTable::parse("0100 code A\n0200 code A\n").unwrap_err().line, 2);
+        assert!(!SymbolTable::invaders().is_empty());
+    }
+
+    #[test]
+    fn test_symbols_in_output() {
+        use crate::code_map::CodeMap;
+        use crate::dissassembler::{disassemble_with, Options};
+        let symbols = SymbolTable::parse("0100 code Start\n0106 code Loop ; Spins\n2400 data Screen\n").unwrap();
+        let program = [0x21, 0x00, 0x24, 0xc3, 0x06, 0x01, 0xc3, 0x06, 0x01];
+        let options = Options { symbols: Some(&symbols), ..Options::default() };
+        assert_eq!(disassemble_with(&program, 0x100, options).to_string(), "0100\tLXI\tH,Screen");
+        assert_eq!(disassemble_with(&program[3..], 0x103, options).to_string(), "0103\tJMP\tLoop");
+
+        let mut listing = Vec::new();
+        CodeMap::trace_with_symbols(&program, 0x100, &[], symbols.clone()).write_listing(&mut listing, &program).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            "Screen\tEQU\t2400H\n\tORG\t0100H\n\
+             Start:\n\tLXI\tH,Screen\t; 0100\n\tJMP\tLoop\t; 0103\n\
+             Loop:\t\t; Spins\n\tJMP\tLoop\t; 0106\n\tEND\n"
+        );
+
+        let cpu = CPUState { pc: 0x0108, h: 0x24, l: 0x00, ..CPUState::new() };
+        let dump = cpu.with_symbols(&symbols).to_string();
+        assert!(dump.contains("PC: 0108 (Loop+2)") && dump.contains("HL: 2400 (Screen)"), "{}", dump);
+        assert!(cpu.to_string().contains("PC: 0108,"));
+    }
+
+    #[test]
+    fn test_step_stack_wraps() {
+        let mut cpu = CPUState::new();
+        let mut memory = Memory::new();
+        // LXI SP,0; LXI B,1234H; PUSH B; POP D puts the stack at the top of memory
+        memory.load(&[0x31, 0x00, 0x00, 0x01, 0x34, 0x12, 0xc5, 0xd1], 0);
+        for _ in 0..3 {
+            assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Executed));
+        }
+        assert_eq!(cpu.sp, 0xfffe);
+        assert_eq!((memory.read(0xfffe), memory.read(0xffff)), (0x34, 0x12));
+        assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Executed));
+        assert_eq!((cpu.sp, cpu.d, cpu.e), (0x0000, 0x12, 0x34));
+    }
+
+    #[test]
+    fn test_step_checks() {
+        // 0xcb is an undocumented JMP
+        let mut cpu = CPUState::new();
+        let mut memory = Memory::new();
+        memory.load(&[0xcb, 0x00, 0x10], 0);
+        cpu.checks.documented_only = true;
+        let before = cpu;
+        assert_eq!(step(&mut cpu, &mut memory), Err(EmulationError::IllegalOpcode { pc: 0, opcode: 0xcb }));
+        assert_eq!(cpu, before);
+
+        // LXI B,1234H; PUSH B; PUSH B; POP D; POP D; POP D with room for one push
+        let mut cpu = CPUState::new();
+        let mut memory = Memory::new();
+        memory.load(&[0x01, 0x34, 0x12, 0xc5, 0xc5, 0xd1, 0xd1, 0xd1], 0);
+        cpu.sp = 0x2400;
+        cpu.checks.stack = Some((0x23fe, 0x2400));
+        assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Executed));
+        assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Executed));
+        let before = cpu;
+        assert_eq!(step(&mut cpu, &mut memory), Err(EmulationError::StackOverflow { pc: 4, sp: 0x23fe }));
+        assert_eq!((cpu, memory.read(0x23fd)), (before, 0));
+        cpu.pc = 5;
+        assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Executed));
+        assert_eq!(step(&mut cpu, &mut memory), Err(EmulationError::StackUnderflow { pc: 6, sp: 0x2400 }));
+
+        // A conditional return that is not taken does not pop: RZ with Z clear
+        memory.load(&[0xc8], 6);
+        assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Executed));
+    }
+
+    #[test]
+    fn test_step_hlt() {
+        let mut cpu = CPUState::new();
+        let mut memory = Memory::new();
+        memory.load(&[0x76], 0);
+        cpu.sp = 0x2400;
+        assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Halted));
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 1);
+
+        // Halted, the CPU idles without fetching
+        assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Halted));
+        assert_eq!((cpu.pc, cpu.cycles), (1, 4));
+
+        // An interrupt wakes it and returns past the HLT
+        cpu = generate_interrupt(cpu, &mut memory, 1);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.total_cycles, 7 + 4 + 11);
+        assert_eq!(cpu.pc, 0x08);
+        assert_eq!(step(&mut cpu, &mut memory), Ok(StepOutcome::Executed));
+        assert_eq!(memory.bytes[0x23fe], 0x01);
+    }
+
+}
@@ -1,27 +1,21 @@
 extern crate sdl2;
 
-mod invaders;
-mod cpu;
-mod condition_codes;
-mod op_arithmetic;
-mod op_branch;
-mod op_data_transfer;
-mod op_logical;
-mod op_special_io;
-mod op_stack;
-mod dissassembler;
-mod interrupts;
-mod helpers;
-
-use std::io;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
 use std::env;
+use std::net::TcpListener;
 use std::thread;
 use std::time::Duration;
 
-use invaders::emulate_invaders;
-use invaders::Machine;
-
-use helpers::{new_machine, generate_interrupt};
+use emulator8080::coverage::Coverage;
+use emulator8080::debugger::Debugger;
+use emulator8080::gdb::GdbServer;
+use emulator8080::invaders::{CYCLES_PER_FRAME, ROM_SIZE};
+use emulator8080::profiler::Profiler;
+use emulator8080::rom;
+use emulator8080::trace::{Filter, Tracer};
+use emulator8080::SymbolTable;
+use emulator8080::{generate_interrupt, EmulationError, Machine};
 
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
@@ -29,11 +23,66 @@ use sdl2::event::Event;
 use sdl2::rect::Rect;
 
 const SCALE_FACTOR: i32 = 3;
-const CYCLES_PER_FRAME:u64 = 4_000_000 / 60;
 
 fn main() -> io::Result<()> {
-    let _args: Vec<String> = env::args().collect();
-    let (mut machine, _buffer) = new_machine();
+    let args: Vec<String> = env::args().collect();
+    // --rom PATH: a merged image, a directory with the chip files or a zip
+    let path = args.iter().position(|arg| arg == "--rom").and_then(|i| args.get(i + 1));
+    let rom = rom::load(path.map(String::as_str).unwrap_or(rom::DEFAULT_PATH)).map_err(io::Error::other)?;
+    let mut machine = Machine::new();
+    machine.load_rom(&rom);
+    let mut tracer = tracer(&args)?;
+    let coverage_file = args.iter().position(|arg| arg == "--coverage").and_then(|i| args.get(i + 1)).cloned();
+    let mut coverage = coverage_file.as_ref().map(|_| Coverage::new());
+
+    // No window: the debugger drives the machine from stdin
+    if args.iter().any(|arg| arg == "--debug") {
+        let mut debugger = Debugger::new(machine, SymbolTable::invaders());
+        debugger.tracer = tracer;
+        debugger.coverage = coverage;
+        let stdin = io::stdin();
+        debugger.repl(stdin.lock(), &mut io::stdout())?;
+        write_coverage(coverage_file, debugger.coverage.take(), &debugger.machine)?;
+        return debugger.tracer.map(Tracer::finish).unwrap_or(Ok(()));
+    }
+    // No window: runs the attract mode for a while and says where the time went
+    if let Some(i) = args.iter().position(|arg| arg == "--profile") {
+        let report = match args.get(i + 1) {
+            Some(report) => report,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "--profile needs a file")),
+        };
+        let frames: u64 = match args.iter().position(|arg| arg == "--frames").map(|i| args.get(i + 1)) {
+            Some(frames) => frames.and_then(|f| f.parse().ok()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "--frames needs a number")
+            })?,
+            None => 600,
+        };
+        let mut debugger = Debugger::new(machine, SymbolTable::invaders());
+        debugger.tracer = tracer;
+        debugger.profiler = Some(Profiler::new());
+        debugger.coverage = coverage;
+        let end = frames * CYCLES_PER_FRAME;
+        debugger.run(u64::MAX, |machine| machine.cpu.total_cycles >= end);
+        let profiler = debugger.profiler.take().unwrap_or_default();
+        let mut out = BufWriter::new(File::create(report)?);
+        profiler.write_report(&mut out, &debugger.symbols, usize::MAX)?;
+        out.flush()?;
+        let mut out = BufWriter::new(File::create(format!("{}.folded", report))?);
+        profiler.write_folded(&mut out, &debugger.symbols)?;
+        out.flush()?;
+        write_coverage(coverage_file, debugger.coverage.take(), &debugger.machine)?;
+        return debugger.tracer.map(Tracer::finish).unwrap_or(Ok(()));
+    }
+    // No window either: GDB drives it, through `target remote :PORT`
+    if let Some(i) = args.iter().position(|arg| arg == "--gdb") {
+        let port: u16 = match args.get(i + 1).and_then(|port| port.parse().ok()) {
+            Some(port) => port,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "--gdb needs a port")),
+        };
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+        return GdbServer::new(Debugger::new(machine, SymbolTable::invaders())).serve(&listener);
+    }
 
     let sdl_context = sdl2::init().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
@@ -86,29 +135,68 @@ fn main() -> io::Result<()> {
         }
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
-        machine = half_step(machine, &mut canvas, true);
-        machine = half_step(machine, &mut canvas, false);
+        machine = half_step(machine, &mut canvas, true, &mut tracer, &mut coverage).map_err(io::Error::other)?;
+        machine = half_step(machine, &mut canvas, false, &mut tracer, &mut coverage).map_err(io::Error::other)?;
         canvas.present();
         thread::sleep(Duration::from_millis(16));
     }
+    write_coverage(coverage_file, coverage, &machine)?;
+    tracer.map(Tracer::finish).unwrap_or(Ok(()))
+}
+
+// --coverage FILE: the ROM listing, marked with what the run used of it
+fn write_coverage(path: Option<String>, coverage: Option<Coverage>, machine: &Machine) -> io::Result<()> {
+    if let (Some(path), Some(coverage)) = (path, coverage) {
+        let mut out = BufWriter::new(File::create(path)?);
+        coverage.write_listing(&mut out, &machine.memory[..ROM_SIZE], &SymbolTable::invaders())?;
+        out.flush()?;
+    }
     Ok(())
 }
 
-fn half_step(mut machine: Machine, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, top_half: bool) -> Machine {
-    let mut cycles_spent:u128 = 0;
-    while cycles_spent < (CYCLES_PER_FRAME / 2) as u128 {
-        machine = emulate_invaders(machine);
-        cycles_spent += machine.cpu.cycles as u128;
+// --trace FILE, narrowed by --trace-pc START-END and --trace-count FIRST-LAST
+fn tracer(args: &[String]) -> io::Result<Option<Tracer>> {
+    let value = |flag: &str| args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1));
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let path = match value("--trace") {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let mut filter = Filter::default();
+    if let Some(range) = value("--trace-pc") {
+        filter.pc_range = Some(Filter::parse_pc_range(range).map_err(invalid)?);
+    }
+    if let Some(window) = value("--trace-count") {
+        filter.window = Some(Filter::parse_window(window).map_err(invalid)?);
+    }
+    Tracer::create(path, filter).map(Some)
+}
+
+fn half_step(mut machine: Machine, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, top_half: bool, tracer: &mut Option<Tracer>, coverage: &mut Option<Coverage>) -> Result<Machine, EmulationError> {
+    let start = machine.cpu.total_cycles;
+    while machine.cpu.total_cycles - start < CYCLES_PER_FRAME / 2 {
+        if let Some(tracer) = tracer {
+            let cpu = machine.cpu;
+            tracer.trace(&cpu, &mut machine);
+        }
+        match coverage {
+            Some(coverage) => {
+                let mut cpu = machine.cpu;
+                coverage.step(&mut cpu, &mut machine)?;
+                machine.cpu = cpu;
+            }
+            None => {
+                machine.step()?;
+            }
+        }
     }
     redraw_screen(canvas, &machine, top_half);
     let int_enable = machine.cpu.int_enable;
     if int_enable {
-        return Machine {
-            cpu: generate_interrupt(machine.cpu, if top_half { 1 } else { 2 }),
-            ..machine
-        };
+        let cpu = generate_interrupt(machine.cpu, &mut machine, if top_half { 1 } else { 2 });
+        return Ok(Machine { cpu, ..machine });
     }
-    machine
+    Ok(machine)
 }
 
 fn redraw_screen(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, machine: &Machine, top_half: bool) {
@@ -121,7 +209,7 @@ fn redraw_screen(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, machine
     };
 
     for offset in 0..0xE00 {
-        let byte = machine.cpu.memory[start_memory + offset];
+        let byte = machine.memory[start_memory + offset];
 
         for bit in 0..8 {
             let color: u32 = if byte & (1 << bit) == 0 {
@@ -161,95 +249,3 @@ fn draw_pixel(canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, x: i32, y:
     let pixel_height = ((y + 1) * height / 256) - newy;
     canvas.fill_rect(Rect::new(newx, newy, pixel_width as u32, pixel_height as u32));
 }
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::io::Read;
-    use cpu::CPUState;
-    use dissassembler::disassemble;
-    use std::fs::File;
-    use cpu::emulate_8080_op;
-
-    #[test]
-    fn test_37_410_instructions_cpu_pc() {
-        let mut cpu = CPUState::new();
-        let mut buffer = Vec::new();
-        let mut f = File::open("invaders").unwrap();
-        f.read_to_end(&mut buffer).unwrap();
-        cpu.load_memory(&buffer, buffer.len());
-        let mut n = 0;
-        while n < 37410 {
-            cpu = emulate_8080_op(cpu);
-            disassemble(&buffer[cpu.pc as usize..], cpu.pc as usize);
-            n += 1;
-        }
-        assert_eq!(cpu.pc , 0x090e)
-    }
-
-    #[test]
-    fn test_39_000_instructions_cpu_pc() {
-        let mut cpu = CPUState::new();
-        let mut buffer = Vec::new();
-        let mut f = File::open("invaders").unwrap();
-        f.read_to_end(&mut buffer).unwrap();
-        cpu.load_memory(&buffer, buffer.len());
-        let mut n = 0;
-        while n < 39000 {
-            cpu = emulate_8080_op(cpu);
-            disassemble(&buffer[cpu.pc as usize..], cpu.pc as usize);
-            n += 1;
-        }
-        assert_eq!(cpu.pc , 0x1442)
-    }
-
-    #[test]
-    fn test_37_410_instructions_cpu_sp() {
-        let mut cpu = CPUState::new();
-        let mut buffer = Vec::new();
-        let mut f = File::open("invaders").unwrap();
-        f.read_to_end(&mut buffer).unwrap();
-        cpu.load_memory(&buffer, buffer.len());
-        let mut n = 0;
-        while n < 37410 {
-            cpu = emulate_8080_op(cpu);
-            disassemble(&buffer[cpu.pc as usize..], cpu.pc as usize);
-            n += 1;
-        }
-        assert_eq!(cpu.sp , 0x23f8)
-    }
-
-    #[test]
-    fn test_full_ops() {
-        let mut cpu = CPUState::new();
-        let mut buffer = Vec::new();
-        let mut f = File::open("cpudiag.bin").unwrap();
-        f.read_to_end(&mut buffer).unwrap();
-        let buffer = {
-            let mut padding = vec![0; 0x100];
-            padding[0] = 0xc3;
-            padding[1] = 0x00;
-            padding[2] = 0x01;
-            padding.append(&mut buffer);
-            //padding[368] = 0x7;
-            padding[0x59c] = 0xc3;
-            padding[0x59d] = 0xc2;
-            padding[0x59e] = 0x05;
-            padding[0x319] = 0x00;
-            padding[0x31a] = 0x00;
-            padding[0x31b] = 0x00;
-            padding[0x31c] = 0x00;
-            cpu.pc = 0x100;
-            padding
-        };
-        cpu.load_memory(&buffer, buffer.len());
-        let mut n = 0;
-        while n < 590 {
-            cpu = emulate_8080_op(cpu);
-            disassemble(&buffer[cpu.pc as usize..], cpu.pc as usize);
-            n += 1;
-        }
-        assert_eq!(cpu.pc, 0x0688);
-    }
-
-}
@@ -1,52 +1,93 @@
+use crate::bus::Bus;
 use crate::condition_codes::{Flags as ConditionCodes};
 use crate::cpu::CPUState;
 use crate::cpu::WithSPPairs;
-use crate::helpers::{set_all_flags, arith_flags};
+use crate::helpers::{add_flags, sub_flags, arith_flags};
 
-pub fn add(addendum: u8, cycles: u8, cpu: CPUState) -> CPUState {
+pub fn add(addendum: u8, cpu: CPUState) -> CPUState {
     let answer: u16 = (cpu.a as u16).wrapping_add(addendum as u16);
     let a = answer as u8;
     CPUState {
         a,
-        cc: set_all_flags(answer),
+        cc: add_flags(cpu.a, addendum, 0),
         pc: cpu.pc.wrapping_add(1),
-        cycles,
         ..cpu
     }
 }
 
-pub fn adi(addendum: u8, cycles: u8, cpu: CPUState) -> CPUState {
+pub fn adi(addendum: u8, cpu: CPUState) -> CPUState {
     let answer: u16 = (cpu.a as u16).wrapping_add(addendum as u16);
     let a = answer as u8;
     CPUState {
         a,
-        cc: set_all_flags(answer),
+        cc: add_flags(cpu.a, addendum, 0),
         pc: cpu.pc.wrapping_add(2),
-        cycles,
         ..cpu
     }
 }
 
-pub fn sub(subtraend: u8, cycles: u8, cpu: CPUState) -> CPUState {
+pub fn adc(addendum: u8, cpu: CPUState) -> CPUState {
+    let answer: u16 = (cpu.a as u16) + (addendum as u16) + (cpu.cc.cy as u16);
+    let a = answer as u8;
+    CPUState {
+        a,
+        cc: add_flags(cpu.a, addendum, cpu.cc.cy),
+        pc: cpu.pc.wrapping_add(1),
+        ..cpu
+    }
+}
+
+pub fn aci(addendum: u8, cpu: CPUState) -> CPUState {
+    let answer: u16 = (cpu.a as u16) + (addendum as u16) + (cpu.cc.cy as u16);
+    let a = answer as u8;
+    CPUState {
+        a,
+        cc: add_flags(cpu.a, addendum, cpu.cc.cy),
+        pc: cpu.pc.wrapping_add(2),
+        ..cpu
+    }
+}
+
+pub fn sub(subtraend: u8, cpu: CPUState) -> CPUState {
     let answer: u16 = (cpu.a as u16).wrapping_sub(subtraend as u16);
     let a = answer as u8;
     CPUState {
         a,
-        cc: set_all_flags(answer),
+        cc: sub_flags(cpu.a, subtraend, 0),
         pc: cpu.pc.wrapping_add(1),
-        cycles,
         ..cpu
     }
 }
 
-pub fn sui(subtraend: u8, cycles: u8, cpu: CPUState) -> CPUState {
+pub fn sui(subtraend: u8, cpu: CPUState) -> CPUState {
     let answer: u16 = (cpu.a as u16).wrapping_sub(subtraend as u16);
     let a = answer as u8;
     CPUState {
         a,
-        cc: set_all_flags(answer),
+        cc: sub_flags(cpu.a, subtraend, 0),
+        pc: cpu.pc.wrapping_add(2),
+        ..cpu
+    }
+}
+
+pub fn sbb(subtraend: u8, cpu: CPUState) -> CPUState {
+    let answer: u16 = (cpu.a as u16).wrapping_sub(subtraend as u16).wrapping_sub(cpu.cc.cy as u16);
+    let a = answer as u8;
+    CPUState {
+        a,
+        cc: sub_flags(cpu.a, subtraend, cpu.cc.cy),
+        pc: cpu.pc.wrapping_add(1),
+        ..cpu
+    }
+}
+
+pub fn sbi(subtraend: u8, cpu: CPUState) -> CPUState {
+    let answer: u16 = (cpu.a as u16).wrapping_sub(subtraend as u16).wrapping_sub(cpu.cc.cy as u16);
+    let a = answer as u8;
+    CPUState {
+        a,
+        cc: sub_flags(cpu.a, subtraend, cpu.cc.cy),
         pc: cpu.pc.wrapping_add(2),
-        cycles,
         ..cpu
     }
 }
@@ -54,31 +95,31 @@ pub fn sui(subtraend: u8, cycles: u8, cpu: CPUState) -> CPUState {
 pub fn inr_r(cpu: CPUState, r: char) -> CPUState {
     let (inter_cpu, answer ) = match r {
         'a' => {
-            let answer: u16 = (cpu.a as u16).wrapping_add(1 as u16);
+            let answer: u16 = (cpu.a as u16).wrapping_add(1);
             (CPUState { a: answer as u8, pc: cpu.pc.wrapping_add(1), ..cpu }, answer)
         }
         'b' => {
-            let answer: u16 = (cpu.b as u16).wrapping_add(1 as u16);
+            let answer: u16 = (cpu.b as u16).wrapping_add(1);
             (CPUState { b: answer as u8, pc: cpu.pc.wrapping_add(1),  ..cpu }, answer)
         }
         'c' => {
-            let answer: u16 = (cpu.c as u16).wrapping_add(1 as u16);
+            let answer: u16 = (cpu.c as u16).wrapping_add(1);
             (CPUState { c: answer as u8, pc: cpu.pc.wrapping_add(1),  ..cpu }, answer)
         }
         'd' => {
-            let answer: u16 = (cpu.d as u16).wrapping_add(1 as u16);
+            let answer: u16 = (cpu.d as u16).wrapping_add(1);
             (CPUState { d: answer as u8, pc: cpu.pc.wrapping_add(1),  ..cpu }, answer)
         }
         'e' => {
-            let answer: u16 = (cpu.e as u16).wrapping_add(1 as u16);
+            let answer: u16 = (cpu.e as u16).wrapping_add(1);
             (CPUState { e: answer as u8, pc: cpu.pc.wrapping_add(1),  ..cpu }, answer)
         }
         'h' => {
-            let answer: u16 = (cpu.h as u16).wrapping_add(1 as u16);
+            let answer: u16 = (cpu.h as u16).wrapping_add(1);
             (CPUState { h: answer as u8, pc: cpu.pc.wrapping_add(1),  ..cpu }, answer)
         }
         'l' => {
-            let answer: u16 = (cpu.l as u16).wrapping_add(1 as u16);
+            let answer: u16 = (cpu.l as u16).wrapping_add(1);
             (CPUState { l: answer as u8, pc: cpu.pc.wrapping_add(1),  ..cpu }, answer)
         }
         _ => (CPUState {pc: cpu.pc.wrapping_add(1),  ..cpu }, 0),
@@ -88,48 +129,45 @@ pub fn inr_r(cpu: CPUState, r: char) -> CPUState {
         z: cc.0,
         s: cc.1,
         p: cc.3,
+        ac: if answer & 0xf == 0 { 1 } else { 0 },
         ..inter_cpu.cc
     };
     CPUState { cc: flags, pc: cpu.pc.wrapping_add(1), ..inter_cpu }
 }
-pub fn inr_m(cpu: CPUState) -> CPUState {
+pub fn inr_m<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     let address: u16 = (cpu.h as u16) << 8 | cpu.l as u16;
-    let mut memory = cpu.memory;
-    let answer: u16 = (memory[address as usize] as u16).wrapping_add(1 as u16);
+    let answer: u16 = (bus.read(address) as u16).wrapping_add(1);
     let cc = arith_flags(answer);
-    memory[address as usize] = answer as u8;
+    bus.write(address, answer as u8);
     let flags = ConditionCodes {
         z: cc.0,
         s: cc.1,
         p: cc.3,
+        ac: if answer & 0xf == 0 { 1 } else { 0 },
         ..cpu.cc
     };
     CPUState {
-        memory,
         cc: flags,
         pc: cpu.pc.wrapping_add(1),
-        cycles: 3,
         ..cpu
     }
 }
 
-pub fn dcr_m(cpu: CPUState) -> CPUState {
+pub fn dcr_m<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     let address: u16 = (cpu.h as u16) << 8 | cpu.l as u16;
-    let mut memory = cpu.memory;
-    let answer: u16 = (memory[address as usize] as u16).wrapping_sub(1 as u16);
-    memory[address as usize] = answer as u8;
+    let answer: u16 = (bus.read(address) as u16).wrapping_sub(1);
+    bus.write(address, answer as u8);
     let cc = arith_flags(answer);
     let flags = ConditionCodes {
         z: cc.0,
         s: cc.1,
         p: cc.3,
+        ac: if answer & 0xf != 0xf { 1 } else { 0 },
         ..cpu.cc
     };
     CPUState {
-        memory,
         cc: flags,
         pc: cpu.pc.wrapping_add(1),
-        cycles: 3,
         ..cpu
     }
 }
@@ -137,31 +175,31 @@ pub fn dcr_m(cpu: CPUState) -> CPUState {
 pub fn dcr_r(cpu: CPUState, r: char) -> CPUState {
     let (inter_cpu, answer ) = match r {
         'a' => {
-            let answer: u16 = (cpu.a as u16).wrapping_sub(1 as u16);
+            let answer: u16 = (cpu.a as u16).wrapping_sub(1);
             (CPUState { a: answer as u8, pc: cpu.pc.wrapping_add(1), ..cpu }, answer)
         }
         'b' => {
-            let answer: u16 = (cpu.b as u16).wrapping_sub(1 as u16);
+            let answer: u16 = (cpu.b as u16).wrapping_sub(1);
             (CPUState { b: answer as u8, pc: cpu.pc.wrapping_add(1), ..cpu }, answer)
         }
         'c' => {
-            let answer: u16 = (cpu.c as u16).wrapping_sub(1 as u16);
+            let answer: u16 = (cpu.c as u16).wrapping_sub(1);
             (CPUState { c: answer as u8, pc: cpu.pc.wrapping_add(1), ..cpu }, answer)
         }
         'd' => {
-            let answer: u16 = (cpu.d as u16).wrapping_sub(1 as u16);
+            let answer: u16 = (cpu.d as u16).wrapping_sub(1);
             (CPUState { d: answer as u8, pc: cpu.pc.wrapping_add(1), ..cpu }, answer)
         }
         'e' => {
-            let answer: u16 = (cpu.e as u16).wrapping_sub(1 as u16);
+            let answer: u16 = (cpu.e as u16).wrapping_sub(1);
             (CPUState { e: answer as u8, pc: cpu.pc.wrapping_add(1), ..cpu }, answer)
         }
         'h' => {
-            let answer: u16 = (cpu.h as u16).wrapping_sub(1 as u16);
+            let answer: u16 = (cpu.h as u16).wrapping_sub(1);
             (CPUState { h: answer as u8, pc: cpu.pc.wrapping_add(1), ..cpu }, answer)
         }
         'l' => {
-            let answer: u16 = (cpu.l as u16).wrapping_sub(1 as u16);
+            let answer: u16 = (cpu.l as u16).wrapping_sub(1);
             (CPUState { l: answer as u8, pc: cpu.pc.wrapping_add(1), ..cpu }, answer)
         }
         _ => (CPUState { ..cpu }, 0),
@@ -171,6 +209,7 @@ pub fn dcr_r(cpu: CPUState, r: char) -> CPUState {
         z: cc.0,
         s: cc.1,
         p: cc.3,
+        ac: if answer & 0xf != 0xf { 1 } else { 0 },
         ..inter_cpu.cc
     };
     CPUState { cc: flags, pc: cpu.pc.wrapping_add(1), ..inter_cpu }
@@ -180,40 +219,36 @@ pub fn inx(cpu: CPUState, rp: WithSPPairs) -> CPUState {
     match rp {
         WithSPPairs::BC => {
             let result =
-                (((cpu.b as u16) << 8 | cpu.c as u16).wrapping_add(1 as u16)).to_be_bytes();
+                (((cpu.b as u16) << 8 | cpu.c as u16).wrapping_add(1)).to_be_bytes();
             CPUState {
                 b: result[0],
                 c: result[1],
                 pc: cpu.pc.wrapping_add(1),
-                cycles: 1,
                 ..cpu
             }
         }
         WithSPPairs::DE => {
             let result =
-                (((cpu.d as u16) << 8 | cpu.e as u16).wrapping_add(1 as u16)).to_be_bytes();
+                (((cpu.d as u16) << 8 | cpu.e as u16).wrapping_add(1)).to_be_bytes();
             CPUState {
                 d: result[0],
                 e: result[1],
                 pc: cpu.pc.wrapping_add(1),
-                cycles: 1,
                 ..cpu
             }
         }
         WithSPPairs::HL => {
             let result =
-                (((cpu.h as u16) << 8 | cpu.l as u16).wrapping_add(1 as u16)).to_be_bytes();
+                (((cpu.h as u16) << 8 | cpu.l as u16).wrapping_add(1)).to_be_bytes();
             CPUState {
                 h: result[0],
                 l: result[1],
                 pc: cpu.pc.wrapping_add(1),
-                cycles: 1,
                 ..cpu
             }
         }
         WithSPPairs::SP => CPUState {
-            sp: cpu.sp.wrapping_add(1 as u16),
-            cycles: 1,
+            sp: cpu.sp.wrapping_add(1),
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
@@ -224,41 +259,37 @@ pub fn dcx(cpu: CPUState, rp: WithSPPairs) -> CPUState {
     match rp {
         WithSPPairs::BC => {
             let result =
-                (((cpu.b as u16) << 8 | cpu.c as u16).wrapping_sub(1 as u16)).to_be_bytes();
+                (((cpu.b as u16) << 8 | cpu.c as u16).wrapping_sub(1)).to_be_bytes();
             CPUState {
                 b: result[0],
                 c: result[1],
                 pc: cpu.pc.wrapping_add(1),
-                cycles: 1,
                 ..cpu
             }
         }
         WithSPPairs::DE => {
             let result =
-                (((cpu.d as u16) << 8 | cpu.e as u16).wrapping_sub(1 as u16)).to_be_bytes();
+                (((cpu.d as u16) << 8 | cpu.e as u16).wrapping_sub(1)).to_be_bytes();
             CPUState {
                 d: result[0],
                 e: result[1],
                 pc: cpu.pc.wrapping_add(1),
-                cycles: 1,
                 ..cpu
             }
         }
         WithSPPairs::HL => {
             let result =
-                (((cpu.h as u16) << 8 | cpu.l as u16).wrapping_sub(1 as u16)).to_be_bytes();
+                (((cpu.h as u16) << 8 | cpu.l as u16).wrapping_sub(1)).to_be_bytes();
             CPUState {
                 h: result[0],
                 l: result[1],
                 pc: cpu.pc.wrapping_add(1),
-                cycles: 1,
                 ..cpu
             }
         }
         WithSPPairs::SP => CPUState {
-            sp: cpu.sp.wrapping_sub(1 as u16),
+            sp: cpu.sp.wrapping_sub(1),
             pc: cpu.pc.wrapping_add(1),
-            cycles: 1,
             ..cpu
         }
     }
@@ -266,19 +297,20 @@ pub fn dcx(cpu: CPUState, rp: WithSPPairs) -> CPUState {
 
 pub fn dad(cpu: CPUState, rp: (char, char)) -> CPUState {
     let rp_hl: u16 = (cpu.h as u16) << 8 | cpu.l as u16;
-    if rp == ('s', 'p') {
-        return CPUState {
-            sp: cpu.sp.wrapping_add(rp_hl),
-            pc: cpu.pc.wrapping_add(1),
-            cycles: 1,
-            ..cpu
-        }
-    }
-    let value_to_add = match rp {
-        ('b', 'c') => (((cpu.b as u16) << 8 | cpu.c as u16).wrapping_add(rp_hl)).to_be_bytes(),
-        ('d', 'e') => (((cpu.d as u16) << 8 | cpu.e as u16).wrapping_add(rp_hl)).to_be_bytes(),
-        ('h', 'l') => (((cpu.h as u16) << 8 | cpu.l as u16).wrapping_add(rp_hl)).to_be_bytes(),
-        _ => rp_hl.to_be_bytes(),
+    let value_to_add: u16 = match rp {
+        ('b', 'c') => (cpu.b as u16) << 8 | cpu.c as u16,
+        ('d', 'e') => (cpu.d as u16) << 8 | cpu.e as u16,
+        ('h', 'l') => rp_hl,
+        ('s', 'p') => cpu.sp,
+        _ => 0,
     };
-    CPUState { h: value_to_add[0], l: value_to_add[1], pc: cpu.pc.wrapping_add(1), cycles: 3, ..cpu}
+    let (answer, carry) = rp_hl.overflowing_add(value_to_add);
+    let answer = answer.to_be_bytes();
+    CPUState {
+        h: answer[0],
+        l: answer[1],
+        pc: cpu.pc.wrapping_add(1),
+        cc: ConditionCodes { cy: if carry { 1 } else { 0 }, ..cpu.cc },
+        ..cpu
+    }
 }
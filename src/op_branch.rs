@@ -1,10 +1,10 @@
 #![allow(dead_code)]
+use crate::bus::Bus;
 use crate::cpu::CPUState;
 use crate::helpers::{pop_from_stack, push_to_stack_addr};
 
 pub fn jmp(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
     CPUState {
-        cycles: 3,
         pc: (opcode_2 as u16) << 8 | opcode_1 as u16,
         ..cpu
     }
@@ -15,7 +15,6 @@ pub fn jnz(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
         return jmp(cpu, opcode_1, opcode_2);
     }
     CPUState {
-        cycles: 3,
         pc: cpu.pc.wrapping_add(3),
         ..cpu
     }
@@ -26,7 +25,6 @@ pub fn jz(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
         return jmp(cpu, opcode_1, opcode_2);
     }
     CPUState {
-        cycles: 3,
         pc: cpu.pc.wrapping_add(3),
         ..cpu
     }
@@ -39,7 +37,6 @@ pub fn jnc(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
         cpu.pc.wrapping_add(3)
     };
     CPUState {
-        cycles: 3,
         pc: value,
         ..cpu
     }
@@ -50,7 +47,6 @@ pub fn jc(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
         return jmp(cpu, opcode_1, opcode_2);
     }
     CPUState {
-        cycles: 3,
         pc: cpu.pc.wrapping_add(3),
         ..cpu
     }
@@ -63,7 +59,6 @@ pub fn jpo(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
         cpu.pc.wrapping_add(3)
     };
     CPUState {
-        cycles: 3,
         pc: value,
         ..cpu
     }
@@ -76,7 +71,6 @@ pub fn jpe(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
         cpu.pc.wrapping_add(3)
     };
     CPUState {
-        cycles: 3,
         pc: value,
         ..cpu
     }
@@ -89,7 +83,6 @@ pub fn jp(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
         cpu.pc.wrapping_add(3)
     };
     CPUState {
-        cycles: 3,
         pc: value,
         ..cpu
     }
@@ -102,197 +95,179 @@ pub fn jm(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
         cpu.pc.wrapping_add(3)
     };
     CPUState {
-        cycles: 3,
         pc: value,
         ..cpu
     }
 }
 
-pub fn call(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn call<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     let pc = cpu.pc.wrapping_add(3);
-    let cpu = push_to_stack_addr(cpu, pc);
+    let cpu = push_to_stack_addr(cpu, bus, pc);
     jmp(cpu, opcode_1, opcode_2)
 }
 
-pub fn cc(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn cc<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     match cpu.cc.cy {
-        1 => call(cpu, opcode_1, opcode_2),
+        1 => call(cpu, bus, opcode_1, opcode_2),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(3),
             ..cpu
         },
     }
 }
 
-pub fn cnc(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn cnc<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     match cpu.cc.cy {
-        0 => call(cpu, opcode_1, opcode_2),
+        0 => call(cpu, bus, opcode_1, opcode_2),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(3),
             ..cpu
         },
     }
 }
 
-pub fn cz(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn cz<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     match cpu.cc.z {
-        1 => call(cpu, opcode_1, opcode_2),
+        1 => call(cpu, bus, opcode_1, opcode_2),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(3),
             ..cpu
         },
     }
 }
 
-pub fn cnz(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn cnz<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     match cpu.cc.z {
-        0 => call(cpu, opcode_1, opcode_2),
+        0 => call(cpu, bus, opcode_1, opcode_2),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(3),
             ..cpu
         },
     }
 }
 
-pub fn cp(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn cp<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     match cpu.cc.s {
-        0 => call(cpu, opcode_1, opcode_2),
+        0 => call(cpu, bus, opcode_1, opcode_2),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(3),
             ..cpu
         },
     }
 }
 
-pub fn cm(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn cm<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     match cpu.cc.s {
-        1 => call(cpu, opcode_1, opcode_2),
+        1 => call(cpu, bus, opcode_1, opcode_2),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(3),
             ..cpu
         },
     }
 }
 
-pub fn cpe(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn cpe<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     match cpu.cc.p {
-        1 => call(cpu, opcode_1, opcode_2),
+        1 => call(cpu, bus, opcode_1, opcode_2),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(3),
             ..cpu
         },
     }
 }
 
-pub fn cpo(cpu: CPUState, opcode_1: u8, opcode_2: u8) -> CPUState {
+pub fn cpo<B: Bus>(cpu: CPUState, bus: &mut B, opcode_1: u8, opcode_2: u8) -> CPUState {
     match cpu.cc.p {
-        0 => call(cpu, opcode_1, opcode_2),
+        0 => call(cpu, bus, opcode_1, opcode_2),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(3),
             ..cpu
         },
     }
 }
 
-pub fn ret(cpu: CPUState) -> CPUState {
-    let (cpu, addr) = pop_from_stack(cpu);
+pub fn ret<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
+    let (cpu, addr) = pop_from_stack(cpu, bus);
     CPUState {
-        cycles: 10,
         pc: addr,
         ..cpu
     }
 }
 
-pub fn rc(cpu: CPUState) -> CPUState {
+pub fn rc<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     match cpu.cc.cy {
-        1 => ret(cpu),
+        1 => ret(cpu, bus),
         _ => CPUState {
-            cycles: 5,
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
     }
 }
 
-pub fn rnc(cpu: CPUState) -> CPUState {
+pub fn rnc<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     match cpu.cc.cy {
-        0 => ret(cpu),
+        0 => ret(cpu, bus),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
     }
 }
 
-pub fn rz(cpu: CPUState) -> CPUState {
+pub fn rz<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     match cpu.cc.z {
-        1 => ret(cpu),
+        1 => ret(cpu, bus),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
     }
 }
 
-pub fn rnz(cpu: CPUState) -> CPUState {
+pub fn rnz<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     match cpu.cc.z {
-        0 => ret(cpu),
+        0 => ret(cpu, bus),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
     }
 }
 
-pub fn rp(cpu: CPUState) -> CPUState {
+pub fn rp<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     match cpu.cc.s {
-        0 => ret(cpu),
+        0 => ret(cpu, bus),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
     }
 }
 
-pub fn rm(cpu: CPUState) -> CPUState {
+pub fn rm<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     match cpu.cc.s {
-        1 => ret(cpu),
+        1 => ret(cpu, bus),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
     }
 }
 
-pub fn rpo(cpu: CPUState) -> CPUState {
+pub fn rpo<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     match cpu.cc.p {
-        0 => ret(cpu),
+        0 => ret(cpu, bus),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
     }
 }
 
-pub fn rpe(cpu: CPUState) -> CPUState {
+pub fn rpe<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     match cpu.cc.p {
-        1 => ret(cpu),
+        1 => ret(cpu, bus),
         _ => CPUState {
-            cycles: 3,
             pc: cpu.pc.wrapping_add(1),
             ..cpu
         },
@@ -302,25 +277,21 @@ pub fn rpe(cpu: CPUState) -> CPUState {
 
 pub fn pchl(cpu: CPUState) -> CPUState {
     CPUState {
-        cycles: 1,
         pc: (cpu.h as u16) << 8 | cpu.l as u16,
         ..cpu
     }
 }
 
-pub fn rst(cpu: CPUState, n: u8) -> CPUState {
-    let pc = cpu.pc.to_be_bytes();
-    let mut memory = cpu.memory;
-    memory[cpu.sp as usize - 1] = pc[0];
-    memory[cpu.sp as usize - 2] = pc[1];
+pub fn rst<B: Bus>(cpu: CPUState, bus: &mut B, n: u8) -> CPUState {
+    let pc = cpu.pc.wrapping_add(1).to_be_bytes();
+    bus.write(cpu.sp.wrapping_sub(1), pc[0]);
+    bus.write(cpu.sp.wrapping_sub(2), pc[1]);
     match n {
         0..=7 => CPUState {
-            cycles: 3,
             pc: (8 * n) as u16,
             sp: cpu.sp.wrapping_sub(2),
-            memory,
             ..cpu
         },
-        _ => CPUState { memory, ..cpu }
+        _ => cpu
     }
 }
@@ -1,9 +1,9 @@
 #![allow(dead_code)]
 use crate::condition_codes::{Flags as ConditionCodes};
 use crate::cpu::CPUState;
-use crate::helpers::arith_flags_logs;
+use crate::helpers::{arith_flags_logs, sub_flags};
 
-pub fn ana(value: u8, cycles: u8, cpu: CPUState) -> CPUState {
+pub fn ana(value: u8, cpu: CPUState) -> CPUState {
     let answer = (cpu.a & value) as u16;
     let flags_result = arith_flags_logs(answer);
     let flags = ConditionCodes {
@@ -11,13 +11,13 @@ pub fn ana(value: u8, cycles: u8, cpu: CPUState) -> CPUState {
         s: flags_result.1,
         cy: 0,
         p: flags_result.3,
-        ..cpu.cc
+        // The 8080 ANDs through the adder, leaving bit 3 of the OR in AC
+        ac: ((cpu.a | value) >> 3) & 1,
     };
 
     CPUState {
         a: answer.to_be_bytes()[1],
-        cycles,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         cc: flags,
         ..cpu
     }
@@ -31,20 +31,19 @@ pub fn ani(value: u8, cpu: CPUState) -> CPUState {
         s: flags_result.1,
         cy: 0,
         p: flags_result.3,
-        ac: 0,
-        ..cpu.cc
+        // Same AC quirk as ANA
+        ac: ((cpu.a | value) >> 3) & 1,
     };
 
     CPUState {
         a: answer.to_be_bytes()[1],
-        cycles: 2,
-        pc: cpu.pc + 2,
+        pc: cpu.pc.wrapping_add(2),
         cc: flags,
         ..cpu
     }
 }
 
-pub fn xra(value: u8, cycles: u8, cpu: CPUState) -> CPUState {
+pub fn xra(value: u8, cpu: CPUState) -> CPUState {
     let answer = (cpu.a ^ value) as u16;
     let flags_result = arith_flags_logs(answer);
     let flags = ConditionCodes {
@@ -52,13 +51,12 @@ pub fn xra(value: u8, cycles: u8, cpu: CPUState) -> CPUState {
         s: flags_result.1,
         cy: 0,
         p: flags_result.3,
-        ..cpu.cc
+        ac: 0,
     };
 
     CPUState {
         a: answer.to_be_bytes()[1],
-        cycles,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         cc: flags,
         ..cpu
     }
@@ -72,19 +70,18 @@ pub fn xri(value: u8, cpu: CPUState) -> CPUState {
         s: flags_result.1,
         cy: 0,
         p: flags_result.3,
-        ..cpu.cc
+        ac: 0,
     };
 
     CPUState {
         a: answer.to_be_bytes()[1],
-        cycles: 2,
-        pc: cpu.pc + 2,
+        pc: cpu.pc.wrapping_add(2),
         cc: flags,
         ..cpu
     }
 }
 
-pub fn ora(value: u8, cycles: u8, cpu: CPUState) -> CPUState {
+pub fn ora(value: u8, cpu: CPUState) -> CPUState {
     let answer = (cpu.a | value) as u16;
     let flags_result = arith_flags_logs(answer);
     let flags = ConditionCodes {
@@ -92,13 +89,12 @@ pub fn ora(value: u8, cycles: u8, cpu: CPUState) -> CPUState {
         s: flags_result.1,
         cy: 0,
         p: flags_result.3,
-        ..cpu.cc
+        ac: 0,
     };
 
     CPUState {
         a: answer.to_be_bytes()[1],
-        cycles,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         cc: flags,
         ..cpu
     }
@@ -112,68 +108,29 @@ pub fn ori(value: u8, cpu: CPUState) -> CPUState {
         s: flags_result.1,
         cy: 0,
         p: flags_result.3,
-        ..cpu.cc
+        ac: 0,
     };
 
     CPUState {
         a: answer.to_be_bytes()[1],
-        cycles: 2,
-        pc: cpu.pc + 2,
+        pc: cpu.pc.wrapping_add(2),
         cc: flags,
         ..cpu
     }
 }
 
-pub fn cmp(value: u8, cycles: u8, cpu: CPUState) -> CPUState {
-    let answer = ((cpu.a).wrapping_sub(value)) as u16;
-    let flags_result = arith_flags_logs(answer);
-    let flags = ConditionCodes {
-        z: if answer.to_be_bytes()[1] == cpu.a {
-            1
-        } else {
-            cpu.cc.z
-        },
-        s: flags_result.1,
-        cy: if cpu.a < value {
-            1
-        } else {
-            cpu.cc.z
-        },
-        p: flags_result.3,
-        ..cpu.cc
-    };
-
+pub fn cmp(value: u8, cpu: CPUState) -> CPUState {
     CPUState {
-        cycles,
-        pc: cpu.pc + 1,
-        cc: flags,
+        pc: cpu.pc.wrapping_add(1),
+        cc: sub_flags(cpu.a, value, 0),
         ..cpu
     }
 }
 
 pub fn cpi(value: u8, cpu: CPUState) -> CPUState {
-    let answer = (cpu.a as u16).wrapping_sub(value as u16);
-    let flags_result = arith_flags_logs(answer);
-    let flags = ConditionCodes {
-        z: if value == cpu.a {
-            1
-        } else {
-            0
-        },
-        s: flags_result.1,
-        cy: if cpu.a < value {
-            1
-        } else {
-            0
-        },
-        p: flags_result.3,
-        ..cpu.cc
-    };
-
     CPUState {
-        cycles: 2,
-        pc: cpu.pc + 2,
-        cc: flags,
+        pc: cpu.pc.wrapping_add(2),
+        cc: sub_flags(cpu.a, value, 0),
         ..cpu
     }
 }
@@ -183,8 +140,7 @@ pub fn rlc(cpu: CPUState) -> CPUState {
     let mut answer: u8 = cpu.a << 1;
     answer |= bit7 >> 7;
     CPUState {
-        cycles: 1,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         a: answer,
         cc: ConditionCodes { cy: if bit7 != 0 { 1 } else { 0 }, ..cpu.cc },
         ..cpu
@@ -196,7 +152,6 @@ pub fn rrc(cpu: CPUState) -> CPUState {
     let mut answer = cpu.a >> 1;
     answer |= bit0 << 7;
     CPUState {
-        cycles: 1,
         pc: cpu.pc.wrapping_add(1),
         a: answer,
         cc: ConditionCodes { cy: if bit0 != 0 { 1 } else { 0 }, ..cpu.cc },
@@ -209,8 +164,7 @@ pub fn rar(cpu: CPUState) -> CPUState {
     let mut answer = cpu.a >> 1;
     if cpu.cc.cy == 1 { answer |= 1 << 7; }
     CPUState {
-        cycles: 1,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         a: answer,
         cc: ConditionCodes { cy: if bit0 != 0 { 1 } else { 0 }, ..cpu.cc },
         ..cpu
@@ -222,18 +176,16 @@ pub fn ral(cpu: CPUState) -> CPUState {
     let mut answer = cpu.a << 1;
     answer |= cpu.cc.cy;
     CPUState {
-        cycles: 1,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         a: answer,
-        cc: ConditionCodes { cy: bit7, ..cpu.cc },
+        cc: ConditionCodes { cy: bit7 >> 7, ..cpu.cc },
         ..cpu
     }
 }
 
 pub fn cma(cpu: CPUState) -> CPUState {
     CPUState {
-        cycles: 1,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         a: !cpu.a,
         ..cpu
     }
@@ -241,10 +193,9 @@ pub fn cma(cpu: CPUState) -> CPUState {
 
 pub fn cmc(cpu: CPUState) -> CPUState {
     CPUState {
-        cycles: 1,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         cc: ConditionCodes {
-            cy: !cpu.cc.cy,
+            cy: cpu.cc.cy ^ 1,
             ..cpu.cc
         },
         ..cpu
@@ -253,8 +204,7 @@ pub fn cmc(cpu: CPUState) -> CPUState {
 
 pub fn stc(cpu: CPUState) -> CPUState {
     CPUState {
-        cycles: 1,
-        pc: cpu.pc + 1,
+        pc: cpu.pc.wrapping_add(1),
         cc: ConditionCodes { cy: 1, ..cpu.cc },
         ..cpu
     }
@@ -1,13 +1,13 @@
 #![allow(dead_code)]
-use std::process;
+use crate::bus::Bus;
 use crate::cpu::CPUState;
-use crate::helpers::{set_all_flags, arith_flags};
+use crate::condition_codes::{Flags as ConditionCodes};
+use crate::helpers::add_flags;
 
 pub fn ei(cpu: CPUState) -> CPUState {
     CPUState {
         pc: cpu.pc.wrapping_add(1),
         int_enable: true,
-        cycles: 1,
         ..cpu
     }
 }
@@ -16,27 +16,30 @@ pub fn di(cpu: CPUState) -> CPUState {
     CPUState {
         pc: cpu.pc.wrapping_add(1),
         int_enable: false,
-        cycles: 1,
         ..cpu
     }
 }
 
-pub fn hlt() {
-    process::exit(0);
+pub fn hlt(cpu: CPUState) -> CPUState {
+    CPUState {
+        pc: cpu.pc.wrapping_add(1),
+        halted: true,
+        ..cpu
+    }
 }
 
-pub fn op_in(cpu: CPUState, value: u8) -> CPUState {
+pub fn op_in<B: Bus>(cpu: CPUState, bus: &mut B, port: u8) -> CPUState {
     CPUState {
-        a: value,
+        a: bus.port_in(port),
         pc: cpu.pc.wrapping_add(2),
         ..cpu
     }
 }
 
-pub fn out(cpu: CPUState) -> CPUState {
+pub fn out<B: Bus>(cpu: CPUState, bus: &mut B, port: u8) -> CPUState {
+    bus.port_out(port, cpu.a);
     CPUState {
         pc: cpu.pc.wrapping_add(2),
-        cycles: 3,
         ..cpu
     }
 }
@@ -44,28 +47,30 @@ pub fn out(cpu: CPUState) -> CPUState {
 pub fn nop(cpu: CPUState) -> CPUState {
     CPUState {
         pc: cpu.pc.wrapping_add(1),
-        cycles: 1,
         ..cpu
     }
 }
 
 pub fn daa(cpu: CPUState) -> CPUState {
-    let mut result = cpu.a as u16;
-    let mut ac = cpu.cc.ac;
-    let lsb = result & 0xf;
+    let lsb = cpu.a & 0xf;
+    let msb = cpu.a >> 4;
+    let mut correction: u8 = 0;
+    let mut cy = cpu.cc.cy;
     if (cpu.cc.ac == 1) || lsb > 9 {
-        result += 6;
-        if result & 0xf < lsb { ac = 1 }
+        correction |= 0x06;
+    }
+    if (cpu.cc.cy == 1) || msb > 9 || (msb >= 9 && lsb > 9) {
+        correction |= 0x60;
+        cy = 1;
     }
-    let lsb = result & 0xf;
-    let mut msb = (result >> 4) & 0xf;
-    if (cpu.cc.cy == 1) || msb > 9 { msb += 6 }
-    let result = (msb << 4) | lsb;
-    let mut all_flags = set_all_flags(result);
-    all_flags.ac = ac;
+    let result = cpu.a.wrapping_add(correction);
+    let all_flags = ConditionCodes {
+        cy,
+        ..add_flags(cpu.a, correction, 0)
+    };
     CPUState {
         pc: cpu.pc.wrapping_add(1),
-        a: result as u8,
+        a: result,
         cc: all_flags,
         ..cpu
     }
@@ -1,33 +1,33 @@
+use crate::bus::Bus;
 use crate::cpu::CPUState;
 use crate::cpu::StackPairs;
 use crate::helpers::{pop_from_stack, set_psw, push_to_stack_addr};
 
-pub fn push(cpu: CPUState, rp: StackPairs) -> CPUState {
+pub fn push<B: Bus>(cpu: CPUState, bus: &mut B, rp: StackPairs) -> CPUState {
     let new_cpu = match rp {
         StackPairs::BC => {
             let addr = ((cpu.b as u16) << 8) | cpu.c as u16;
-            push_to_stack_addr(cpu, addr)
+            push_to_stack_addr(cpu, bus, addr)
         }
         StackPairs::DE => {
             let addr = ((cpu.d as u16) << 8) | cpu.e as u16;
-            push_to_stack_addr(cpu, addr)
+            push_to_stack_addr(cpu, bus, addr)
         }
         StackPairs::HL => {
             let addr = ((cpu.h as u16) << 8) | cpu.l as u16;
-            push_to_stack_addr(cpu, addr)
+            push_to_stack_addr(cpu, bus, addr)
         }
     };
 
     CPUState {
-        cycles: 3,
         pc: new_cpu.pc.wrapping_add(1),
         ..new_cpu
     }
 }
 
-pub fn pop(cpu: CPUState, rp: StackPairs) -> CPUState {
-    let value_l: u8 = cpu.memory[cpu.sp as usize];
-    let value_h: u8 = cpu.memory[(cpu.sp.wrapping_add(1)) as usize];
+pub fn pop<B: Bus>(cpu: CPUState, bus: &mut B, rp: StackPairs) -> CPUState {
+    let value_l: u8 = bus.read(cpu.sp);
+    let value_h: u8 = bus.read(cpu.sp.wrapping_add(1));
     let inter_cpu = match rp {
         StackPairs::BC => {
             CPUState { b: value_h, c: value_l, ..cpu }
@@ -41,41 +41,24 @@ pub fn pop(cpu: CPUState, rp: StackPairs) -> CPUState {
     };
 
     CPUState {
-        cycles: 3,
         sp: cpu.sp.wrapping_add(2),
         pc: cpu.pc.wrapping_add(1),
         ..inter_cpu
     }
 }
 
-pub fn push_psw(cpu: CPUState) -> CPUState {
-    let mut psw:u16 = 0;
-    let s = if cpu.cc.s == 1 { 1 } else { 0 };
-    let z = if cpu.cc.z == 1 { 1 } else { 0 };
-    let ac = if cpu.cc.ac == 1 { 1 } else { 0 };
-    let p = if cpu.cc.p == 1 { 1 } else { 0 };
-    let cy = if cpu.cc.cy == 1 { 1 } else { 0 };
-
-    psw |= s << 7;
-    psw |= z << 6;
-    psw |= 0 << 5;
-    psw |= ac << 4;
-    psw |= 0 << 3;
-    psw |= p << 2;
-    psw |= 1 << 1;
-    psw |= cy;
-    psw |= (cpu.a as u16) << 8;
+pub fn push_psw<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
+    let psw = (cpu.a as u16) << 8 | cpu.cc.psw() as u16;
     CPUState {
         pc: cpu.pc.wrapping_add(1),
-        ..push_to_stack_addr(cpu, psw)
+        ..push_to_stack_addr(cpu, bus, psw)
     }
 }
 
-pub fn pop_psw(cpu: CPUState) -> CPUState {
-    let (cpu, data) = pop_from_stack(cpu);
+pub fn pop_psw<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
+    let (cpu, data) = pop_from_stack(cpu, bus);
     let a = (data >> 8) as u8;
     CPUState {
-        cycles: 3,
         a,
         cc: set_psw(data as u8),
         pc: cpu.pc.wrapping_add(1),
@@ -83,14 +66,14 @@ pub fn pop_psw(cpu: CPUState) -> CPUState {
     }
 }
 
-pub fn xthl(cpu: CPUState) -> CPUState {
+pub fn xthl<B: Bus>(cpu: CPUState, bus: &mut B) -> CPUState {
     let temp:u16 = ((cpu.h as u16) << 8) | (cpu.l as u16);
-    let (cpu, temp2) = pop_from_stack(cpu);
+    let (cpu, temp2) = pop_from_stack(cpu, bus);
     CPUState {
         pc: cpu.pc.wrapping_add(1),
         h: (temp2 >> 8) as u8,
         l: temp2 as u8,
-        ..push_to_stack_addr(cpu, temp)
+        ..push_to_stack_addr(cpu, bus, temp)
     }
 }
 
@@ -98,7 +81,6 @@ pub fn sphl(cpu: CPUState) -> CPUState {
     let value: u16 = (cpu.h as u16) << 8 | cpu.l as u16;
     CPUState {
         sp: value,
-        cycles: 3,
         pc: cpu.pc.wrapping_add(1),
         ..cpu
     }
@@ -0,0 +1,162 @@
+use crate::call_stack::CallStack;
+use crate::invaders::CYCLES_PER_FRAME;
+use crate::symbols::SymbolTable;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Instructions executed, and the cycles they took.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Count {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Count {
+    fn add(&mut self, cycles: u64) {
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+}
+
+/// Where a run spent its time: instructions and cycles by address, and by
+/// routine from the shadow call stack.
+///
+/// A routine's own time is what it executed itself while innermost, its
+/// total time also counts the routines it called. Code outside any routine
+/// (the main loop, or everything when no call stack is given) is the top
+/// level. The folded stacks are the input of flamegraph tools such as
+/// `flamegraph.pl` and inferno, weighted by cycles.
+#[derive(Clone, Debug)]
+pub struct Profiler {
+    addresses: Vec<Count>,
+    pub total: Count,
+    // Own time and total cycles by routine, None being the top level
+    routines: HashMap<Option<u16>, (Count, u64)>,
+    // Cycles by the routines on the stack, outermost first
+    stacks: HashMap<Vec<u16>, u64>,
+    stack: Vec<u16>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            addresses: vec![Count::default(); 0x10000],
+            total: Count::default(),
+            routines: HashMap::new(),
+            stacks: HashMap::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Counts the instruction at `pc`, which took `cycles`, executed inside
+    /// the routines on `calls` as they were before it.
+    pub fn record(&mut self, pc: u16, cycles: u64, calls: Option<&CallStack>) {
+        self.addresses[pc as usize].add(cycles);
+        self.total.add(cycles);
+        self.stack.clear();
+        if let Some(calls) = calls {
+            self.stack.extend(calls.frames().map(|frame| frame.routine));
+            self.stack.reverse();
+        }
+        self.routines.entry(self.stack.last().copied()).or_default().0.add(cycles);
+        self.routines.entry(None).or_default().1 += cycles;
+        for (i, routine) in self.stack.iter().enumerate() {
+            // A routine that called itself only counts once
+            if !self.stack[..i].contains(routine) {
+                self.routines.entry(Some(*routine)).or_default().1 += cycles;
+            }
+        }
+        match self.stacks.get_mut(&self.stack[..]) {
+            Some(total) => *total += cycles,
+            None => {
+                self.stacks.insert(self.stack.clone(), cycles);
+            }
+        }
+    }
+
+    /// What the instruction at `address` did.
+    pub fn at(&self, address: u16) -> Count {
+        self.addresses[address as usize]
+    }
+
+    /// A routine's own time and its total cycles, or the top level's for
+    /// None.
+    pub fn routine(&self, routine: Option<u16>) -> (Count, u64) {
+        self.routines.get(&routine).copied().unwrap_or_default()
+    }
+
+    /// The routines by own cycles and the addresses by cycles, most first,
+    /// at most `limit` of each.
+    pub fn write_report<W: Write>(&self, out: &mut W, symbols: &SymbolTable, limit: usize) -> io::Result<()> {
+        let percent = |cycles: u64| 100.0 * cycles as f64 / self.total.cycles.max(1) as f64;
+        writeln!(
+            out,
+            "{} instructions, {} cycles, {:.1} frames",
+            self.total.instructions,
+            self.total.cycles,
+            self.total.cycles as f64 / CYCLES_PER_FRAME as f64
+        )?;
+        writeln!(out)?;
+        writeln!(out, "{:<24} {:>12} {:>6} {:>12} {:>6} {:>12}", "routine", "own cycles", "%", "total", "%", "instructions")?;
+        let mut routines: Vec<_> = self.routines.iter().filter(|(_, (own, _))| own.instructions > 0).collect();
+        routines.sort_by_key(|(routine, (own, _))| (std::cmp::Reverse(own.cycles), **routine));
+        for (routine, (own, total)) in routines.into_iter().take(limit) {
+            let name = routine.map(|r| routine_name(r, symbols)).unwrap_or_else(|| "(top level)".to_string());
+            writeln!(
+                out,
+                "{:<24} {:>12} {:>6.2} {:>12} {:>6.2} {:>12}",
+                name,
+                own.cycles,
+                percent(own.cycles),
+                total,
+                percent(*total),
+                own.instructions
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(out, "{:<8} {:<24} {:>12} {:>6} {:>12}", "address", "where", "cycles", "%", "executions")?;
+        let mut addresses: Vec<(u16, Count)> =
+            (0..=0xffff).map(|a| (a, self.at(a))).filter(|(_, count)| count.instructions > 0).collect();
+        addresses.sort_by_key(|(address, count)| (std::cmp::Reverse(count.cycles), *address));
+        for (address, count) in addresses.into_iter().take(limit) {
+            let place = symbols.describe(address).unwrap_or_default();
+            writeln!(
+                out,
+                "{:04x}     {:<24} {:>12} {:>6.2} {:>12}",
+                address,
+                place,
+                count.cycles,
+                percent(count.cycles),
+                count.instructions
+            )?;
+        }
+        Ok(())
+    }
+
+    /// A line per call stack seen, `top;Outer;Inner cycles`, sorted.
+    pub fn write_folded<W: Write>(&self, out: &mut W, symbols: &SymbolTable) -> io::Result<()> {
+        let mut lines: Vec<(String, u64)> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let names = stack.iter().map(|routine| routine_name(*routine, symbols).replace(' ', "_"));
+                (std::iter::once("top".to_string()).chain(names).collect::<Vec<_>>().join(";"), *cycles)
+            })
+            .collect();
+        lines.sort();
+        for (stack, cycles) in lines {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+fn routine_name(routine: u16, symbols: &SymbolTable) -> String {
+    symbols.describe(routine).unwrap_or_else(|| format!("{:04x}", routine))
+}
@@ -0,0 +1,142 @@
+use crate::invaders::{ROM_CHIPS, ROM_CHIP_SIZE, ROM_SIZE};
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Where the emulator looks for the ROM when it is not told: a merged image
+/// in the current directory.
+pub const DEFAULT_PATH: &str = "invaders";
+
+// The names a merged image goes by in a directory or an archive
+const MERGED_NAMES: [&str; 2] = ["invaders.rom", "invaders"];
+
+/// Why a ROM could not be loaded.
+#[derive(Debug)]
+pub enum RomError {
+    /// A file or directory could not be read.
+    Io { path: PathBuf, error: io::Error },
+    /// A zip archive could not be read.
+    Zip { path: PathBuf, error: ZipError },
+    /// `path`, a directory or an archive, lacks the chips named, or has no
+    /// ROM in it at all.
+    Missing { path: PathBuf, names: Vec<String> },
+    /// The file `name` is `size` bytes long, not `expected`.
+    WrongSize { name: String, size: usize, expected: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            RomError::Zip { path, error } => write!(f, "{}: {}", path.display(), error),
+            RomError::Missing { path, names } => {
+                write!(f, "{}: missing {}", path.display(), names.join(", "))
+            }
+            RomError::WrongSize { name, size, expected } => {
+                write!(f, "{} is {} bytes, expected {}", name, size, expected)
+            }
+        }
+    }
+}
+
+impl error::Error for RomError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RomError::Io { error, .. } => Some(error),
+            RomError::Zip { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the 8KB Space Invaders ROM from `path`, which is one of:
+///
+/// * a merged image, the four chips one after the other,
+/// * a directory with the MAME chip files `invaders.h`, `.g`, `.f` and `.e`,
+///   or a merged image named `invaders.rom` or `invaders`,
+/// * a `.zip` archive with the same files, in any folder inside it.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, RomError> {
+    let path = path.as_ref();
+    let io_error = |error| RomError::Io { path: path.to_path_buf(), error };
+    if path.is_dir() {
+        let mut files = HashMap::new();
+        for name in names() {
+            let file = path.join(name);
+            if file.is_file() {
+                let bytes = fs::read(&file).map_err(|error| RomError::Io { path: file, error })?;
+                files.insert(name.to_string(), bytes);
+            }
+        }
+        return from_files(path, &files);
+    }
+    let is_zip = path.extension().map(|e| e.eq_ignore_ascii_case("zip")).unwrap_or(false);
+    if is_zip {
+        return from_zip(File::open(path).map_err(io_error)?, path);
+    }
+    let bytes = fs::read(path).map_err(io_error)?;
+    check_size(&path.display().to_string(), &bytes, ROM_SIZE)?;
+    Ok(bytes)
+}
+
+/// Reads the ROM from a zip archive, as `load` does for a `.zip` file;
+/// `path` only names it in errors.
+pub fn from_zip<R: Read + Seek>(reader: R, path: &Path) -> Result<Vec<u8>, RomError> {
+    let zip_error = |error| RomError::Zip { path: path.to_path_buf(), error };
+    let mut archive = ZipArchive::new(reader).map_err(zip_error)?;
+    let mut files = HashMap::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(zip_error)?;
+        // MAME sets are flat, but a zipped folder has the folder in the names
+        let name = entry.name().rsplit('/').next().unwrap_or_default().to_string();
+        if entry.is_dir() || !names().any(|wanted| wanted == name) {
+            continue;
+        }
+        let mut bytes = Vec::new();
+        entry.read_to_end(&mut bytes).map_err(|error| zip_error(error.into()))?;
+        files.insert(name, bytes);
+    }
+    from_files(path, &files)
+}
+
+// The files a ROM can be made of
+fn names() -> impl Iterator<Item = &'static str> {
+    ROM_CHIPS.iter().map(|&(name, _)| name).chain(MERGED_NAMES.iter().copied())
+}
+
+// The chips when there are any, else a merged image; `files` has the
+// contents of the ones found, by name
+fn from_files(path: &Path, files: &HashMap<String, Vec<u8>>) -> Result<Vec<u8>, RomError> {
+    let chips_found = ROM_CHIPS.iter().any(|(name, _)| files.contains_key(*name));
+    if !chips_found {
+        if let Some(name) = MERGED_NAMES.iter().find(|name| files.contains_key(**name)) {
+            let bytes = &files[*name];
+            check_size(&path.join(name).display().to_string(), bytes, ROM_SIZE)?;
+            return Ok(bytes.clone());
+        }
+    }
+    let missing: Vec<String> =
+        ROM_CHIPS.iter().filter(|(name, _)| !files.contains_key(*name)).map(|(name, _)| name.to_string()).collect();
+    if !missing.is_empty() {
+        return Err(RomError::Missing { path: path.to_path_buf(), names: missing });
+    }
+    let mut rom = vec![0; ROM_SIZE];
+    for &(name, start) in ROM_CHIPS.iter() {
+        let bytes = &files[name];
+        check_size(&path.join(name).display().to_string(), bytes, ROM_CHIP_SIZE)?;
+        rom[start as usize..start as usize + ROM_CHIP_SIZE].copy_from_slice(bytes);
+    }
+    Ok(rom)
+}
+
+fn check_size(name: &str, bytes: &[u8], expected: usize) -> Result<(), RomError> {
+    if bytes.len() == expected {
+        Ok(())
+    } else {
+        Err(RomError::WrongSize { name: name.to_string(), size: bytes.len(), expected })
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Whether a symbol names a routine or a variable/table.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Code,
+    Data,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub address: u16,
+    pub name: String,
+    pub kind: SymbolKind,
+    pub comment: Option<String>,
+}
+
+/// Names for addresses, read from a symbol file with one symbol per line:
+///
+/// ```text
+/// # Lines starting with # are ignored
+/// 1a5c code ClearScreen ; Zeroes the video RAM
+/// 20eb data NumCoins
+/// ```
+///
+/// The address is hexadecimal, the kind `code` or `data`, and everything
+/// after a `;` is the symbol's comment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: BTreeMap<u16, Symbol>,
+}
+
+/// A line of a symbol file that could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for SymbolError {}
+
+// How far past a code symbol an address is still shown as name+offset
+const MAX_OFFSET: u16 = 0x100;
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { symbols: BTreeMap::new() }
+    }
+
+    /// The symbols for the Space Invaders ROM that ship with the crate.
+    pub fn invaders() -> SymbolTable {
+        SymbolTable::parse(include_str!("invaders.sym")).expect("invaders.sym is valid")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<SymbolTable> {
+        let text = fs::read_to_string(path)?;
+        SymbolTable::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse(text: &str) -> Result<SymbolTable, SymbolError> {
+        let mut table = SymbolTable::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| SymbolError { line: index + 1, message };
+            let (fields, comment) = match line.find(';') {
+                Some(i) => (&line[..i], Some(line[i + 1..].trim().to_string())),
+                None => (line, None),
+            };
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            if fields.len() != 3 {
+                return Err(error(format!("expected `address kind name`, found {:?}", line.trim())));
+            }
+            let address = u16::from_str_radix(fields[0].trim_start_matches("0x"), 16)
+                .map_err(|_| error(format!("bad address {:?}", fields[0])))?;
+            let kind = match fields[1] {
+                "code" => SymbolKind::Code,
+                "data" => SymbolKind::Data,
+                other => return Err(error(format!("kind must be code or data, found {:?}", other))),
+            };
+            let name = fields[2];
+            if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                return Err(error(format!("bad name {:?}", name)));
+            }
+            if let Some(existing) = table.lookup(name) {
+                return Err(error(format!("{} is already defined at {:04x}", name, existing)));
+            }
+            table.insert(Symbol {
+                address,
+                name: name.to_string(),
+                kind,
+                comment: comment.filter(|c| !c.is_empty()),
+            });
+        }
+        Ok(table)
+    }
+
+    /// Adds `symbol`, replacing any symbol at the same address.
+    pub fn insert(&mut self, symbol: Symbol) {
+        self.symbols.insert(symbol.address, symbol);
+    }
+
+    pub fn get(&self, address: u16) -> Option<&Symbol> {
+        self.symbols.get(&address)
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.get(address).map(|symbol| symbol.name.as_str())
+    }
+
+    /// The address of the symbol called `name`.
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.symbols.values().find(|symbol| symbol.name == name).map(|symbol| symbol.address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// `address` relative to the closest code symbol at or before it, as in
+    /// `ClearScreen` or `ClearScreen+3`.
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let (&start, symbol) = self.symbols.range(..=address).rev().find(|(_, s)| {
+            s.kind == SymbolKind::Code || s.address == address
+        })?;
+        match address - start {
+            0 => Some(symbol.name.clone()),
+            offset if offset < MAX_OFFSET => Some(format!("{}+{}", symbol.name, offset)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::bus::Bus;
+use crate::cpu::CPUState;
+use crate::instruction::{decode, length};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Which instructions a `Tracer` writes; all of them by default.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    /// Only instructions at addresses from the first to the second, both
+    /// included.
+    pub pc_range: Option<(u16, u16)>,
+    /// Only the first to the second instruction traced, both included and
+    /// counting from 1.
+    pub window: Option<(u64, u64)>,
+}
+
+impl Filter {
+    pub fn accepts(&self, pc: u16, count: u64) -> bool {
+        let pc_ok = self.pc_range.map(|(start, end)| (start..=end).contains(&pc)).unwrap_or(true);
+        let count_ok = self.window.map(|(first, last)| (first..=last).contains(&count)).unwrap_or(true);
+        pc_ok && count_ok
+    }
+
+    /// Parses `START-END` or a single `ADDRESS`, in hex.
+    pub fn parse_pc_range(text: &str) -> Result<(u16, u16), String> {
+        parse_range(text, |t| u16::from_str_radix(t.trim_start_matches("0x"), 16).ok())
+            .ok_or_else(|| format!("bad address range {:?}", text))
+    }
+
+    /// Parses `FIRST-LAST` or a single count, in decimal.
+    pub fn parse_window(text: &str) -> Result<(u64, u64), String> {
+        parse_range(text, |t| t.parse().ok()).ok_or_else(|| format!("bad instruction window {:?}", text))
+    }
+}
+
+fn parse_range<T: Copy + PartialOrd, F: Fn(&str) -> Option<T>>(text: &str, number: F) -> Option<(T, T)> {
+    let (start, end) = match text.split_once('-') {
+        Some((start, end)) => (number(start)?, number(end)?),
+        None => (number(text)?, number(text)?),
+    };
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Writes a line per instruction executed, before executing it:
+///
+/// ```text
+///          1 0000  00        NOP            A:00 B:00 C:00 D:00 E:00 H:00 L:00 F:02 ..... SP:0000 CYC:0
+/// ```
+///
+/// That is the instruction count, PC, the instruction's bytes and mnemonic,
+/// the registers, the flags as PUSH PSW stores them and as letters (S Z AC P
+/// CY, a dot when clear), SP and the cycles run so far. The columns line up
+/// and nothing in them depends on the host, so two traces of the same
+/// program can be compared with `diff`.
+///
+/// Cycles spent halted are not instructions and are not traced. Write
+/// errors are kept until `finish` rather than stopping the emulation.
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    pub filter: Filter,
+    /// Instructions seen so far, traced or not.
+    pub count: u64,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new<W: Write + Send + 'static>(out: W, filter: Filter) -> Tracer {
+        Tracer { out: Box::new(out), filter, count: 0, error: None }
+    }
+
+    /// A tracer writing to a new file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P, filter: Filter) -> io::Result<Tracer> {
+        Ok(Tracer::new(BufWriter::new(File::create(path)?), filter))
+    }
+
+    /// Traces the instruction `cpu` is about to execute from `bus`.
+    pub fn trace<B: Bus>(&mut self, cpu: &CPUState, bus: &mut B) {
+        if cpu.halted || self.error.is_some() {
+            return;
+        }
+        self.count += 1;
+        if !self.filter.accepts(cpu.pc, self.count) {
+            return;
+        }
+        let opcode = bus.read(cpu.pc);
+        let mut bytes = [opcode, 0, 0];
+        for i in 1..length(opcode) {
+            bytes[i as usize] = bus.read(cpu.pc.wrapping_add(i as u16));
+        }
+        let line = format_line(self.count, cpu, &bytes);
+        if let Err(error) = writeln!(self.out, "{}", line) {
+            self.error = Some(error);
+        }
+    }
+
+    /// Whether the instruction window is over, so nothing more will be
+    /// written.
+    pub fn is_done(&self) -> bool {
+        self.filter.window.map(|(_, last)| self.count >= last).unwrap_or(false)
+    }
+
+    /// Flushes the output, returning the first error writing to it.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.out.flush(),
+        }
+    }
+}
+
+/// The trace line for instruction number `count`, which is at the start of
+/// `bytes`, about to run on `cpu`.
+pub fn format_line(count: u64, cpu: &CPUState, bytes: &[u8]) -> String {
+    let instruction = decode(bytes);
+    let hex = bytes[..instruction.length() as usize].iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>();
+    let cc = &cpu.cc;
+    let flags: String = [(cc.s, 'S'), (cc.z, 'Z'), (cc.ac, 'A'), (cc.p, 'P'), (cc.cy, 'C')]
+        .iter()
+        .map(|&(set, letter)| if set != 0 { letter } else { '.' })
+        .collect();
+    format!(
+        "{:10} {:04x}  {:<8}  {:<14} A:{:02x} B:{:02x} C:{:02x} D:{:02x} E:{:02x} H:{:02x} L:{:02x} F:{:02x} {} SP:{:04x} CYC:{}",
+        count,
+        cpu.pc,
+        hex.join(" "),
+        instruction.to_string().replace('\t', " "),
+        cpu.a,
+        cpu.b,
+        cpu.c,
+        cpu.d,
+        cpu.e,
+        cpu.h,
+        cpu.l,
+        cc.psw(),
+        flags,
+        cpu.sp,
+        cpu.total_cycles
+    )
+}
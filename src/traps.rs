@@ -0,0 +1,327 @@
+use crate::bus::Bus;
+use crate::cpu::CPUState;
+use crate::invaders::{Machine, INVADERS_MEMORY_SIZE};
+use std::fmt;
+
+/// A bus access made by an instruction, or by an interrupt pushing PC.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+    In(u8, u8),
+    Out(u8, u8),
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read(address, value) => write!(f, "read {:02x} from {:04x}", value, address),
+            Access::Write(address, value) => write!(f, "write {:02x} to {:04x}", value, address),
+            Access::In(port, value) => write!(f, "IN {:02x} from port {}", value, port),
+            Access::Out(port, value) => write!(f, "OUT {:02x} to port {}", value, port),
+        }
+    }
+}
+
+/// The machine, with every access it makes written down.
+pub struct Recorder<'a> {
+    pub machine: &'a mut Machine,
+    pub accesses: Vec<Access>,
+    /// The address and previous value of each write, for undoing them.
+    pub overwritten: Vec<(u16, u8)>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(machine: &'a mut Machine) -> Recorder<'a> {
+        Recorder { machine, accesses: Vec::new(), overwritten: Vec::new() }
+    }
+
+    /// Drops the reads that fetched the `length` bytes of the instruction
+    /// at `pc`, leaving the ones it made as data.
+    pub fn forget_fetch(&mut self, pc: u16, length: u8) {
+        let mut fetched = 0;
+        self.accesses.retain(|access| match access {
+            Access::Read(address, _) if fetched < length && *address == pc.wrapping_add(fetched as u16) => {
+                fetched += 1;
+                false
+            }
+            _ => true,
+        });
+    }
+}
+
+impl<'a> Bus for Recorder<'a> {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.machine.read(address);
+        self.accesses.push(Access::Read(address, value));
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.overwritten.push((address, self.machine.read(address)));
+        self.machine.write(address, value);
+        self.accesses.push(Access::Write(address, value));
+    }
+
+    fn port_in(&mut self, port: u8) -> u8 {
+        let value = self.machine.port_in(port);
+        self.accesses.push(Access::In(port, value));
+        value
+    }
+
+    fn port_out(&mut self, port: u8, value: u8) {
+        self.machine.port_out(port, value);
+        self.accesses.push(Access::Out(port, value));
+    }
+}
+
+/// Which accesses a watchpoint or port trap fires on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Reads, or IN.
+    In,
+    /// Writes, or OUT.
+    Out,
+    Both,
+}
+
+impl Direction {
+    fn matches(self, is_in: bool) -> bool {
+        match self {
+            Direction::In => is_in,
+            Direction::Out => !is_in,
+            Direction::Both => true,
+        }
+    }
+}
+
+/// Something that stops the machine.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trap {
+    /// PC reached `address` and `condition`, if any, holds.
+    Breakpoint { address: u16, condition: Option<Expression> },
+    /// An access to memory from `start` to `end`, both included.
+    Watchpoint { start: u16, end: u16, direction: Direction },
+    Port { port: u8, direction: Direction },
+}
+
+impl Trap {
+    /// Whether the trap fires for the state after an instruction and the
+    /// accesses it made: `None` if not, otherwise the access that set it off,
+    /// which breakpoints do not have.
+    pub fn fires(&self, cpu: &CPUState, accesses: &[Access]) -> Option<Option<Access>> {
+        match self {
+            Trap::Breakpoint { address, condition } => {
+                let holds = condition.as_ref().map(|c| c.evaluate(cpu) != 0).unwrap_or(true);
+                if cpu.pc == *address && holds {
+                    Some(None)
+                } else {
+                    None
+                }
+            }
+            Trap::Watchpoint { start, end, direction } => accesses
+                .iter()
+                .find(|access| match **access {
+                    Access::Read(address, _) => direction.matches(true) && covers(*start, *end, address),
+                    Access::Write(address, _) => direction.matches(false) && covers(*start, *end, address),
+                    _ => false,
+                })
+                .map(|access| Some(*access)),
+            Trap::Port { port, direction } => accesses
+                .iter()
+                .find(|access| match **access {
+                    Access::In(p, _) => direction.matches(true) && p == *port,
+                    Access::Out(p, _) => direction.matches(false) && p == *port,
+                    _ => false,
+                })
+                .map(|access| Some(*access)),
+        }
+    }
+}
+
+// Whether `address` is the same byte as one from `start` to `end`, both
+// included, on a board that mirrors its memory every INVADERS_MEMORY_SIZE
+fn covers(start: u16, end: u16, address: u16) -> bool {
+    let size = INVADERS_MEMORY_SIZE as u16;
+    let span = end.wrapping_sub(start);
+    span >= size - 1 || address.wrapping_sub(start) % size <= span
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = |d: &Direction, read: &'static str, write: &'static str| match d {
+            Direction::In => read,
+            Direction::Out => write,
+            Direction::Both => "access",
+        };
+        match self {
+            Trap::Breakpoint { address, condition: None } => write!(f, "break {:04x}", address),
+            Trap::Breakpoint { address, condition: Some(condition) } => {
+                write!(f, "break {:04x} if {}", address, condition)
+            }
+            Trap::Watchpoint { start, end, direction: d } if start == end => {
+                write!(f, "watch {:04x} {}", start, direction(d, "read", "write"))
+            }
+            Trap::Watchpoint { start, end, direction: d } => {
+                write!(f, "watch {:04x}-{:04x} {}", start, end, direction(d, "read", "write"))
+            }
+            Trap::Port { port, direction: d } => write!(f, "trap {} {}", direction(d, "in", "out"), port),
+        }
+    }
+}
+
+/// A condition on the registers and flags, as in `A == 10 && !Z`.
+///
+/// Names are the registers (A, B, C, D, E, H, L), the pairs (BC, DE, HL, SP,
+/// PC) and the flags (Z, S, P, CY, AC); numbers are hex and start with a
+/// digit (0A, not A). The operators are, loosest first, `||`, `&&`, the
+/// comparisons, `|`, `&`, `+` and `-`, then unary `!`; parentheses group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expression {
+    text: String,
+    tree: Node,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Number(u16),
+    Name(&'static str),
+    Not(Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+const NAMES: [&str; 17] = ["A", "B", "C", "D", "E", "H", "L", "BC", "DE", "HL", "SP", "PC", "Z", "S", "P", "CY", "AC"];
+
+// Binary operators, loosest first
+const LEVELS: [&[&str]; 6] = [&["||"], &["&&"], &["==", "!=", "<=", ">=", "<", ">"], &["|"], &["&"], &["+", "-"]];
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let tokens = tokenize(text)?;
+        let mut position = 0;
+        let tree = parse_level(&tokens, &mut position, 0)?;
+        if position != tokens.len() {
+            return Err(format!("unexpected {:?} in {:?}", tokens[position], text));
+        }
+        Ok(Expression { text: text.trim().to_string(), tree })
+    }
+
+    pub fn evaluate(&self, cpu: &CPUState) -> u16 {
+        evaluate(&self.tree, cpu)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            i += 1;
+        } else if chars[i].is_ascii_alphanumeric() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            let two: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let operator = ["||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "&", "+", "-", "!", "(", ")"]
+                .iter()
+                .find(|o| two.starts_with(*o))
+                .ok_or_else(|| format!("unexpected {:?} in {:?}", chars[i], text))?;
+            tokens.push(operator.to_string());
+            i += operator.len();
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_level(tokens: &[String], position: &mut usize, level: usize) -> Result<Node, String> {
+    if level == LEVELS.len() {
+        return parse_unary(tokens, position);
+    }
+    let mut node = parse_level(tokens, position, level + 1)?;
+    while let Some(operator) = tokens.get(*position).and_then(|t| LEVELS[level].iter().find(|o| **o == t.as_str())) {
+        *position += 1;
+        let right = parse_level(tokens, position, level + 1)?;
+        node = Node::Binary(operator, Box::new(node), Box::new(right));
+    }
+    Ok(node)
+}
+
+fn parse_unary(tokens: &[String], position: &mut usize) -> Result<Node, String> {
+    let token = tokens.get(*position).ok_or_else(|| "expression ends early".to_string())?;
+    *position += 1;
+    match token.as_str() {
+        "!" => Ok(Node::Not(Box::new(parse_unary(tokens, position)?))),
+        "(" => {
+            let node = parse_level(tokens, position, 0)?;
+            match tokens.get(*position).map(|t| t.as_str()) {
+                Some(")") => {
+                    *position += 1;
+                    Ok(node)
+                }
+                _ => Err("missing )".to_string()),
+            }
+        }
+        text if text.starts_with(|c: char| c.is_ascii_digit()) => {
+            let digits = text.strip_prefix("0x").or_else(|| text.strip_suffix('h')).unwrap_or(text);
+            u16::from_str_radix(digits, 16).map(Node::Number).map_err(|_| format!("bad number {:?}", text))
+        }
+        text => match NAMES.iter().find(|name| name.eq_ignore_ascii_case(text)) {
+            Some(name) => Ok(Node::Name(name)),
+            None => Err(format!("unknown name {:?}", text)),
+        },
+    }
+}
+
+fn evaluate(node: &Node, cpu: &CPUState) -> u16 {
+    let pair = |high: u8, low: u8| (high as u16) << 8 | low as u16;
+    match node {
+        Node::Number(value) => *value,
+        Node::Name(name) => match *name {
+            "A" => cpu.a as u16,
+            "B" => cpu.b as u16,
+            "C" => cpu.c as u16,
+            "D" => cpu.d as u16,
+            "E" => cpu.e as u16,
+            "H" => cpu.h as u16,
+            "L" => cpu.l as u16,
+            "BC" => pair(cpu.b, cpu.c),
+            "DE" => pair(cpu.d, cpu.e),
+            "HL" => pair(cpu.h, cpu.l),
+            "SP" => cpu.sp,
+            "PC" => cpu.pc,
+            "Z" => cpu.cc.z as u16,
+            "S" => cpu.cc.s as u16,
+            "P" => cpu.cc.p as u16,
+            "CY" => cpu.cc.cy as u16,
+            _ => cpu.cc.ac as u16,
+        },
+        Node::Not(node) => (evaluate(node, cpu) == 0) as u16,
+        Node::Binary(operator, left, right) => {
+            let (left, right) = (evaluate(left, cpu), evaluate(right, cpu));
+            match *operator {
+                "||" => (left != 0 || right != 0) as u16,
+                "&&" => (left != 0 && right != 0) as u16,
+                "==" => (left == right) as u16,
+                "!=" => (left != right) as u16,
+                "<=" => (left <= right) as u16,
+                ">=" => (left >= right) as u16,
+                "<" => (left < right) as u16,
+                ">" => (left > right) as u16,
+                "|" => left | right,
+                "&" => left & right,
+                "+" => left.wrapping_add(right),
+                _ => left.wrapping_sub(right),
+            }
+        }
+    }
+}
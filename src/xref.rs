@@ -0,0 +1,301 @@
+use crate::code_map::CodeMap;
+use crate::instruction::{Operation, Register, RegisterPair};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+
+/// How an instruction refers to an address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReferenceKind {
+    /// CALL, a conditional call or RST.
+    Call,
+    /// JMP or a conditional jump.
+    Jump,
+    Read,
+    Write,
+    /// Loaded into a register pair with LXI without being accessed, as for
+    /// a table handed to a routine.
+    Address,
+}
+
+impl fmt::Display for ReferenceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReferenceKind::Call => "call",
+            ReferenceKind::Jump => "jump",
+            ReferenceKind::Read => "read",
+            ReferenceKind::Write => "write",
+            ReferenceKind::Address => "address",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Reference {
+    pub from: u16,
+    pub kind: ReferenceKind,
+}
+
+/// Who refers to what in a traced ROM.
+///
+/// A routine starts at each call or RST target, each code symbol and each
+/// entry point, and is every instruction reachable from there without
+/// calling or jumping into another routine. Memory accesses are found
+/// statically: the direct ones (LDA, STA, LHLD, SHLD), and those through
+/// M, STAX and LDAX while the pair still holds a value loaded with LXI in
+/// the same routine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrossReference<'a> {
+    pub map: &'a CodeMap,
+    /// The references to each address, in order of the referring address.
+    pub references: BTreeMap<u16, BTreeSet<Reference>>,
+    /// The instructions of each routine, by its start.
+    pub routines: BTreeMap<u16, BTreeSet<u16>>,
+    /// Calls, and jumps into another routine, between routine starts.
+    pub calls: BTreeSet<(u16, u16)>,
+    pub jumps: BTreeSet<(u16, u16)>,
+    // LXIs whose value something read or wrote through
+    dereferenced: BTreeSet<u16>,
+}
+
+// The values of BC, DE and HL while known, with the address of the LXI that
+// loaded them
+#[derive(Copy, Clone, Default)]
+struct Pointers([Option<(u16, u16)>; 3]);
+
+impl Pointers {
+    fn index(rp: RegisterPair) -> Option<usize> {
+        match rp {
+            RegisterPair::BC => Some(0),
+            RegisterPair::DE => Some(1),
+            RegisterPair::HL => Some(2),
+            RegisterPair::SP | RegisterPair::PSW => None,
+        }
+    }
+
+    fn get(&self, rp: RegisterPair) -> Option<(u16, u16)> {
+        Pointers::index(rp).and_then(|i| self.0[i])
+    }
+
+    fn set(&mut self, rp: RegisterPair, value: Option<(u16, u16)>) {
+        if let Some(i) = Pointers::index(rp) {
+            self.0[i] = value;
+        }
+    }
+
+    // Forgets the pair a register belongs to when it is written
+    fn clobber(&mut self, r: Register) {
+        match r {
+            Register::B | Register::C => self.0[0] = None,
+            Register::D | Register::E => self.0[1] = None,
+            Register::H | Register::L => self.0[2] = None,
+            Register::M | Register::A => {}
+        }
+    }
+}
+
+impl<'a> CrossReference<'a> {
+    pub fn new(map: &'a CodeMap) -> CrossReference<'a> {
+        let mut xref = CrossReference {
+            map,
+            references: BTreeMap::new(),
+            routines: BTreeMap::new(),
+            calls: BTreeSet::new(),
+            jumps: BTreeSet::new(),
+            dereferenced: BTreeSet::new(),
+        };
+        let starts = xref.routine_starts();
+        for &start in &starts {
+            xref.walk(start, &starts);
+        }
+        // Those values are not just addresses
+        for loaded_at in xref.dereferenced.clone() {
+            let value = xref.map.instructions[&loaded_at].operation.operand().unwrap_or_default();
+            if let Some(references) = xref.references.get_mut(&value) {
+                references.remove(&Reference { from: loaded_at, kind: ReferenceKind::Address });
+                if references.is_empty() {
+                    xref.references.remove(&value);
+                }
+            }
+        }
+        xref
+    }
+
+    fn routine_starts(&self) -> BTreeSet<u16> {
+        let mut starts = BTreeSet::new();
+        // Anything nothing else flows into is an entry point
+        let mut reached = BTreeSet::new();
+        for (&address, instruction) in &self.map.instructions {
+            let next = address.wrapping_add(instruction.length() as u16);
+            match instruction.operation {
+                Operation::Call(target) | Operation::Ccc(_, target) => {
+                    starts.insert(target);
+                    reached.insert(next);
+                }
+                Operation::Rst(n) => {
+                    starts.insert(n as u16 * 8);
+                    reached.insert(next);
+                }
+                Operation::Jmp(target) => {
+                    reached.insert(target);
+                }
+                Operation::Jcc(_, target) => {
+                    reached.insert(target);
+                    reached.insert(next);
+                }
+                Operation::Ret | Operation::Pchl => {}
+                _ => {
+                    reached.insert(next);
+                }
+            }
+        }
+        starts.extend(self.map.instructions.keys().filter(|a| !reached.contains(a)));
+        starts.extend(&self.map.entry_points);
+        starts.extend(self.map.symbols.iter().map(|s| s.address));
+        starts.retain(|a| self.map.instructions.contains_key(a));
+        starts
+    }
+
+    fn refer(&mut self, to: u16, from: u16, kind: ReferenceKind) {
+        self.references.entry(to).or_default().insert(Reference { from, kind });
+    }
+
+    fn walk(&mut self, start: u16, starts: &BTreeSet<u16>) {
+        let mut members = BTreeSet::new();
+        let mut pending = vec![(start, Pointers::default())];
+        while let Some((address, mut pointers)) = pending.pop() {
+            let instruction = match self.map.instructions.get(&address) {
+                Some(instruction) if !members.contains(&address) => *instruction,
+                _ => continue,
+            };
+            members.insert(address);
+            let next = address.wrapping_add(instruction.length() as u16);
+            let mut successors = vec![next];
+            let access = |xref: &mut Self, rp: RegisterPair, kind: ReferenceKind| {
+                if let Some((target, loaded_at)) = pointers.get(rp) {
+                    xref.refer(target, address, kind);
+                    xref.dereferenced.insert(loaded_at);
+                }
+            };
+            match instruction.operation {
+                Operation::Call(target) | Operation::Ccc(_, target) => {
+                    self.refer(target, address, ReferenceKind::Call);
+                    self.calls.insert((start, target));
+                }
+                Operation::Rst(n) => {
+                    self.refer(n as u16 * 8, address, ReferenceKind::Call);
+                    self.calls.insert((start, n as u16 * 8));
+                }
+                Operation::Jmp(target) | Operation::Jcc(_, target) => {
+                    self.refer(target, address, ReferenceKind::Jump);
+                    if let Operation::Jmp(_) = instruction.operation {
+                        successors.clear();
+                    }
+                    successors.push(target);
+                }
+                Operation::Ret | Operation::Pchl => successors.clear(),
+                Operation::Lda(target) | Operation::Lhld(target) => self.refer(target, address, ReferenceKind::Read),
+                Operation::Sta(target) | Operation::Shld(target) => self.refer(target, address, ReferenceKind::Write),
+                // Recorded as an address unless something reads or writes
+                // through it
+                Operation::Lxi(rp, value) if rp != RegisterPair::SP => {
+                    self.refer(value, address, ReferenceKind::Address)
+                }
+                Operation::Ldax(rp) => access(self, rp, ReferenceKind::Read),
+                Operation::Stax(rp) => access(self, rp, ReferenceKind::Write),
+                Operation::Mov(Register::M, _) | Operation::Mvi(Register::M, _) => {
+                    access(self, RegisterPair::HL, ReferenceKind::Write)
+                }
+                Operation::Inr(Register::M) | Operation::Dcr(Register::M) => {
+                    access(self, RegisterPair::HL, ReferenceKind::Read);
+                    access(self, RegisterPair::HL, ReferenceKind::Write);
+                }
+                Operation::Mov(_, Register::M)
+                | Operation::Add(Register::M)
+                | Operation::Adc(Register::M)
+                | Operation::Sub(Register::M)
+                | Operation::Sbb(Register::M)
+                | Operation::Ana(Register::M)
+                | Operation::Xra(Register::M)
+                | Operation::Ora(Register::M)
+                | Operation::Cmp(Register::M) => access(self, RegisterPair::HL, ReferenceKind::Read),
+                _ => {}
+            }
+
+            // What is known about the pointers afterwards
+            match instruction.operation {
+                Operation::Lxi(rp, value) => pointers.set(rp, Some((value, address))),
+                Operation::Inx(rp) => pointers.set(rp, pointers.get(rp).map(|(v, at)| (v.wrapping_add(1), at))),
+                Operation::Dcx(rp) => pointers.set(rp, pointers.get(rp).map(|(v, at)| (v.wrapping_sub(1), at))),
+                Operation::Xchg => pointers.0.swap(1, 2),
+                Operation::Mov(r, _) | Operation::Mvi(r, _) | Operation::Inr(r) | Operation::Dcr(r) => pointers.clobber(r),
+                Operation::Pop(rp) => pointers.set(rp, None),
+                Operation::Lhld(_) | Operation::Dad(_) | Operation::Xthl => pointers.set(RegisterPair::HL, None),
+                // The callee may change anything
+                Operation::Call(_) | Operation::Ccc(..) | Operation::Rst(_) => pointers = Pointers::default(),
+                _ => {}
+            }
+            for successor in successors {
+                // Jumping or running into another routine ends this one
+                if successor != start && starts.contains(&successor) {
+                    self.jumps.insert((start, successor));
+                } else {
+                    pending.push((successor, pointers));
+                }
+            }
+        }
+        self.routines.insert(start, members);
+    }
+
+    /// The routines `address` belongs to.
+    pub fn routines_of(&self, address: u16) -> Vec<u16> {
+        self.routines.iter().filter(|(_, members)| members.contains(&address)).map(|(&start, _)| start).collect()
+    }
+
+    /// The name of `address` in the listing, or its hex value.
+    pub fn name(&self, address: u16) -> String {
+        self.map
+            .label(address)
+            .or_else(|| self.map.symbols.describe(address))
+            .unwrap_or_else(|| format!("{:04x}", address))
+    }
+
+    /// Lists every referenced address with what refers to it and from which
+    /// routine, code first, then memory.
+    pub fn write_report<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        let (code, data): (Vec<_>, Vec<_>) =
+            self.references.iter().partition(|(address, _)| self.map.instructions.contains_key(address));
+        for (title, section) in [("Code", code), ("Data", data)].iter() {
+            writeln!(out, "; {}", title)?;
+            for (&address, references) in section {
+                match self.map.label(address).or_else(|| self.map.symbols.describe(address)) {
+                    Some(name) => writeln!(out, "{:04x}\t{}", address, name)?,
+                    None => writeln!(out, "{:04x}", address)?,
+                }
+                for reference in references.iter() {
+                    let routines =
+                        self.routines_of(reference.from).iter().map(|r| self.name(*r)).collect::<Vec<_>>().join(", ");
+                    writeln!(out, "\t{}\t{:04x}\t{}", reference.kind, reference.from, routines)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the call graph in Graphviz DOT format, with jumps from one
+    /// routine into another dashed.
+    pub fn write_dot<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph calls {{")?;
+        writeln!(out, "\tnode [shape=box];")?;
+        for &start in self.routines.keys() {
+            writeln!(out, "\t\"{}\";", self.name(start))?;
+        }
+        for &(from, to) in &self.calls {
+            writeln!(out, "\t\"{}\" -> \"{}\";", self.name(from), self.name(to))?;
+        }
+        for &(from, to) in &self.jumps {
+            writeln!(out, "\t\"{}\" -> \"{}\" [style=dashed];", self.name(from), self.name(to))?;
+        }
+        writeln!(out, "}}")
+    }
+}
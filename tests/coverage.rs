@@ -0,0 +1,106 @@
+// Which ROM bytes a run executed, read and left alone, and the listing.
+use emulator8080::coverage::{Coverage, Use};
+use emulator8080::cpu::StepOutcome;
+use emulator8080::debugger::{Debugger, Reply};
+use emulator8080::invaders::CYCLES_PER_FRAME;
+use emulator8080::{assemble, new_machine, Machine, SymbolTable};
+
+const PROGRAM: &str = "\
+START:\tLXI\tSP,2400H
+\tLDA\tTABLE
+\tLXI\tH,TABLE
+\tINX\tH
+\tMOV\tB,M
+\tHLT
+\tJMP\tSTART
+TABLE:\tDB\t1,2,3
+";
+
+fn run(source: &str) -> (Coverage, Machine, SymbolTable) {
+    let program = assemble(source).unwrap();
+    let mut machine = Machine::new();
+    machine.memory[..program.bytes.len()].copy_from_slice(&program.bytes);
+    let mut coverage = Coverage::new();
+    let mut cpu = machine.cpu;
+    while coverage.step(&mut cpu, &mut machine).unwrap() == StepOutcome::Executed {}
+    // Halted steps mark nothing
+    coverage.step(&mut cpu, &mut machine).unwrap();
+    (coverage, machine, program.symbol_table())
+}
+
+#[test]
+fn code_data_and_untouched() {
+    let (coverage, _, _) = run(PROGRAM);
+    assert_eq!(coverage.usage(0x0000), Use::Code);
+    // LDA's operand is code, the byte it loads is data
+    assert_eq!(coverage.usage(0x0004), Use::Code);
+    assert_eq!(coverage.usage(0x000b), Use::Code);
+    assert_eq!(coverage.usage(0x000c), Use::Untouched);
+    assert_eq!(coverage.usage(0x000f), Use::Data);
+    assert_eq!(coverage.usage(0x0010), Use::Data);
+    assert_eq!(coverage.usage(0x0011), Use::Untouched);
+    // The ROM is mirrored, RAM is not counted
+    assert_eq!(coverage.usage(0x4000), Use::Code);
+    assert_eq!(coverage.usage(0x2000), Use::Untouched);
+    assert_eq!(coverage.count(0x0000, 0x07ff), (12, 2, 2034));
+}
+
+#[test]
+fn summary_and_listing() {
+    let (coverage, machine, symbols) = run(PROGRAM);
+    let mut summary = Vec::new();
+    coverage.write_summary(&mut summary).unwrap();
+    let summary = String::from_utf8(summary).unwrap();
+    let lines: Vec<&str> = summary.lines().collect();
+    assert_eq!(lines[0], "invaders.h  0000-07ff  code   0.6%  data   0.1%  untouched  99.3%");
+    assert_eq!(lines[1], "invaders.g  0800-0fff  code   0.0%  data   0.0%  untouched 100.0%");
+    assert_eq!(lines[4], "total       0000-1fff  code   0.1%  data   0.0%  untouched  99.8%");
+    assert_eq!(lines.len(), 5);
+
+    let mut listing = Vec::new();
+    coverage.write_listing(&mut listing, &machine.memory[..0x2000], &symbols).unwrap();
+    let listing = String::from_utf8(listing).unwrap();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines[0], format!("; {}", summary.lines().next().unwrap()));
+    assert_eq!(lines[5], "START:");
+    assert!(lines[6].starts_with("x  0000\tLXI"));
+    assert!(lines[11].starts_with("x  000b\tHLT"));
+    assert_eq!(lines[12], ".  000c\tDB\t$c3,$00,$00");
+    assert_eq!(lines[13], "TABLE:");
+    assert_eq!(lines[14], "r  000f\tDB\t$01,$02");
+    assert_eq!(lines[15], ".  0011\tDB\t$03,$00,$00,$00,$00,$00,$00,$00");
+}
+
+#[test]
+fn attract_mode() {
+    let (machine, _buffer) = new_machine();
+    let mut debugger = Debugger::new(machine, SymbolTable::invaders());
+    match debugger.execute("coverage") {
+        Reply::Text(text) => assert!(text.starts_with("not measuring coverage")),
+        Reply::Quit => panic!("quit"),
+    }
+    debugger.execute("coverage on");
+    debugger.run(u64::MAX, |machine| machine.cpu.total_cycles >= 300 * CYCLES_PER_FRAME);
+    let coverage = debugger.coverage.as_ref().unwrap();
+    // The boot code and the interrupt handlers ran, the data tables were read
+    assert_eq!(coverage.usage(0x0000), Use::Code);
+    assert_eq!(coverage.usage(0x0008), Use::Code);
+    assert_eq!(coverage.usage(0x0010), Use::Code);
+    let (code, data, untouched) = coverage.count(0x0000, 0x1fff);
+    assert!(code > 0x100 && data > 0x100 && untouched > 0x1000, "{} {} {}", code, data, untouched);
+
+    let summary = match debugger.execute("coverage") {
+        Reply::Text(text) => text,
+        Reply::Quit => panic!("quit"),
+    };
+    assert_eq!(summary.lines().count(), 5);
+    assert!(summary.lines().nth(3).unwrap().starts_with("invaders.e  1800-1fff"));
+
+    let path = std::env::temp_dir().join(format!("emulator8080-coverage-{}.lst", std::process::id()));
+    debugger.execute(&format!("coverage write {}", path.display()));
+    let listing = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines[0], format!("; {}", summary.lines().next().unwrap()));
+    assert!(lines.iter().any(|line| line.starts_with("x  0000\t")));
+}
@@ -0,0 +1,147 @@
+// The BDOS console and file calls, driven by small hand assembled programs.
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+
+use emulator8080::cpm::TPA_SIZE;
+use emulator8080::{Bus, Cpm, CpmError, CpmExit};
+
+// BDOS function in C with DE as the argument: LXI D; MVI C; CALL 5
+fn bdos(function: u8, de: u16) -> Vec<u8> {
+    vec![0x11, de as u8, (de >> 8) as u8, 0x0e, function, 0xcd, 0x05, 0x00]
+}
+
+fn scratch_directory(name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!("cpm8080-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+#[test]
+fn console_line_to_new_file() {
+    let directory = scratch_directory("write");
+    let mut program = vec![
+        0x3e, 0x10, // MVI A, 16
+        0x32, 0x00, 0x02, // STA $0200, the line buffer size
+    ];
+    program.extend(bdos(10, 0x0200)); // read console buffer
+    program.extend(bdos(26, 0x0200)); // set DMA
+    program.extend(bdos(22, 0x005c)); // make file
+    program.extend(bdos(21, 0x005c)); // write sequential
+    program.extend(bdos(16, 0x005c)); // close file
+    program.extend(bdos(2, b'!' as u16)); // console output
+    program.extend(&[0xc3, 0x00, 0x00]); // JMP 0
+
+    let mut cpm = Cpm::new(&program).unwrap().with_directory(&directory);
+    cpm.set_arguments(&["out.txt".to_string()]);
+    cpm.console.input.extend(b"hello\r");
+    assert_eq!(cpm.run(10_000), Ok(CpmExit::WarmBoot));
+    assert_eq!(cpm.output_string(), "\r\n!");
+
+    let written = fs::read(directory.join("out.txt")).unwrap();
+    assert_eq!(written.len(), 128);
+    assert_eq!(&written[..7], b"\x10\x05hello");
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn read_file_to_console() {
+    let directory = scratch_directory("read");
+    fs::write(directory.join("IN.TXT"), b"HI THERE$").unwrap();
+    let mut program = Vec::new();
+    program.extend(bdos(15, 0x005c)); // open file
+    program.extend(bdos(26, 0x0300)); // set DMA
+    program.extend(bdos(20, 0x005c)); // read sequential
+    program.extend(bdos(9, 0x0300)); // print string
+    program.extend(bdos(20, 0x005c)); // read sequential, at end of file
+    program.extend(&[0xc3, 0x00, 0x00]); // JMP 0
+
+    let mut cpm = Cpm::new(&program).unwrap().with_directory(&directory);
+    cpm.set_arguments(&["in.txt".to_string()]);
+    assert_eq!(cpm.run(10_000), Ok(CpmExit::WarmBoot));
+    assert_eq!(cpm.output_string(), "HI THERE");
+    assert_eq!(cpm.cpu.a, 1);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn record_wraps_past_the_top_of_memory() {
+    let directory = scratch_directory("wrap");
+    let mut contents = vec![b'A'; 64];
+    contents.extend(vec![b'B'; 64]);
+    fs::write(directory.join("IN.TXT"), &contents).unwrap();
+    let mut program = Vec::new();
+    program.extend(bdos(15, 0x005c)); // open file
+    program.extend(bdos(26, 0xffc0)); // set DMA
+    program.extend(bdos(20, 0x005c)); // read sequential
+    program.extend(&[0xc3, 0x00, 0x00]); // JMP 0
+
+    let mut cpm = Cpm::new(&program).unwrap().with_directory(&directory);
+    cpm.set_arguments(&["in.txt".to_string()]);
+    assert_eq!(cpm.run(10_000), Ok(CpmExit::WarmBoot));
+    assert_eq!(cpm.cpu.a, 0);
+    assert_eq!((cpm.memory.read(0xffc0), cpm.memory.read(0xffff)), (b'A', b'A'));
+    assert_eq!((cpm.memory.read(0x0000), cpm.memory.read(0x003f)), (b'B', b'B'));
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn print_string_without_a_terminator() {
+    let mut program = bdos(9, 0x0300); // print string
+    program.extend(&[0xc3, 0x00, 0x00]); // JMP 0
+
+    let mut cpm = Cpm::new(&program).unwrap();
+    for byte in cpm.memory.bytes.iter_mut().filter(|byte| **byte == b'$') {
+        *byte = b'.';
+    }
+    assert_eq!(cpm.run(1_000), Ok(CpmExit::WarmBoot));
+    assert_eq!(cpm.console.output.len(), 0x10000);
+}
+
+#[test]
+fn stdin_polled_by_cpm8080() {
+    let directory = scratch_directory("poll");
+    let program = [
+        0x0e, 0x0b, 0xcd, 0x05, 0x00, // POLL: MVI C, 11; CALL 5, console status
+        0xb7, 0xca, 0x00, 0x01, // ORA A; JZ POLL
+        0x0e, 0x01, 0xcd, 0x05, 0x00, // MVI C, 1; CALL 5, console input
+        0xc3, 0x00, 0x00, // JMP 0
+    ];
+    let path = directory.join("poll.com");
+    fs::write(&path, program).unwrap();
+
+    let mut cpm8080 = Command::new(env!("CARGO_BIN_EXE_cpm8080"))
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    cpm8080.stdin.take().unwrap().write_all(b"x").unwrap();
+    let output = cpm8080.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"x");
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn program_larger_than_the_tpa() {
+    assert!(Cpm::new(&vec![0; TPA_SIZE]).is_ok());
+    let error = Cpm::new(&vec![0; 0xff01]).err();
+    assert_eq!(error, Some(CpmError::TooLarge { size: 0xff01 }));
+}
+
+#[test]
+fn missing_file_fails_to_open() {
+    let directory = scratch_directory("missing");
+    let mut program = bdos(15, 0x005c);
+    program.extend(&[0xc3, 0x00, 0x00]);
+
+    let mut cpm = Cpm::new(&program).unwrap().with_directory(&directory);
+    cpm.set_arguments(&["nothere.com".to_string()]);
+    assert_eq!(cpm.run(1_000), Ok(CpmExit::WarmBoot));
+    assert_eq!(cpm.cpu.a, 0xff);
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,287 @@
+// The debugger's commands, on small assembled programs and on the ROM.
+use emulator8080::debugger::{Debugger, Reply};
+use emulator8080::traps::{Access, Direction, Trap};
+use emulator8080::{assemble, new_machine, Machine, SymbolTable};
+
+const PROGRAM: &str = "\
+START:\tLXI\tSP,2400H
+\tMVI\tA,1
+\tCALL\tDOUBLE
+\tCALL\tDOUBLE
+\tSTA\t2000H
+\tHLT
+DOUBLE:\tADD\tA
+\tRET
+";
+
+// An unbalanced routine, one that drops its return address, and a RET with
+// nothing to return from
+const MISMATCHES: &str = "\
+START:\tLXI\tSP,2400H
+\tLXI\tH,BACK
+\tCALL\tSKIP
+BACK:\tCALL\tDROP
+\tRET
+SKIP:\tPUSH\tH
+\tRET
+DROP:\tPOP\tH
+\tPCHL
+";
+
+fn debugger(source: &str) -> Debugger {
+    let program = assemble(source).unwrap();
+    let mut machine = Machine::new();
+    machine.memory[..program.bytes.len()].copy_from_slice(&program.bytes);
+    Debugger::new(machine, program.symbol_table())
+}
+
+fn text(debugger: &mut Debugger, command: &str) -> String {
+    match debugger.execute(command) {
+        Reply::Text(text) => text,
+        Reply::Quit => panic!("{} quit", command),
+    }
+}
+
+#[test]
+fn step_next_and_until() {
+    let mut debugger = debugger(PROGRAM);
+    assert_eq!(text(&mut debugger, "step 3"), "=> 000f\tADD\tA\t; DOUBLE");
+    assert_eq!(debugger.machine.cpu.sp, 0x23fe);
+    text(&mut debugger, "s 2");
+    // Over the second call in one go, and an empty line repeats it
+    assert_eq!(text(&mut debugger, "next"), "=> 000b\tSTA\t$2000\t; START+11");
+    assert_eq!(debugger.machine.cpu.a, 4);
+    assert_eq!(text(&mut debugger, ""), "=> 000e\tHLT\t; START+14");
+
+    let mut debugger = self::debugger(PROGRAM);
+    text(&mut debugger, "until DOUBLE");
+    assert_eq!(debugger.machine.cpu.pc, 0x0f);
+    // HLT leaves PC past itself
+    assert_eq!(text(&mut debugger, "continue"), "Halted\n=> 000f\tADD\tA\t; DOUBLE");
+    assert_eq!(debugger.machine.memory[0x2000], 4);
+    assert!(text(&mut debugger, "registers").contains("AF: 0402"));
+    assert_eq!(debugger.execute("quit"), Reply::Quit);
+}
+
+#[test]
+fn memory_and_disassembly() {
+    let mut debugger = debugger(PROGRAM);
+    assert_eq!(text(&mut debugger, "write 2000 41 42 ff"), "");
+    assert_eq!(
+        text(&mut debugger, "memory 2000 20"),
+        "2000  41 42 ff 00 00 00 00 00 00 00 00 00 00 00 00 00  AB..............\n\
+         2010  00 00 00 00                                      ...."
+    );
+    assert!(text(&mut debugger, "write 2000 100").contains("not a byte"));
+    let everything = text(&mut debugger, "memory 2000 4294967295");
+    assert_eq!(everything.lines().count(), 0x1000);
+    assert!(everything.ends_with("\n1ff0  00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00  ................"));
+
+    text(&mut debugger, "s 4");
+    let listing = text(&mut debugger, "disassemble");
+    let lines: Vec<&str> = listing.lines().collect();
+    // Three instructions before PC, and the rest after
+    assert_eq!(lines[..5], ["   000b\tSTA\t$2000", "   000e\tHLT", "DOUBLE:", "   000f\tADD\tA", "=> 0010\tRET"]);
+    assert_eq!(lines.len(), 11);
+    assert!(text(&mut debugger, "d START 2").starts_with("START:\n   0000\tLXI\tSP,#$2400"));
+    assert!(text(&mut debugger, "frobnicate").starts_with("unknown command"));
+}
+
+#[test]
+fn interrupts_by_hand_and_by_video() {
+    let mut debugger = debugger("\tLXI\tSP,2400H\n\tEI\nLOOP:\tJMP\tLOOP\n\tORG\t8\n\tMVI\tA,1\n\tEI\n\tRET\n");
+    text(&mut debugger, "video off");
+    text(&mut debugger, "s 2");
+    text(&mut debugger, "interrupt 1");
+    assert_eq!((debugger.machine.cpu.pc, debugger.machine.cpu.int_enable), (0x08, false));
+    text(&mut debugger, "s 3");
+    assert_eq!((debugger.machine.cpu.pc, debugger.machine.cpu.a), (0x04, 1));
+
+    // The ROM waits for the video interrupts before drawing anything
+    let (machine, _) = new_machine();
+    let mut debugger = Debugger::new(machine, SymbolTable::invaders());
+    text(&mut debugger, "until DrawStatus");
+    assert_eq!(debugger.machine.cpu.pc, 0x1956);
+}
+
+#[test]
+fn conditional_breakpoints() {
+    let mut debugger = debugger(PROGRAM);
+    assert_eq!(text(&mut debugger, "break DOUBLE if A == 2"), "1\tbreak 000f if A == 2");
+    // The first time through A is 1
+    assert_eq!(text(&mut debugger, "continue"), "1 (break 000f if A == 2)\n=> 000f\tADD\tA\t; DOUBLE");
+    assert_eq!(debugger.machine.cpu.a, 2);
+    assert_eq!(text(&mut debugger, "continue"), "Halted\n=> 000f\tADD\tA\t; DOUBLE");
+
+    assert!(text(&mut debugger, "break DOUBLE if A = 2").starts_with("unexpected"));
+    assert!(text(&mut debugger, "break DOUBLE if Q").starts_with("unknown name"));
+    assert_eq!(text(&mut debugger, "b START if (HL | 0ff00) != 0 && !CY"), "2\tbreak 0000 if (HL | 0ff00) != 0 && !CY");
+    text(&mut debugger, "delete 2");
+    assert_eq!(text(&mut debugger, "traps"), "1\tbreak 000f if A == 2");
+}
+
+#[test]
+fn watchpoints_and_port_traps() {
+    let mut debugger = debugger(PROGRAM);
+    text(&mut debugger, "watch 2000");
+    // RET reading back the return address the CALL pushed
+    text(&mut debugger, "watch 23fe-23ff read");
+    assert_eq!(text(&mut debugger, "c"), "read 00 from 23ff, 2 (watch 23fe-23ff read)\n=> 0008\tCALL\tDOUBLE\t; START+8");
+    text(&mut debugger, "delete 2");
+    assert_eq!(text(&mut debugger, "c"), "write 04 to 2000, 1 (watch 2000 write)\n=> 000e\tHLT\t; START+14");
+    // The RAM is mirrored above 0x4000, whichever copy is watched or written
+    let mut debugger = self::debugger(PROGRAM);
+    text(&mut debugger, "watch 6000");
+    assert!(text(&mut debugger, "c").starts_with("write 04 to 2000, 1 (watch 6000 write)"));
+    let watch = Trap::Watchpoint { start: 0x2400, end: 0x2401, direction: Direction::Both };
+    let cpu = debugger.machine.cpu;
+    assert_eq!(watch.fires(&cpu, &[Access::Write(0x6401, 1)]), Some(Some(Access::Write(0x6401, 1))));
+    assert_eq!(watch.fires(&cpu, &[Access::Write(0x6402, 1)]), None);
+    // Fetching the instructions is not reading them
+    let mut debugger = self::debugger(PROGRAM);
+    text(&mut debugger, "watch 0000-000f read");
+    assert!(text(&mut debugger, "c").starts_with("Halted"));
+
+    // The ROM feeds the watchdog and draws on the screen
+    let (machine, _) = new_machine();
+    let mut debugger = Debugger::new(machine, SymbolTable::invaders());
+    assert_eq!(text(&mut debugger, "trap out 6"), "1\ttrap out 6");
+    assert!(text(&mut debugger, "c").contains("to port 6, 1 (trap out 6)"));
+    text(&mut debugger, "delete all");
+    text(&mut debugger, "watch VideoRAM-3fff");
+    assert!(text(&mut debugger, "c").contains(", 2 (watch 2400-3fff write)"));
+    assert!(debugger.accesses.iter().any(|a| matches!(a, Access::Write(0x2400..=0x3fff, _))));
+}
+
+#[test]
+fn reverse_execution() {
+    let mut debugger = debugger(PROGRAM);
+    text(&mut debugger, "continue");
+    assert_eq!(debugger.machine.memory[0x2000], 4);
+    text(&mut debugger, "watch 2000");
+    // Back to before the write, which stepping forward makes again
+    assert_eq!(text(&mut debugger, "rc"), "write 04 to 2000, 1 (watch 2000 write)\n=> 000b\tSTA\t$2000\t; START+11");
+    assert_eq!((debugger.machine.memory[0x2000], debugger.machine.cpu.a), (0, 4));
+    assert!(text(&mut debugger, "step").starts_with("write 04 to 2000"));
+    assert_eq!(debugger.machine.memory[0x2000], 4);
+
+    text(&mut debugger, "delete all");
+    // Undoing the STA, the RET and the ADD
+    assert_eq!(text(&mut debugger, "reverse-step 3"), "=> 000f\tADD\tA\t; DOUBLE");
+    assert_eq!((debugger.machine.cpu.a, debugger.machine.cpu.sp, debugger.machine.memory[0x2000]), (2, 0x23fe, 0));
+    assert_eq!(text(&mut debugger, "rc"), "Start of history\n=> 0000\tLXI\tSP,#$2400\t; START");
+    assert_eq!(debugger.machine, self::debugger(PROGRAM).machine);
+}
+
+#[test]
+fn changes_by_hand_are_undone() {
+    let mut debugger = debugger(PROGRAM);
+    text(&mut debugger, "step");
+    text(&mut debugger, "write 2100 aa bb");
+    text(&mut debugger, "interrupt 1");
+    assert_eq!((debugger.machine.cpu.pc, debugger.machine.memory[0x23fe]), (0x0008, 0x03));
+    // The interrupt, its push included, then the write, then the LXI
+    text(&mut debugger, "rs");
+    assert_eq!((debugger.machine.cpu.pc, debugger.machine.cpu.sp, debugger.machine.memory[0x23fe]), (0x0003, 0x2400, 0));
+    assert_eq!(text(&mut debugger, "backtrace"), "#0  0003 in START+3");
+    assert_eq!(debugger.machine.memory[0x2100..0x2102], [0xaa, 0xbb]);
+    text(&mut debugger, "rs");
+    assert_eq!(debugger.machine.memory[0x2100..0x2102], [0, 0]);
+    text(&mut debugger, "rs");
+    assert_eq!(debugger.machine, self::debugger(PROGRAM).machine);
+}
+
+#[test]
+fn rewinding_frames() {
+    let (machine, _) = new_machine();
+    let mut debugger = Debugger::new(machine.clone(), SymbolTable::invaders());
+    // More than a second of the game's time
+    text(&mut debugger, "continue 300000");
+    assert!(text(&mut debugger, "history").starts_with("60 frames of 60 kept"));
+    let rewound = text(&mut debugger, "rewind 2");
+    let cycles = debugger.machine.cpu.total_cycles;
+    assert!(rewound.starts_with(&format!("Rewound to cycle {}\n", cycles)));
+    assert!(text(&mut debugger, "history").starts_with("58 frames"));
+    assert!(text(&mut debugger, "rewind 100").starts_with("the history only has 58 frames"));
+
+    // The same machine run up to the same cycle, shift register and all
+    let mut again = Debugger::new(machine, SymbolTable::invaders());
+    again.run(u64::MAX, |machine| machine.cpu.total_cycles >= cycles);
+    assert_eq!(debugger.machine, again.machine);
+    text(&mut debugger, "continue 10000");
+    text(&mut again, "continue 10000");
+    assert_eq!(debugger.machine, again.machine);
+
+    assert!(text(&mut debugger, "history 0").starts_with("0 frames of 0 kept"));
+    text(&mut debugger, "step");
+    assert!(text(&mut debugger, "rs").starts_with("Start of history"));
+}
+
+#[test]
+fn backtraces() {
+    let mut debugger = debugger(PROGRAM);
+    text(&mut debugger, "until DOUBLE");
+    assert_eq!(text(&mut debugger, "backtrace"), "#0  000f in DOUBLE\n#1  0005 in START+5");
+    assert_eq!(debugger.calls.top().map(|frame| (frame.return_address, frame.sp)), Some((0x0008, 0x23fe)));
+    text(&mut debugger, "step 2");
+    assert_eq!(text(&mut debugger, "bt"), "#0  0008 in START+8");
+    // Going back puts the frames back
+    text(&mut debugger, "rs");
+    assert_eq!(debugger.calls.depth(), 1);
+
+    let mut debugger = self::debugger("\tLXI\tSP,2400H\n\tEI\nLOOP:\tJMP\tLOOP\n\tORG\t10H\nINT2:\tRST\t3\n\tEI\n\tRET\n\tORG\t18H\nRST3:\tRET\n");
+    text(&mut debugger, "video off");
+    text(&mut debugger, "s 2");
+    text(&mut debugger, "interrupt 2");
+    text(&mut debugger, "s 1");
+    assert_eq!(text(&mut debugger, "bt"), "#0  0018 in RST3\n#1  0010 in INT2 (RST 3)\n#2  0004 in LOOP (interrupt 2)");
+}
+
+#[test]
+fn stack_mismatches() {
+    let mut debugger = debugger(MISMATCHES);
+    // Nothing stops without stack-check
+    assert_eq!(text(&mut debugger, "c 7"), "=> 0010\tPCHL\t; DROP+1");
+    assert!(debugger.mismatch.is_some());
+
+    let mut debugger = self::debugger(MISMATCHES);
+    text(&mut debugger, "stack-check on");
+    assert_eq!(
+        text(&mut debugger, "c"),
+        "RET at 000e from 000d with SP at 23fc, the return address is at 23fe\n=> 0009\tCALL\tDROP\t; BACK"
+    );
+    assert_eq!(text(&mut debugger, "c"), "000f dropped the return address of 000f from the stack\n=> 0010\tPCHL\t; DROP+1");
+    assert_eq!(text(&mut debugger, "c"), "RET at 000c to 0009, with no call to return from\n=> 0009\tCALL\tDROP\t; BACK");
+    assert_eq!(text(&mut debugger, "bt"), "#0  0009 in BACK");
+}
+
+#[test]
+fn profiling() {
+    let mut debugger = debugger(PROGRAM);
+    assert!(text(&mut debugger, "profile").starts_with("not profiling"));
+    text(&mut debugger, "profile on");
+    text(&mut debugger, "continue");
+    let profiler = debugger.profiler.as_ref().unwrap();
+    // ADD is 4 cycles, twice
+    assert_eq!((profiler.at(0x0f).instructions, profiler.at(0x0f).cycles), (2, 8));
+    // DOUBLE's ADD and RET; LXI, MVI, the CALLs, STA and HLT outside it
+    assert_eq!(profiler.routine(Some(0x0f)).0.cycles, 28);
+    assert_eq!(profiler.routine(None).0.cycles, 71);
+    assert_eq!(profiler.routine(None).1, 99);
+
+    let report = text(&mut debugger, "profile 1");
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines[0], "10 instructions, 99 cycles, 0.0 frames");
+    assert!(lines[3].starts_with("(top level)") && lines[3].contains(" 71  71.72 "));
+    assert!(lines[6].starts_with("0010     DOUBLE+1"));
+    assert_eq!(lines.len(), 7);
+
+    let directory = std::env::temp_dir();
+    let report = directory.join(format!("emulator8080-profile-{}.txt", std::process::id()));
+    let folded = report.with_extension("folded");
+    text(&mut debugger, &format!("profile write {} {}", report.display(), folded.display()));
+    assert_eq!(std::fs::read_to_string(&folded).unwrap(), "top 71\ntop;DOUBLE 28\n");
+    assert_eq!(std::fs::read_to_string(&report).unwrap().lines().count(), 15);
+    std::fs::remove_file(report).unwrap();
+    std::fs::remove_file(folded).unwrap();
+}
@@ -0,0 +1,65 @@
+// The standard 8080 CPU exercisers, run unmodified under the CP/M shim.
+//
+// Only cpudiag is in the repository, the others are looked up in tests/roms
+// (see the README there). TST8080 and 8080PRE take a fraction of a second
+// and run with the other tests whenever they are there; CPUTEST and 8080EXM
+// take minutes and are ignored by default, run them with
+// `cargo test --release -- --ignored`.
+use std::fs;
+use std::path::Path;
+
+use emulator8080::{Cpm, CpmExit};
+
+fn run_com(path: &str, max_instructions: u64) -> String {
+    let program = fs::read(path).unwrap_or_else(|e| panic!("cannot read {}: {}", path, e));
+    let mut cpm = Cpm::new(&program).unwrap();
+    let exit = cpm.run(max_instructions).unwrap();
+    let output = cpm.output_string();
+    assert_eq!(exit, CpmExit::WarmBoot, "{} did not finish, printed:\n{}", path, output);
+    output
+}
+
+// `run_com` for an exerciser that may not have been put in tests/roms
+fn run_com_if_present(path: &str, max_instructions: u64) -> Option<String> {
+    if Path::new(path).exists() {
+        Some(run_com(path, max_instructions))
+    } else {
+        eprintln!("skipping {}, see tests/roms/README.md", path);
+        None
+    }
+}
+
+#[test]
+fn cpudiag() {
+    let output = run_com("cpudiag.bin", 10_000);
+    assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+}
+
+#[test]
+fn tst8080() {
+    if let Some(output) = run_com_if_present("tests/roms/TST8080.COM", 1_000_000) {
+        assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+    }
+}
+
+#[test]
+fn preliminary_8080() {
+    if let Some(output) = run_com_if_present("tests/roms/8080PRE.COM", 1_000_000) {
+        assert!(output.contains("8080 Preliminary tests complete"), "{}", output);
+    }
+}
+
+#[test]
+#[ignore]
+fn cputest() {
+    let output = run_com("tests/roms/CPUTEST.COM", 1_000_000_000);
+    assert!(output.contains("CPU TESTS OK"), "{}", output);
+}
+
+#[test]
+#[ignore]
+fn exerciser_8080() {
+    let output = run_com("tests/roms/8080EXM.COM", 10_000_000_000);
+    assert!(output.contains("Tests complete"), "{}", output);
+    assert!(!output.contains("ERROR"), "{}", output);
+}
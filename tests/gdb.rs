@@ -0,0 +1,177 @@
+// The GDB stub over a local TCP connection, as GDB would drive it.
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use emulator8080::debugger::Debugger;
+use emulator8080::gdb::GdbServer;
+use emulator8080::{assemble, Machine, SymbolTable};
+
+struct Client(TcpStream);
+
+impl Client {
+    // Sends a packet and returns the data of the reply
+    fn send(&mut self, data: &str) -> String {
+        self.0.write_all(packet(data).as_bytes()).unwrap();
+        self.reply()
+    }
+
+    fn reply(&mut self) -> String {
+        let mut reply = Vec::new();
+        let mut byte = [0u8];
+        // The + acknowledging the packet, then $data#xx
+        loop {
+            self.0.read_exact(&mut byte).unwrap();
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        loop {
+            self.0.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        self.0.read_exact(&mut sum).unwrap();
+        self.0.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    // k has no reply
+    fn kill(mut self) {
+        write!(self.0, "$k#6b").unwrap();
+    }
+}
+
+fn packet(data: &str) -> String {
+    let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    format!("${}#{:02x}", data, sum)
+}
+
+fn start(source: &str) -> (Client, SocketAddr, thread::JoinHandle<()>) {
+    let program = assemble(source).unwrap();
+    let mut machine = Machine::new();
+    machine.memory[..program.bytes.len()].copy_from_slice(&program.bytes);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut debugger = Debugger::new(machine, SymbolTable::new());
+        debugger.video_interrupts = false;
+        GdbServer::new(debugger).serve(&listener).unwrap();
+    });
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_nodelay(true).unwrap();
+    (Client(stream), address, server)
+}
+
+#[test]
+fn registers_memory_and_stepping() {
+    let (mut gdb, _, server) = start("\tLXI\tSP,2400H\n\tMVI\tA,12H\n\tSTC\n\tSTA\t2000H\n\tHLT\n");
+    assert!(gdb.send("qSupported:swbreak+").contains("qXfer:features:read+"));
+    assert!(gdb.send("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+    assert_eq!(gdb.send("?"), "S05");
+    assert_eq!(gdb.send("s"), "S05");
+    assert_eq!(gdb.send("s"), "S05");
+    assert_eq!(gdb.send("s"), "S05");
+    // A B C D E H L flags SP PC, the 16 bit ones little endian
+    assert_eq!(gdb.send("g"), "120000000000000300240600");
+    assert_eq!(gdb.send("p9"), "0600");
+    assert_eq!(gdb.send("P1=34"), "OK");
+    assert_eq!(gdb.send("p1"), "34");
+
+    assert_eq!(gdb.send("M2100,3:aabbcc"), "OK");
+    assert_eq!(gdb.send("m2100,3"), "aabbcc");
+    assert_eq!(gdb.send("m0000,3"), "310024");
+    assert_eq!(gdb.send("m0000,1000").len(), 0x2000);
+    assert_eq!(gdb.send("m0000,1001"), "E01");
+    assert_eq!(gdb.send("vMustReplyEmpty"), "");
+    gdb.kill();
+    server.join().unwrap();
+}
+
+#[test]
+fn breakpoints_and_watchpoints() {
+    let (mut gdb, address, server) = start(
+        "\tLXI\tSP,2400H\n\
+         LOOP:\tINR\tA\n\
+         \tSTA\t2000H\n\
+         \tCPI\t3\n\
+         \tJNZ\tLOOP\n\
+         \tHLT\n",
+    );
+    assert_eq!(gdb.send("Z0,3,1"), "OK");
+    assert_eq!(gdb.send("c"), "T05swbreak:;");
+    assert_eq!(gdb.send("c"), "T05swbreak:;");
+    assert_eq!(gdb.send("p0"), "01");
+    assert_eq!(gdb.send("z0,3,1"), "OK");
+
+    assert_eq!(gdb.send("Z2,2000,1"), "OK");
+    assert_eq!(gdb.send("c"), "T05watch:2000;");
+    assert_eq!(gdb.send("m2000,1"), "02");
+    assert_eq!(gdb.send("z2,2000,1"), "OK");
+    assert_eq!(gdb.send("Z4,2000,1"), "OK");
+    assert_eq!(gdb.send("c"), "T05awatch:2000;");
+    assert_eq!(gdb.send("z4,2000,1"), "OK");
+    assert_eq!(gdb.send("c"), "S05");
+    assert_eq!(gdb.send("p0"), "03");
+    assert_eq!(gdb.send("D"), "OK");
+
+    // A second connection finds the machine where the first left it
+    let mut gdb = Client(TcpStream::connect(address).unwrap());
+    assert_eq!(gdb.send("p0"), "03");
+    gdb.kill();
+    server.join().unwrap();
+}
+
+#[test]
+fn packet_sent_while_continuing() {
+    // 65536 times round the loop, so the stub looks for a Ctrl-C on the way
+    let (mut gdb, _, server) = start(
+        "\tLXI\tSP,2400H\n\
+         \tLXI\tB,0\n\
+         LOOP:\tDCX\tB\n\
+         \tMOV\tA,B\n\
+         \tORA\tC\n\
+         \tJNZ\tLOOP\n\
+         \tHLT\n",
+    );
+    let both = packet("c") + &packet("p9");
+    gdb.0.write_all(both.as_bytes()).unwrap();
+    assert_eq!(gdb.reply(), "S05");
+    assert_eq!(gdb.reply(), "0d00");
+    gdb.kill();
+    server.join().unwrap();
+}
+
+#[test]
+fn reverse_step_and_continue() {
+    let (mut gdb, _, server) = start("\tLXI\tSP,2400H\n\tMVI\tA,12H\n\tSTA\t2000H\n\tINR\tA\n\tHLT\n");
+    assert!(gdb.send("qSupported").contains("ReverseContinue+"));
+    assert_eq!(gdb.send("c"), "S05");
+    assert_eq!(gdb.send("p0"), "13");
+    // Back over the HLT, then the INR
+    assert_eq!(gdb.send("bs"), "S05");
+    assert_eq!(gdb.send("p9"), "0900");
+    assert_eq!(gdb.send("bs"), "S05");
+    assert_eq!(gdb.send("p0"), "12");
+    assert_eq!(gdb.send("Z2,2000,1"), "OK");
+    assert_eq!(gdb.send("bc"), "T05watch:2000;");
+    assert_eq!(gdb.send("m2000,1"), "00");
+    assert_eq!(gdb.send("p9"), "0500");
+    assert_eq!(gdb.send("bc"), "T05replaylog:begin;");
+    assert_eq!(gdb.send("p9"), "0000");
+    // Memory and registers set by GDB go back too
+    assert_eq!(gdb.send("z2,2000,1"), "OK");
+    assert_eq!(gdb.send("M2000,1:55"), "OK");
+    assert_eq!(gdb.send("P9=3412"), "OK");
+    assert_eq!(gdb.send("bs"), "S05");
+    assert_eq!(gdb.send("p9"), "0000");
+    assert_eq!(gdb.send("m2000,1"), "55");
+    assert_eq!(gdb.send("bs"), "S05");
+    assert_eq!(gdb.send("m2000,1"), "00");
+    assert_eq!(gdb.send("bs"), "T05replaylog:begin;");
+    gdb.kill();
+    server.join().unwrap();
+}
@@ -0,0 +1,108 @@
+// Loading the ROM from a merged image, the MAME chip files and zip archives.
+use emulator8080::rom::{self, RomError};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+const CHIPS: [&str; 4] = ["invaders.h", "invaders.g", "invaders.f", "invaders.e"];
+
+fn chip(name: &str) -> Vec<u8> {
+    fs::read(format!("docs/rom/{}", name)).unwrap()
+}
+
+fn merged() -> Vec<u8> {
+    CHIPS.iter().flat_map(|name| chip(name)).collect()
+}
+
+// A fresh path in the temporary directory
+fn scratch(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("emulator8080-rom-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn zip(name: &str, files: &[(String, Vec<u8>)]) -> PathBuf {
+    let path = scratch(name);
+    let mut zip = ZipWriter::new(File::create(&path).unwrap());
+    for (file, bytes) in files {
+        zip.start_file(file.as_str(), FileOptions::default()).unwrap();
+        zip.write_all(bytes).unwrap();
+    }
+    zip.finish().unwrap();
+    path
+}
+
+#[test]
+fn merged_image_and_chip_files() {
+    let rom = rom::load("docs/rom").unwrap();
+    assert_eq!(rom.len(), 0x2000);
+    assert_eq!(rom, merged());
+    // invaders.g goes at 0x0800
+    assert_eq!(rom[0x800..0x1000], chip("invaders.g")[..]);
+
+    let image = scratch("image");
+    fs::write(&image, &rom).unwrap();
+    assert_eq!(rom::load(&image).unwrap(), rom);
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn zip_archives() {
+    // A MAME set, flat, and a zipped folder in another order
+    let files: Vec<_> = CHIPS.iter().map(|&name| (name.to_string(), chip(name))).collect();
+    let set = zip("set.zip", &files);
+    assert_eq!(rom::load(&set).unwrap(), merged());
+    fs::remove_file(set).unwrap();
+    let files: Vec<_> = CHIPS.iter().rev().map(|&name| (format!("invaders/{}", name), chip(name))).collect();
+    let folder = zip("folder.ZIP", &files);
+    assert_eq!(rom::load(&folder).unwrap(), merged());
+    fs::remove_file(folder).unwrap();
+
+    let image = zip("image.zip", &[("readme.txt".to_string(), b"hello".to_vec()), ("invaders.rom".to_string(), merged())]);
+    assert_eq!(rom::load(&image).unwrap(), merged());
+    fs::remove_file(image).unwrap();
+}
+
+#[test]
+fn errors() {
+    // Chips missing from a directory and from an archive
+    let directory = scratch("partial");
+    fs::create_dir(&directory).unwrap();
+    fs::write(directory.join("invaders.h"), chip("invaders.h")).unwrap();
+    fs::write(directory.join("invaders.f"), chip("invaders.f")).unwrap();
+    match rom::load(&directory) {
+        Err(RomError::Missing { names, .. }) => assert_eq!(names, ["invaders.g", "invaders.e"]),
+        other => panic!("{:?}", other),
+    }
+    let error = rom::load(&directory).unwrap_err().to_string();
+    assert!(error.ends_with(": missing invaders.g, invaders.e"), "{}", error);
+
+    // A chip of the wrong size
+    fs::write(directory.join("invaders.g"), chip("invaders.g")).unwrap();
+    fs::write(directory.join("invaders.e"), &chip("invaders.e")[..0x7ff]).unwrap();
+    let error = rom::load(&directory).unwrap_err().to_string();
+    assert!(error.ends_with("invaders.e is 2047 bytes, expected 2048"), "{}", error);
+    fs::remove_dir_all(directory).unwrap();
+
+    let archive = zip("empty.zip", &[("readme.txt".to_string(), b"hello".to_vec())]);
+    match rom::load(&archive) {
+        Err(RomError::Missing { names, .. }) => assert_eq!(names, CHIPS),
+        other => panic!("{:?}", other),
+    }
+    fs::remove_file(archive).unwrap();
+
+    // A merged image that is not 8KB, a zip that is not one, no file at all
+    let image = scratch("short");
+    fs::write(&image, &merged()[..0x1800]).unwrap();
+    let error = rom::load(&image).unwrap_err().to_string();
+    assert!(error.ends_with("is 6144 bytes, expected 8192"), "{}", error);
+    fs::remove_file(image).unwrap();
+    let archive = scratch("bad.zip");
+    fs::write(&archive, merged()).unwrap();
+    assert!(matches!(rom::load(&archive), Err(RomError::Zip { .. })));
+    fs::remove_file(archive).unwrap();
+    assert!(matches!(rom::load(scratch("nowhere")), Err(RomError::Io { .. })));
+}
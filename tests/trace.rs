@@ -0,0 +1,92 @@
+// The instruction trace, from the CP/M runner and the debugger.
+use emulator8080::debugger::{Debugger, Reply};
+use emulator8080::trace::{Filter, Tracer};
+use emulator8080::{assemble, Cpm, Machine};
+use std::fs;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+const PROGRAM: &str = "\
+\tORG\t100H
+\tMVI\tA,0FFH
+\tADI\t1
+LOOP:\tLXI\tH,1234H
+\tDCR\tB
+\tJNZ\tLOOP
+\tHLT
+";
+
+// A writer the test can still read after the tracer owns it
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn trace(filter: Filter) -> Vec<String> {
+    let shared = Shared::default();
+    let program = assemble(PROGRAM).unwrap();
+    let mut cpm = Cpm::new(&program.bytes).unwrap().with_tracer(Tracer::new(shared.clone(), filter));
+    cpm.cpu.b = 3;
+    cpm.run(100).unwrap();
+    let text = String::from_utf8(shared.0.lock().unwrap().clone()).unwrap();
+    text.lines().map(String::from).collect()
+}
+
+#[test]
+fn trace_lines() {
+    let lines = trace(Filter::default());
+    assert_eq!(lines.len(), 12);
+    assert_eq!(
+        lines[1..4],
+        [
+            "         2 0102  c6 01     ADI #$01       A:ff B:03 C:00 D:00 E:00 H:00 L:00 F:02 ..... SP:fe00 CYC:7",
+            "         3 0104  21 34 12  LXI H,#$1234   A:00 B:03 C:00 D:00 E:00 H:00 L:00 F:57 .ZAPC SP:fe00 CYC:14",
+            "         4 0107  05        DCR B          A:00 B:03 C:00 D:00 E:00 H:12 L:34 F:57 .ZAPC SP:fe00 CYC:24",
+        ]
+    );
+    // The HLT is traced, the cycles spent halted after it are not
+    assert!(lines[11].starts_with("        12 010b  76        HLT  "));
+}
+
+#[test]
+fn filters() {
+    let pc_range = Filter::parse_pc_range("0107-0108").unwrap();
+    let lines = trace(Filter { pc_range: Some(pc_range), window: None });
+    let columns: Vec<&str> = lines.iter().map(|line| &line[..15]).collect();
+    assert_eq!(columns, ["         4 0107", "         5 0108", "         7 0107", "         8 0108", "        10 0107", "        11 0108"]);
+
+    let window = Filter::parse_window("5-7").unwrap();
+    let lines = trace(Filter { pc_range: Some(pc_range), window: Some(window) });
+    assert_eq!(lines.iter().map(|line| &line[..15]).collect::<Vec<_>>(), ["         5 0108", "         7 0107"]);
+
+    assert_eq!(Filter::parse_pc_range("100"), Ok((0x100, 0x100)));
+    assert!(Filter::parse_pc_range("200-100").is_err());
+    assert!(Filter::parse_window("1-x").is_err());
+}
+
+#[test]
+fn debugger_trace_command() {
+    let program = assemble(PROGRAM.replace("\tORG\t100H\n", "").as_str()).unwrap();
+    let mut machine = Machine::new();
+    machine.memory[..program.bytes.len()].copy_from_slice(&program.bytes);
+    let mut debugger = Debugger::new(machine, program.symbol_table());
+    let path = std::env::temp_dir().join(format!("emulator8080-trace-{}.txt", std::process::id()));
+    let command = format!("trace {} pc LOOP-LOOP+3 count 1-6", path.display());
+    assert_eq!(debugger.execute(&command), Reply::Text(String::new()));
+    debugger.execute("step 20");
+    assert_eq!(debugger.execute("trace off"), Reply::Text(String::new()));
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let pcs: Vec<&str> = text.lines().map(|line| &line[11..15]).collect();
+    assert_eq!(pcs, ["0004", "0007", "0004"]);
+
+    assert!(matches!(debugger.execute("trace x pc"), Reply::Text(t) if t.starts_with("trace takes")));
+}